@@ -0,0 +1,224 @@
+//! Compression and decompression throughput, run with `cargo bench`.
+//!
+//! The built-in corpora are generated to resemble the kinds of files found in
+//! the Silesia corpus: text, structured binary records, long runs and
+//! incompressible data. Every file in the directory named by
+//! `BZIP2_BENCH_CORPUS`, such as an extracted copy of Silesia itself, is
+//! benchmarked as well.
+
+extern crate bzip2;
+extern crate criterion;
+
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+use bzip2::{bufread, read, write, Compression};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const LEN: usize = 1 << 20;
+
+const WORDS: &str = "\
+    the of and to in a is that for it as was with be by on not he this \
+    are or his from at which but have an had they you were their one all \
+    we can her has there been if more when will would who so no \
+    compression";
+
+// A small xorshift generator, so that the corpora are the same every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn text(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let words = WORDS.split(' ').collect::<Vec<_>>();
+    let mut out = Vec::with_capacity(len + 16);
+    while out.len() < len {
+        let word = words[rng.next() as usize % words.len()];
+        out.extend_from_slice(word.as_bytes());
+        out.push(match rng.next() % 16 {
+            0 => b'\n',
+            1 => b',',
+            _ => b' ',
+        });
+    }
+    out.truncate(len);
+    out
+}
+
+// fixed size records of counters, small enums and timestamps, as in
+// databases and object files
+fn records(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut out = Vec::with_capacity(len + 16);
+    let mut time = 1_600_000_000u64;
+    for id in 0u32.. {
+        if out.len() >= len {
+            break;
+        }
+        time += rng.next() % 60;
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&time.to_le_bytes());
+        out.push((rng.next() % 5) as u8);
+        out.extend_from_slice(&[0; 3]);
+    }
+    out.truncate(len);
+    out
+}
+
+fn runs(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0xdead_beef_cafe_f00d);
+    let mut out = Vec::with_capacity(len + 4096);
+    while out.len() < len {
+        let byte = rng.next() as u8;
+        let run = (rng.next() % 4096) as usize;
+        let end = out.len() + run;
+        out.resize(end, byte);
+    }
+    out.truncate(len);
+    out
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut rng = Rng(0x0123_4567_89ab_cdef);
+    (0..len).map(|_| rng.next() as u8).collect()
+}
+
+fn corpora() -> Vec<(String, Vec<u8>)> {
+    let mut corpora = vec![
+        ("text".to_string(), text(LEN)),
+        ("records".to_string(), records(LEN)),
+        ("runs".to_string(), runs(LEN)),
+        ("random".to_string(), random(LEN)),
+    ];
+    if let Some(dir) = env::var_os("BZIP2_BENCH_CORPUS") {
+        let mut files = fs::read_dir(dir)
+            .expect("BZIP2_BENCH_CORPUS isn't a directory")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        files.sort();
+        for path in files {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            corpora.push((name, fs::read(&path).unwrap()));
+        }
+    }
+    corpora
+}
+
+fn compress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compress");
+    group.sample_size(10);
+    for (name, data) in corpora() {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        for &level in &[1, 6, 9] {
+            let id = BenchmarkId::new(format!("level{}", level), &name);
+            group.bench_with_input(id, &data, |b, data| {
+                b.iter(|| bzip2::compress(data, Compression::new(level)))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn decompress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decompress");
+    group.sample_size(10);
+    for (name, data) in corpora() {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        for &level in &[1, 9] {
+            let compressed = bzip2::compress(&data, Compression::new(level));
+            let id = BenchmarkId::new(format!("level{}", level), &name);
+            group.bench_with_input(id, &compressed, |b, compressed| {
+                b.iter(|| bzip2::decompress(compressed).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+// how the size of the buffers between the caller, the streams and libbz2
+// affects the streaming APIs
+fn buffer_sizes(c: &mut Criterion) {
+    let data = text(LEN);
+    let compressed = bzip2::compress(&data, Compression::default());
+    let mut group = c.benchmark_group("buffer_sizes");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for &size in &[512, 4 * 1024, 32 * 1024, 256 * 1024] {
+        group.bench_with_input(BenchmarkId::new("write", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut e = write::BzEncoder::with_capacity(Vec::new(), Compression::fast(), size);
+                for chunk in data.chunks(size) {
+                    e.write_all(chunk).unwrap();
+                }
+                e.finish().unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("write_direct", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut e = write::BzEncoder::with_capacity(Vec::new(), Compression::fast(), size);
+                e.set_direct_output(true);
+                for chunk in data.chunks(size) {
+                    e.write_all(chunk).unwrap();
+                }
+                e.finish().unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("bufread", size), &size, |b, &size| {
+            b.iter(|| {
+                let r = BufReader::with_capacity(size, &compressed[..]);
+                let mut d = bufread::BzDecoder::new(r);
+                let mut buf = vec![0; size];
+                while d.read(&mut buf).unwrap() > 0 {}
+            })
+        });
+    }
+    group.finish();
+}
+
+fn parallel(c: &mut Criterion) {
+    let data = text(8 * LEN);
+    let compressed = bzip2::compress(&data, Compression::best());
+    let mut group = c.benchmark_group("parallel");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for &threads in &[1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("compress", threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    let mut e = write::ParBzEncoder::new(Vec::new(), Compression::best(), threads);
+                    e.write_all(&data).unwrap();
+                    e.finish().unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress", threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    let mut out = Vec::with_capacity(data.len());
+                    read::ParBzDecoder::new(&compressed[..], threads)
+                        .read_to_end(&mut out)
+                        .unwrap();
+                    out
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compress, decompress, buffer_sizes, parallel);
+criterion_main!(benches);
@@ -1,47 +1,443 @@
+#[cfg(feature = "bindgen")]
+extern crate bindgen;
 extern crate cc;
 extern crate pkg_config;
+#[cfg(target_env = "msvc")]
+extern crate vcpkg;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
+/// The oldest system libbz2 linked against, older ones have known bugs and
+/// vulnerabilities.
+const MIN_VERSION: &str = "1.0.6";
+
 fn main() {
     let mut cfg = cc::Build::new();
     let target = env::var("TARGET").unwrap();
+    let host = env::var("HOST").unwrap();
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap();
+    let apple_mobile = matches!(target_os.as_str(), "ios" | "tvos" | "watchos" | "visionos");
+    // cc finds the clang and sysroot of wasi-sdk through `WASI_SDK_PATH` and
+    // `WASI_SYSROOT` itself
+    let wasi = target_os == "wasi";
+    // targets without any libc, for which `shim` stands in, and whatever
+    // else the `freestanding` feature is enabled for
+    let freestanding = cfg!(feature = "freestanding")
+        || matches!(target_os.as_str(), "hermit" | "uefi")
+        || (target_os == "unknown"
+            && (target.starts_with("wasm32-") || target.starts_with("wasm64-")));
     cfg.warnings(false);
 
-    if target.contains("windows") {
-        cfg.define("_WIN32", None);
+    println!("cargo:rerun-if-env-changed=BZIP2_SYS_DYNAMIC");
+    let dynamic =
+        cfg!(feature = "dynamic") || env::var_os("BZIP2_SYS_DYNAMIC").is_some_and(|v| v != "0");
+    println!("cargo:rerun-if-env-changed=BZIP2_SYS_NO_PKG_CONFIG");
+    let no_pkg_config = cfg!(feature = "no-pkg-config")
+        || env::var_os("BZIP2_SYS_NO_PKG_CONFIG").is_some_and(|v| v != "0");
+    if dynamic {
+        if cfg!(feature = "static") {
+            panic!("the `dynamic` and `static` features of bzip2-sys are mutually exclusive");
+        }
+        if no_pkg_config {
+            panic!("linking against a shared system libbz2 needs pkg-config, which was disabled");
+        }
+        if freestanding {
+            panic!("linking against a shared system libbz2 isn't possible without a libc");
+        }
+        if cfg!(feature = "bz-debug") {
+            panic!("the `bz-debug` feature only applies to the bundled sources");
+        }
+        if cfg!(feature = "fast-crc") {
+            panic!("the `fast-crc` feature only applies to the bundled sources");
+        }
+        match pkg_config::Config::new()
+            .cargo_metadata(true)
+            .statik(false)
+            .atleast_version(MIN_VERSION)
+            .probe("bzip2")
+        {
+            Ok(lib) => {
+                generate_bindings(&lib.include_paths);
+                check_layout(&lib.include_paths);
+                system_metadata(
+                    &lib.include_paths,
+                    lib.libs.first(),
+                    lib.link_paths.first(),
+                    false,
+                    Some(&lib.version),
+                );
+                return;
+            }
+            // never fall back to building the bundled sources
+            Err(e) => panic!(
+                "linking against a shared system libbz2 was requested, \
+                 but it couldn't be found through pkg-config: {}",
+                e
+            ),
+        }
+    }
+
+    if target_os == "windows" {
+        // MSVC and MinGW both define `_WIN32`, with which bzlib.h needs this
+        // to declare the functions rather than pointers to them in a DLL
         cfg.define("BZ_EXPORT", None);
-    } else if !cfg!(feature = "static") {
-        // pkg-config doesn't guarantee static link
-        if pkg_config::Config::new()
+    }
+
+    if target_env == "msvc" {
+        // use the same CRT as the Rust code, a mismatch makes the linker
+        // warn and can break fully static binaries
+        let crt_static = env::var("CARGO_CFG_TARGET_FEATURE")
+            .is_ok_and(|features| features.split(',').any(|f| f == "crt-static"));
+        cfg.static_crt(crt_static);
+
+        // many Windows setups manage their C libraries with vcpkg
+        if !cfg!(feature = "static")
+            && !cfg!(feature = "bz-debug")
+            && !cfg!(feature = "fast-crc")
+            && !no_pkg_config
+            && try_vcpkg()
+        {
+            return;
+        }
+    } else if !cfg!(feature = "static")
+        && !cfg!(feature = "bz-debug")
+        && !cfg!(feature = "fast-crc")
+        && !apple_mobile
+        && !wasi
+        && !freestanding
+        && !no_pkg_config
+        && (target_os != "windows" || host != target)
+    {
+        // pkg-config doesn't guarantee static link, and neither the SDKs of
+        // Apple's mobile platforms nor the sysroots for WASI come with any
+        // pkg-config files for it to find. It's used for MinGW only when
+        // cross compiling, where it refuses to run unless it's configured for
+        // the target, and then links a DLL through its import library. Native
+        // MSYS2 builds get the bundled sources like MSVC ones without vcpkg,
+        // rather than depending on whichever MSYS2 DLL is on the `PATH`.
+        if let Ok(lib) = pkg_config::Config::new()
             .cargo_metadata(true)
+            .atleast_version(MIN_VERSION)
             .probe("bzip2")
-            .is_ok()
         {
+            generate_bindings(&lib.include_paths);
+            check_layout(&lib.include_paths);
+            system_metadata(
+                &lib.include_paths,
+                lib.libs.first(),
+                lib.link_paths.first(),
+                pkg_config_static(),
+                Some(&lib.version),
+            );
             return;
         }
+        if let Ok(lib) = pkg_config::Config::new()
+            .cargo_metadata(false)
+            .probe("bzip2")
+        {
+            println!(
+                "cargo:warning=the system libbz2 is version {}, which is older \
+                 than {}, building the bundled sources instead",
+                lib.version, MIN_VERSION
+            );
+        }
     }
 
     let dst = PathBuf::from(env::var_os("OUT_DIR").unwrap());
 
-    cfg.include("bzip2-1.0.8")
+    if apple_mobile {
+        // cc passes on the same minimum OS version as rustc, or the one given
+        // by these, and picks the simulator SDK for `-sim` targets itself
+        for var in &[
+            "IPHONEOS_DEPLOYMENT_TARGET",
+            "TVOS_DEPLOYMENT_TARGET",
+            "WATCHOS_DEPLOYMENT_TARGET",
+            "XROS_DEPLOYMENT_TARGET",
+        ] {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+        // set by Xcode for projects which still embed bitcode
+        println!("cargo:rerun-if-env-changed=ENABLE_BITCODE");
+        if env::var("ENABLE_BITCODE").is_ok_and(|v| v == "YES") {
+            cfg.flag("-fembed-bitcode");
+        }
+    }
+
+    if freestanding {
+        if cfg!(feature = "stdio") {
+            panic!("the `stdio` and `freestanding` features of bzip2-sys are mutually exclusive");
+        }
+        println!("cargo:rerun-if-changed=shim");
+        cfg.include("shim");
+    }
+
+    if !cfg!(feature = "stdio") {
+        cfg.define("BZ_NO_STDIO", None);
+    }
+    if cfg!(feature = "bz-debug") {
+        // checks internal invariants, printing which one failed and exiting
+        cfg.define("BZ_DEBUG", Some("1"));
+    }
+
+    // unlike `CFLAGS` these only apply to bzip2, e.g. to sanitize just it
+    println!("cargo:rerun-if-env-changed=BZIP2_SYS_EXTRA_CFLAGS");
+    if let Ok(flags) = env::var("BZIP2_SYS_EXTRA_CFLAGS") {
+        for flag in flags.split_whitespace() {
+            cfg.flag(flag);
+        }
+    }
+
+    // a tree of one's own, such as a newer release or one with patches
+    // applied, can be built instead of the bundled 1.0.8
+    println!("cargo:rerun-if-env-changed=BZIP2_SYS_SOURCE_DIR");
+    let src = match env::var_os("BZIP2_SYS_SOURCE_DIR") {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            println!("cargo:rerun-if-changed={}", dir.display());
+            dir
+        }
+        None => env::current_dir().unwrap().join("bzip2-1.0.8"),
+    };
+    let version = source_version(&src);
+
+    if cfg!(feature = "fast-crc") {
+        // checksums decompressed data with `crc.c` rather than a byte at a
+        // time, which needs the patch to the bundled `bzlib.c` for it. A
+        // tree without it would quietly build without the feature
+        let bzlib = src.join("bzlib.c");
+        let patched = fs::read_to_string(&bzlib)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", bzlib.display(), e))
+            .contains("BZ_FAST_CRC");
+        if !patched {
+            panic!(
+                "the `fast-crc` feature of bzip2-sys needs the patch to the bundled \
+                 bzlib.c for it, which {} doesn't have",
+                bzlib.display()
+            );
+        }
+        println!("cargo:rerun-if-changed=crc.c");
+        cfg.define("BZ_FAST_CRC", None).file("crc.c");
+    }
+
+    cfg.include(&src)
         .define("_FILE_OFFSET_BITS", Some("64"))
-        .define("BZ_NO_STDIO", None)
-        .file("bzip2-1.0.8/blocksort.c")
-        .file("bzip2-1.0.8/huffman.c")
-        .file("bzip2-1.0.8/crctable.c")
-        .file("bzip2-1.0.8/randtable.c")
-        .file("bzip2-1.0.8/compress.c")
-        .file("bzip2-1.0.8/decompress.c")
-        .file("bzip2-1.0.8/bzlib.c")
+        .file(src.join("blocksort.c"))
+        .file(src.join("huffman.c"))
+        .file(src.join("crctable.c"))
+        .file(src.join("randtable.c"))
+        .file(src.join("compress.c"))
+        .file(src.join("decompress.c"))
+        .file(src.join("bzlib.c"))
         .out_dir(dst.join("lib"))
         .compile("libbz2.a");
 
-    let src = env::current_dir().unwrap().join("bzip2-1.0.8");
     let include = dst.join("include");
     fs::create_dir_all(&include).unwrap();
     fs::copy(src.join("bzlib.h"), dst.join("include/bzlib.h")).unwrap();
+    generate_bindings(&[&include]);
+    check_layout(&[&include]);
     println!("cargo:root={}", dst.display());
     println!("cargo:include={}", dst.join("include").display());
+    println!("cargo:lib=bz2");
+    println!("cargo:lib_dir={}", dst.join("lib").display());
+    println!("cargo:link=static");
+    println!("cargo:version={}", version);
+}
+
+/// Reads the version of the sources in `src` from the `BZ_VERSION` of
+/// `bzlib_private.h`, which looks like `"1.0.8, 13-Jul-2019"`.
+fn source_version(src: &Path) -> String {
+    let header = src.join("bzlib_private.h");
+    let header = fs::read_to_string(&header)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", header.display(), e));
+    header
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("#define BZ_VERSION"))
+        .filter_map(|rest| rest.trim().trim_matches('"').split(',').next())
+        .map(|version| version.trim().to_string())
+        .next()
+        .unwrap_or_else(|| panic!("no BZ_VERSION in {}", src.display()))
+}
+
+/// Tells the build scripts of dependents how the system libbz2 was linked,
+/// the same way as for the bundled one, through `DEP_BZIP2_INCLUDE`,
+/// `DEP_BZIP2_LIB`, `DEP_BZIP2_LIB_DIR`, `DEP_BZIP2_LINK` and
+/// `DEP_BZIP2_VERSION`.
+fn system_metadata(
+    include_paths: &[PathBuf],
+    lib: Option<&String>,
+    lib_dir: Option<&PathBuf>,
+    statik: bool,
+    version: Option<&str>,
+) {
+    if !include_paths.is_empty() {
+        let include = env::join_paths(include_paths).unwrap();
+        println!("cargo:include={}", include.to_string_lossy());
+    }
+    if let Some(name) = lib {
+        println!("cargo:lib={}", name);
+    }
+    if let Some(dir) = lib_dir {
+        println!("cargo:lib_dir={}", dir.display());
+    }
+    println!("cargo:link={}", if statik { "static" } else { "dynamic" });
+    if let Some(version) = version {
+        println!("cargo:version={}", version);
+    }
+}
+
+/// Looks for a libbz2 installed through vcpkg, returning whether one was
+/// found and linked against.
+#[cfg(target_env = "msvc")]
+fn try_vcpkg() -> bool {
+    let lib = match vcpkg::Config::new().find_package("bzip2") {
+        Ok(lib) => lib,
+        Err(_) => return false,
+    };
+    generate_bindings(&lib.include_paths);
+    check_layout(&lib.include_paths);
+    // vcpkg doesn't tell the version, it's checked at runtime instead
+    system_metadata(
+        &lib.include_paths,
+        lib.found_names.first(),
+        lib.link_paths.first(),
+        lib.is_static,
+        None,
+    );
+    true
 }
+
+#[cfg(not(target_env = "msvc"))]
+fn try_vcpkg() -> bool {
+    false
+}
+
+/// Whether pkg-config links statically when not told either way, which it
+/// infers from the environment like this.
+fn pkg_config_static() -> bool {
+    let set = |var: &str| env::var_os(var).is_some();
+    if set("BZIP2_STATIC") {
+        true
+    } else if set("BZIP2_DYNAMIC") {
+        false
+    } else {
+        set("PKG_CONFIG_ALL_STATIC")
+    }
+}
+
+/// Checks that `bz_stream` in the `bzlib.h` found in `include_paths` is laid
+/// out as `repr(C)` lays out the Rust definition on the target, failing the
+/// build otherwise, and writes `$OUT_DIR/layout.rs` asserting the same of the
+/// Rust side.
+fn check_layout<P: AsRef<Path>>(include_paths: &[P]) {
+    let ptr: usize = env::var("CARGO_CFG_TARGET_POINTER_WIDTH")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap()
+        / 8;
+    let align = |offset: usize| offset.div_ceil(ptr) * ptr;
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    for &(field, size) in &[
+        ("next_in", ptr),
+        ("avail_in", 4),
+        ("total_in_lo32", 4),
+        ("total_in_hi32", 4),
+        ("next_out", ptr),
+        ("avail_out", 4),
+        ("total_out_lo32", 4),
+        ("total_out_hi32", 4),
+        ("state", ptr),
+        ("bzalloc", ptr),
+        ("bzfree", ptr),
+        ("opaque", ptr),
+    ] {
+        if size == ptr {
+            offset = align(offset);
+        }
+        fields.push((field, offset));
+        offset += size;
+    }
+    let size = align(offset);
+
+    // a negative array size fails even compilers without `_Static_assert`
+    let mut c = String::from("#include <stddef.h>\n#include <bzlib.h>\n");
+    let mut rust = String::new();
+    c.push_str(&format!(
+        "typedef char size_check[sizeof(bz_stream) == {} ? 1 : -1];\n",
+        size
+    ));
+    rust.push_str(&format!(
+        "const _: () = assert!(::std::mem::size_of::<bz_stream>() == {});\n",
+        size
+    ));
+    for &(field, offset) in &fields {
+        c.push_str(&format!(
+            "typedef char {0}_check[offsetof(bz_stream, {0}) == {1} ? 1 : -1];\n",
+            field, offset
+        ));
+        rust.push_str(&format!(
+            "const _: () = assert!(::std::mem::offset_of!(bz_stream, {}) == {});\n",
+            field, offset
+        ));
+    }
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("layout.c"), c).unwrap();
+    fs::write(out.join("layout.rs"), rust).unwrap();
+    let mut cfg = cc::Build::new();
+    cfg.warnings(false)
+        .cargo_metadata(false)
+        .define("BZ_NO_STDIO", None)
+        .define("BZ_EXPORT", None)
+        .file(out.join("layout.c"));
+    for path in include_paths {
+        cfg.include(path);
+    }
+    if let Err(e) = cfg.try_compile_intermediates() {
+        panic!(
+            "the bz_stream of bzlib.h isn't laid out as bzip2-sys expects \
+             it to be on this target: {}",
+            e
+        );
+    }
+}
+
+/// Writes bindings for the `bzlib.h` found in `include_paths`, or in the
+/// default include path of the compiler, to `$OUT_DIR/bindings.rs`.
+#[cfg(feature = "bindgen")]
+fn generate_bindings<P: AsRef<Path>>(include_paths: &[P]) {
+    let mut builder = bindgen::Builder::default()
+        .header_contents("wrapper.h", "#include <bzlib.h>")
+        .clang_arg("-DBZ_EXPORT")
+        .allowlist_function("BZ2_bz.*")
+        .allowlist_type("bz_stream")
+        .allowlist_var("BZ_.*")
+        // the hand written constants are `c_int`s
+        .default_macro_constant_type(bindgen::MacroTypeVariation::Signed);
+    if cfg!(feature = "stdio") {
+        // use the opaque `FILE` of lib.rs rather than bindgen's idea of it
+        builder = builder
+            .allowlist_type("BZFILE")
+            .blocklist_type("FILE")
+            .blocklist_type("_IO_FILE")
+            .raw_line("use FILE;");
+    } else {
+        // match how the vendored sources are compiled
+        builder = builder.clang_arg("-DBZ_NO_STDIO");
+    }
+    for path in include_paths {
+        builder = builder.clang_arg(format!("-I{}", path.as_ref().display()));
+    }
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    builder
+        .generate()
+        .expect("failed to generate bindings for bzlib.h")
+        .write_to_file(out.join("bindings.rs"))
+        .expect("failed to write bindings");
+}
+
+#[cfg(not(feature = "bindgen"))]
+fn generate_bindings<P: AsRef<Path>>(_include_paths: &[P]) {}
@@ -528,6 +528,14 @@ int BZ_API(BZ2_bzDecompressInit)
 }
 
 
+#ifdef BZ_FAST_CRC
+/* The output is checksummed all at once by BZ2_crc32Update
+   in BZ2_bzDecompress instead of byte by byte here. */
+extern UInt32 BZ2_crc32Update ( UInt32 crc, const UChar* buf, UInt32 len );
+#undef BZ_UPDATE_CRC
+#define BZ_UPDATE_CRC(crcVar,cha) ((void)0)
+#endif
+
 /*---------------------------------------------------*/
 /* Return  True iff data corruption is discovered.
    Returns False if there is no problem.
@@ -817,9 +825,17 @@ int BZ_API(BZ2_bzDecompress) ( bz_stream *strm )
    while (True) {
       if (s->state == BZ_X_IDLE) return BZ_SEQUENCE_ERROR;
       if (s->state == BZ_X_OUTPUT) {
+#ifdef BZ_FAST_CRC
+         char* out = strm->next_out;
+#endif
          if (s->smallDecompress)
             corrupt = unRLE_obuf_to_output_SMALL ( s ); else
             corrupt = unRLE_obuf_to_output_FAST  ( s );
+#ifdef BZ_FAST_CRC
+         s->calculatedBlockCRC
+            = BZ2_crc32Update ( s->calculatedBlockCRC, (UChar*)out,
+                                (UInt32)(strm->next_out - out) );
+#endif
          if (corrupt) return BZ_DATA_ERROR;
          if (s->nblock_used == s->save_nblock+1 && s->state_out_len == 0) {
             BZ_FINALISE_CRC ( s->calculatedBlockCRC );
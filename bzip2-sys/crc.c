@@ -0,0 +1,144 @@
+/*
+ * The block CRC of decompressed data, computed a whole buffer at a time by
+ * bzlib.c when built with BZ_FAST_CRC rather than a byte at a time.
+ *
+ * On x86 CPUs with PCLMULQDQ the data is folded 64 bytes at a time with
+ * carry-less multiplication, as described in Intel's "Fast CRC Computation
+ * for Generic Polynomials Using PCLMULQDQ Instruction". Everywhere else,
+ * and for short buffers, this is the same table lookup as BZ_UPDATE_CRC.
+ *
+ * bzip2's CRC is the unreflected CRC-32, so the register holds the
+ * remainder of the data seen so far times x^32 modulo P, with the first bit
+ * of the data as the highest coefficient.
+ */
+
+#include "bzlib_private.h"
+
+#include <stddef.h>
+
+#if defined(__GNUC__) && (defined(__x86_64__) || defined(__i386__))
+#define BZ_CRC_CLMUL
+#include <cpuid.h>
+#include <stdatomic.h>
+#include <emmintrin.h>
+#include <tmmintrin.h>
+#include <wmmintrin.h>
+#endif
+
+UInt32 BZ2_crc32Update ( UInt32 crc, const UChar* buf, UInt32 len );
+
+static UInt32 crc_table ( UInt32 crc, const UChar* buf, size_t len )
+{
+   while (len--) crc = (crc << 8) ^ BZ2_crc32Table[(crc >> 24) ^ *buf++];
+   return crc;
+}
+
+#ifdef BZ_CRC_CLMUL
+
+/* folding constants, the high and low halves of a 128 bit lane are
+   multiplied by x^(d+64) and x^d mod P to move them d bits along, where
+   P = x^32 + 0x04c11db7 */
+static const long long k576 = 0x8833794c; /* x^576 mod P */
+static const long long k512 = 0xe6228b11; /* x^512 mod P */
+static const long long k192 = 0xc5b9cd4c; /* x^192 mod P */
+static const long long k128 = 0xe8a45605; /* x^128 mod P */
+
+__attribute__((target("pclmul,ssse3")))
+static __m128i load ( const UChar* buf, __m128i swap )
+{
+   return _mm_shuffle_epi8(_mm_loadu_si128((const __m128i*)buf), swap);
+}
+
+__attribute__((target("pclmul,ssse3")))
+static __m128i fold ( __m128i x, __m128i k, __m128i next )
+{
+   return _mm_xor_si128(_mm_xor_si128(_mm_clmulepi64_si128(x, k, 0x01),
+                                      _mm_clmulepi64_si128(x, k, 0x10)),
+                        next);
+}
+
+/* needs len >= 64 */
+__attribute__((target("pclmul,ssse3")))
+static UInt32 crc_clmul ( UInt32 crc, const UChar* buf, size_t len )
+{
+   /* loads big endian, so the first data bit is the highest one */
+   const __m128i swap = _mm_set_epi8(0, 1, 2, 3, 4, 5, 6, 7,
+                                     8, 9, 10, 11, 12, 13, 14, 15);
+   __m128i k = _mm_set_epi64x(k512, k576);
+   __m128i x0, x1, x2, x3;
+   UChar rest[16];
+
+   x0 = _mm_xor_si128(load(buf, swap), _mm_set_epi32((int)crc, 0, 0, 0));
+   x1 = load(buf + 16, swap);
+   x2 = load(buf + 32, swap);
+   x3 = load(buf + 48, swap);
+   buf += 64;
+   len -= 64;
+   while (len >= 64) {
+      x0 = fold(x0, k, load(buf, swap));
+      x1 = fold(x1, k, load(buf + 16, swap));
+      x2 = fold(x2, k, load(buf + 32, swap));
+      x3 = fold(x3, k, load(buf + 48, swap));
+      buf += 64;
+      len -= 64;
+   }
+
+   k = _mm_set_epi64x(k128, k192);
+   x0 = fold(x0, k, x1);
+   x0 = fold(x0, k, x2);
+   x0 = fold(x0, k, x3);
+   while (len >= 16) {
+      x0 = fold(x0, k, load(buf, swap));
+      buf += 16;
+      len -= 16;
+   }
+
+   /* what's left is congruent to the data so far, reduce it the slow way */
+   _mm_storeu_si128((__m128i*)rest, _mm_shuffle_epi8(x0, swap));
+   crc = crc_table(0, rest, 16);
+   return crc_table(crc, buf, len);
+}
+
+/* 1 once the CPU is known to have PCLMULQDQ and SSSE3 and the result has
+   been checked against the table, -1 if it hasn't or it didn't match.
+   Threads racing to probe all come to the same answer, so it's only ever
+   stored once it's complete and nothing else needs ordering around it */
+static atomic_int clmul_state = 0;
+
+static int set_clmul_state ( int state )
+{
+   atomic_store_explicit(&clmul_state, state, memory_order_relaxed);
+   return state > 0;
+}
+
+static int use_clmul ( void )
+{
+   unsigned int eax, ebx, ecx, edx;
+   UChar check[256];
+   UInt32 i, seed = 1;
+   int state = atomic_load_explicit(&clmul_state, memory_order_relaxed);
+
+   if (state != 0) return state > 0;
+   if (!__get_cpuid(1, &eax, &ebx, &ecx, &edx)
+       || !(ecx & bit_PCLMUL) || !(ecx & bit_SSSE3))
+      return set_clmul_state(-1);
+   for (i = 0; i < sizeof(check); i++) {
+      seed = seed * 1103515245u + 12345u;
+      check[i] = (UChar)(seed >> 16);
+   }
+   for (i = 64; i <= sizeof(check); i += 37) {
+      if (crc_clmul(0xffffffffu, check, i) != crc_table(0xffffffffu, check, i))
+         return set_clmul_state(-1);
+   }
+   return set_clmul_state(1);
+}
+
+#endif
+
+UInt32 BZ2_crc32Update ( UInt32 crc, const UChar* buf, UInt32 len )
+{
+#ifdef BZ_CRC_CLMUL
+   if (len >= 256 && use_clmul()) return crc_clmul(crc, buf, len);
+#endif
+   return crc_table(crc, buf, len);
+}
@@ -1,75 +1,213 @@
 #![doc(html_root_url = "https://docs.rs/bzip2-sys/0.1")]
 
-extern crate libc;
-
-use libc::{c_char, c_int, c_uint, c_void};
-
-pub const BZ_RUN: c_int = 0;
-pub const BZ_FLUSH: c_int = 1;
-pub const BZ_FINISH: c_int = 2;
-
-pub const BZ_OK: c_int = 0;
-pub const BZ_RUN_OK: c_int = 1;
-pub const BZ_FLUSH_OK: c_int = 2;
-pub const BZ_FINISH_OK: c_int = 3;
-pub const BZ_STREAM_END: c_int = 4;
-pub const BZ_SEQUENCE_ERROR: c_int = -1;
-pub const BZ_PARAM_ERROR: c_int = -2;
-pub const BZ_MEM_ERROR: c_int = -3;
-pub const BZ_DATA_ERROR: c_int = -4;
-pub const BZ_DATA_ERROR_MAGIC: c_int = -5;
-pub const BZ_IO_ERROR: c_int = -6;
-pub const BZ_UNEXPECTED_EOF: c_int = -7;
-pub const BZ_OUTBUFF_FULL: c_int = -8;
-pub const BZ_CONFIG_ERROR: c_int = -9;
+use std::ffi::c_int;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{mem, panic, process};
 
-#[repr(C)]
-pub struct bz_stream {
-    pub next_in: *mut c_char,
-    pub avail_in: c_uint,
-    pub total_in_lo32: c_uint,
-    pub total_in_hi32: c_uint,
-
-    pub next_out: *mut c_char,
-    pub avail_out: c_uint,
-    pub total_out_lo32: c_uint,
-    pub total_out_hi32: c_uint,
-
-    pub state: *mut c_void,
-
-    pub bzalloc: Option<extern "C" fn(*mut c_void, c_int, c_int) -> *mut c_void>,
-    pub bzfree: Option<extern "C" fn(*mut c_void, *mut c_void)>,
-    pub opaque: *mut c_void,
+// `malloc` and `free` for the bundled sources where there's no libc
+#[cfg(any(
+    feature = "freestanding",
+    target_os = "hermit",
+    target_os = "uefi",
+    all(target_family = "wasm", target_os = "unknown")
+))]
+mod shim;
+
+#[cfg(feature = "bindgen")]
+#[allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
+mod bindings {
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 }
+#[cfg(feature = "bindgen")]
+pub use bindings::*;
 
-macro_rules! abi_compat {
-    ($(pub fn $name:ident($($arg:ident: $t:ty),*) -> $ret:ty,)*) => {
-        #[cfg(windows)]
-        extern "system" {
-            $(pub fn $name($($arg: $t),*) -> $ret;)*
-        }
-        #[cfg(not(windows))]
-        extern {
-            $(pub fn $name($($arg: $t),*) -> $ret;)*
+#[cfg(not(feature = "bindgen"))]
+pub use manual::*;
+
+// asserts that `bz_stream` is laid out like the C struct, see `build.rs`
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+
+#[cfg(not(feature = "bindgen"))]
+mod manual {
+    use std::ffi::{c_char, c_int, c_uint, c_void};
+
+    pub const BZ_RUN: c_int = 0;
+    pub const BZ_FLUSH: c_int = 1;
+    pub const BZ_FINISH: c_int = 2;
+
+    pub const BZ_OK: c_int = 0;
+    pub const BZ_RUN_OK: c_int = 1;
+    pub const BZ_FLUSH_OK: c_int = 2;
+    pub const BZ_FINISH_OK: c_int = 3;
+    pub const BZ_STREAM_END: c_int = 4;
+    pub const BZ_SEQUENCE_ERROR: c_int = -1;
+    pub const BZ_PARAM_ERROR: c_int = -2;
+    pub const BZ_MEM_ERROR: c_int = -3;
+    pub const BZ_DATA_ERROR: c_int = -4;
+    pub const BZ_DATA_ERROR_MAGIC: c_int = -5;
+    pub const BZ_IO_ERROR: c_int = -6;
+    pub const BZ_UNEXPECTED_EOF: c_int = -7;
+    pub const BZ_OUTBUFF_FULL: c_int = -8;
+    pub const BZ_CONFIG_ERROR: c_int = -9;
+
+    #[repr(C)]
+    pub struct bz_stream {
+        pub next_in: *mut c_char,
+        pub avail_in: c_uint,
+        pub total_in_lo32: c_uint,
+        pub total_in_hi32: c_uint,
+
+        pub next_out: *mut c_char,
+        pub avail_out: c_uint,
+        pub total_out_lo32: c_uint,
+        pub total_out_hi32: c_uint,
+
+        pub state: *mut c_void,
+
+        pub bzalloc: Option<extern "C" fn(*mut c_void, c_int, c_int) -> *mut c_void>,
+        pub bzfree: Option<extern "C" fn(*mut c_void, *mut c_void)>,
+        pub opaque: *mut c_void,
+    }
+
+    macro_rules! abi_compat {
+        ($(pub fn $name:ident($($arg:ident: $t:ty),*) -> $ret:ty,)*) => {
+            #[cfg(windows)]
+            extern "system" {
+                $(pub fn $name($($arg: $t),*) -> $ret;)*
+            }
+            #[cfg(not(windows))]
+            extern "C" {
+                $(pub fn $name($($arg: $t),*) -> $ret;)*
+            }
         }
     }
-}
 
-abi_compat! {
-    pub fn BZ2_bzCompressInit(stream: *mut bz_stream,
-                              blockSize100k: c_int,
+    abi_compat! {
+        pub fn BZ2_bzCompressInit(stream: *mut bz_stream,
+                                  blockSize100k: c_int,
+                                  verbosity: c_int,
+                                  workFactor: c_int) -> c_int,
+        pub fn BZ2_bzCompress(stream: *mut bz_stream, action: c_int) -> c_int,
+        pub fn BZ2_bzCompressEnd(stream: *mut bz_stream) -> c_int,
+        pub fn BZ2_bzDecompressInit(stream: *mut bz_stream,
+                                    verbosity: c_int,
+                                    small: c_int) -> c_int,
+        pub fn BZ2_bzDecompress(stream: *mut bz_stream) -> c_int,
+        pub fn BZ2_bzDecompressEnd(stream: *mut bz_stream) -> c_int,
+        pub fn BZ2_bzBuffToBuffCompress(dest: *mut c_char,
+                                        destLen: *mut c_uint,
+                                        source: *mut c_char,
+                                        sourceLen: c_uint,
+                                        blockSize100k: c_int,
+                                        verbosity: c_int,
+                                        workFactor: c_int) -> c_int,
+        pub fn BZ2_bzBuffToBuffDecompress(dest: *mut c_char,
+                                          destLen: *mut c_uint,
+                                          source: *mut c_char,
+                                          sourceLen: c_uint,
+                                          small: c_int,
+                                          verbosity: c_int) -> c_int,
+        pub fn BZ2_bzlibVersion() -> *const c_char,
+    }
+
+    #[cfg(feature = "stdio")]
+    pub use FILE;
+
+    #[cfg(feature = "stdio")]
+    pub type BZFILE = c_void;
+
+    #[cfg(feature = "stdio")]
+    abi_compat! {
+        pub fn BZ2_bzReadOpen(bzerror: *mut c_int,
+                              f: *mut FILE,
                               verbosity: c_int,
-                              workFactor: c_int) -> c_int,
-    pub fn BZ2_bzCompress(stream: *mut bz_stream, action: c_int) -> c_int,
-    pub fn BZ2_bzCompressEnd(stream: *mut bz_stream) -> c_int,
-    pub fn BZ2_bzDecompressInit(stream: *mut bz_stream,
-                                verbosity: c_int,
-                                small: c_int) -> c_int,
-    pub fn BZ2_bzDecompress(stream: *mut bz_stream) -> c_int,
-    pub fn BZ2_bzDecompressEnd(stream: *mut bz_stream) -> c_int,
+                              small: c_int,
+                              unused: *mut c_void,
+                              nUnused: c_int) -> *mut BZFILE,
+        pub fn BZ2_bzReadClose(bzerror: *mut c_int, b: *mut BZFILE) -> (),
+        pub fn BZ2_bzReadGetUnused(bzerror: *mut c_int,
+                                   b: *mut BZFILE,
+                                   unused: *mut *mut c_void,
+                                   nUnused: *mut c_int) -> (),
+        pub fn BZ2_bzRead(bzerror: *mut c_int,
+                          b: *mut BZFILE,
+                          buf: *mut c_void,
+                          len: c_int) -> c_int,
+        pub fn BZ2_bzWriteOpen(bzerror: *mut c_int,
+                               f: *mut FILE,
+                               blockSize100k: c_int,
+                               verbosity: c_int,
+                               workFactor: c_int) -> *mut BZFILE,
+        pub fn BZ2_bzWrite(bzerror: *mut c_int,
+                           b: *mut BZFILE,
+                           buf: *mut c_void,
+                           len: c_int) -> (),
+        pub fn BZ2_bzWriteClose(bzerror: *mut c_int,
+                                b: *mut BZFILE,
+                                abandon: c_int,
+                                nbytes_in: *mut c_uint,
+                                nbytes_out: *mut c_uint) -> (),
+        pub fn BZ2_bzWriteClose64(bzerror: *mut c_int,
+                                  b: *mut BZFILE,
+                                  abandon: c_int,
+                                  nbytes_in_lo32: *mut c_uint,
+                                  nbytes_in_hi32: *mut c_uint,
+                                  nbytes_out_lo32: *mut c_uint,
+                                  nbytes_out_hi32: *mut c_uint) -> (),
+        pub fn BZ2_bzerror(b: *mut BZFILE, errnum: *mut c_int) -> *const c_char,
+    }
+}
+
+/// The C `FILE` taken by the stdio functions, which are passed pointers to
+/// it from `fopen` and friends, such as `libc::FILE`s cast to this.
+#[cfg(feature = "stdio")]
+#[repr(C)]
+pub struct FILE {
+    _private: [u8; 0],
+}
+
+#[cfg(feature = "fast-crc")]
+extern "C" {
+    /// Continues the block CRC in `crc` over the `len` bytes at `buf`, as the
+    /// bundled libbz2 does for the data it decompresses with the `fast-crc`
+    /// feature.
+    ///
+    /// The CRC starts out as `0xffffffff` and is inverted once a block ends.
+    pub fn BZ2_crc32Update(
+        crc: std::ffi::c_uint,
+        buf: *const std::ffi::c_uchar,
+        len: std::ffi::c_uint,
+    ) -> std::ffi::c_uint;
+}
+
+static INTERNAL_ERROR_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers a function called when libbz2 detects an internal
+/// inconsistency, before the process is aborted.
+///
+/// The function receives the code libbz2 reports, such as 1007 for a CRC
+/// mismatch in the compressor, and can log it or save state, but the process
+/// is aborted once it returns: some of these checks guard array bounds, so
+/// libbz2 can't safely carry on. Panicking in the handler aborts the process
+/// as well, as it can't unwind through libbz2.
+///
+/// Passing `None` restores the default of aborting straight away. The
+/// handler is only called by the bundled sources: a system libbz2 found
+/// through pkg-config or vcpkg, which is preferred unless the `static`
+/// feature is enabled, has its own `bz_internal_error`, and so does the
+/// bundled libbz2 with the `stdio` feature, which prints a message and
+/// exits.
+pub fn set_internal_error_handler(handler: Option<fn(c_int)>) {
+    let handler = handler.map_or(0, |f| f as usize);
+    INTERNAL_ERROR_HANDLER.store(handler, Ordering::SeqCst);
 }
 
 #[no_mangle]
-pub fn bz_internal_error(errcode: c_int) {
-    panic!("bz internal error: {}", errcode);
+pub extern "C" fn bz_internal_error(errcode: c_int) {
+    let handler = INTERNAL_ERROR_HANDLER.load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: fn(c_int) = unsafe { mem::transmute(handler) };
+        let _ = panic::catch_unwind(|| handler(errcode));
+    }
+    process::abort();
 }
@@ -0,0 +1,51 @@
+//! `malloc` and `free` for the bundled sources on targets without a libc,
+//! declared to them by `shim/stdlib.h`.
+//!
+//! They're exported under names of their own, which the header maps
+//! `malloc` and `free` to, so that they never clash with a libc's should the
+//! `freestanding` feature be enabled for a target that has one after all.
+//!
+//! The size of every allocation is stored in a header in front of it so that
+//! `free` can hand the right layout back to the Rust allocator. It is sized
+//! as a `usize`, which is also `size_t`, so this works for wasm64 as well as
+//! for wasm32.
+
+use std::alloc::{self, Layout};
+use std::ffi::c_void;
+use std::mem;
+use std::ptr;
+
+/// The alignment guaranteed by `malloc`, that of `max_align_t`.
+const ALIGN: usize = 16;
+
+/// The room taken up by the header, which keeps the returned memory aligned.
+const HEADER: usize = ALIGN;
+
+const _: () = assert!(mem::size_of::<usize>() <= HEADER);
+
+#[no_mangle]
+pub unsafe extern "C" fn bzip2_sys_malloc(size: usize) -> *mut c_void {
+    let layout = match size
+        .checked_add(HEADER)
+        .and_then(|total| Layout::from_size_align(total, ALIGN).ok())
+    {
+        Some(layout) => layout,
+        None => return ptr::null_mut(),
+    };
+    let base = alloc::alloc(layout);
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    (base as *mut usize).write(layout.size());
+    base.add(HEADER) as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bzip2_sys_free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    let base = (ptr as *mut u8).sub(HEADER);
+    let total = (base as *mut usize).read();
+    alloc::dealloc(base, Layout::from_size_align_unchecked(total, ALIGN));
+}
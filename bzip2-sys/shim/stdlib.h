@@ -0,0 +1,16 @@
+/* The parts of <stdlib.h> which the bzip2 sources use when built with
+ * BZ_NO_STDIO, for targets without a libc. `malloc` and `free` are defined
+ * in Rust, by shim.rs, under names which can't clash with those of a libc.
+ * Nothing else is needed but the <stddef.h> every compiler comes with. */
+#ifndef BZIP2_SYS_SHIM_STDLIB_H
+#define BZIP2_SYS_SHIM_STDLIB_H
+
+#include <stddef.h>
+
+#define malloc bzip2_sys_malloc
+#define free bzip2_sys_free
+
+void *malloc(size_t size);
+void free(void *ptr);
+
+#endif
@@ -0,0 +1,54 @@
+//! Decodes arbitrary data as a single bzip2 stream through the decoders and
+//! the raw `Decompress`, which have to agree with each other.
+
+#![no_main]
+
+use std::io::prelude::*;
+
+use bzip2::{Decompress, Status};
+use libfuzzer_sys::fuzz_target;
+
+// small inputs can decompress to a lot, which is cut off here to keep the
+// fuzzer within its memory limit
+const LIMIT: usize = 1 << 24;
+
+fuzz_target!(|data: &[u8]| {
+    let mut read = Vec::new();
+    let read = bzip2::read::BzDecoder::new(data)
+        .take(LIMIT as u64)
+        .read_to_end(&mut read)
+        .map(|_| read);
+
+    let mut bufread = Vec::new();
+    let bufread = bzip2::bufread::BzDecoder::new(data)
+        .take(LIMIT as u64)
+        .read_to_end(&mut bufread)
+        .map(|_| bufread);
+    assert_eq!(read.as_ref().ok(), bufread.as_ref().ok());
+
+    for small in [false, true] {
+        let raw = raw(data, small);
+        match read {
+            Ok(ref read) if read.len() < LIMIT => assert!(raw.as_ref() == Some(read)),
+            Ok(_) => {}
+            Err(_) => assert!(raw.is_none()),
+        }
+    }
+});
+
+// Decompresses the first stream of `data` with `Decompress`, returning `None`
+// if it fails or the output grows past `LIMIT`.
+fn raw(data: &[u8], small: bool) -> Option<Vec<u8>> {
+    let mut d = Decompress::new(small);
+    let mut out = Vec::with_capacity(4096);
+    loop {
+        let rest = &data[d.total_in() as usize..];
+        match d.decompress_vec(rest, &mut out).ok()? {
+            Status::StreamEnd => return Some(out),
+            _ if out.len() >= LIMIT => return None,
+            _ if out.len() == out.capacity() => out.reserve(out.capacity()),
+            _ if d.total_in() as usize == data.len() => return None,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! Decodes arbitrary data as a multistream, through the multistream decoders
+//! and the one-shot functions, which have to agree with each other.
+
+#![no_main]
+
+use std::io::prelude::*;
+
+use libfuzzer_sys::fuzz_target;
+
+// as for the `decompress` target
+const LIMIT: usize = 1 << 24;
+
+fuzz_target!(|data: &[u8]| {
+    let mut read = Vec::new();
+    let read = bzip2::read::MultiBzDecoder::new(data)
+        .take(LIMIT as u64)
+        .read_to_end(&mut read)
+        .map(|_| read);
+
+    let mut w = bzip2::write::MultiBzDecoder::new(Vec::new());
+    let write = w.write_all(data).and_then(|()| w.finish());
+
+    if let Ok(one_shot) = bzip2::decompress_with_limit(data, LIMIT) {
+        assert!(read.ok() == Some(one_shot.clone()));
+        assert!(write.ok() == Some(one_shot.clone()));
+        let mut buf = vec![0; one_shot.len()];
+        assert_eq!(bzip2::decompress_into(data, &mut buf), Ok(buf.len()));
+        assert!(buf == one_shot);
+    }
+});
@@ -0,0 +1,58 @@
+//! Compresses arbitrary data through each of the compression APIs and checks
+//! that the results decompress back to it.
+
+#![no_main]
+
+use std::io::prelude::*;
+
+use bzip2::{Action, Compress, Compression, Decompress, Status};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &[u8]| {
+    let (level, data) = match input.split_first() {
+        Some((&b, data)) => (Compression::new(u32::from(b % 9) + 1), data),
+        None => return,
+    };
+
+    let compressed = bzip2::compress(data, level);
+    assert!(bzip2::decompress(&compressed).unwrap() == data);
+
+    let mut w = bzip2::write::BzEncoder::new(Vec::new(), level);
+    w.write_all(data).unwrap();
+    assert!(w.finish().unwrap() == compressed);
+
+    let mut read = Vec::new();
+    bzip2::read::BzEncoder::new(data, level)
+        .read_to_end(&mut read)
+        .unwrap();
+    assert!(read == compressed);
+
+    let mut buf = vec![0; data.len() + data.len() / 100 + 600];
+    let n = bzip2::compress_into(data, &mut buf, level).unwrap();
+    assert!(buf[..n] == compressed[..]);
+
+    // the vec based APIs, with the smallest output buffers they can work with
+    let mut c = Compress::new(level, 30);
+    let mut vec = Vec::new();
+    loop {
+        vec.reserve(1);
+        let rest = &data[c.total_in() as usize..];
+        if c.compress_vec(rest, &mut vec, Action::Finish).unwrap() == Status::StreamEnd {
+            break;
+        }
+    }
+    assert!(vec == compressed);
+
+    for small in [false, true] {
+        let mut d = Decompress::new(small);
+        let mut out = Vec::new();
+        loop {
+            out.reserve(1);
+            let rest = &compressed[d.total_in() as usize..];
+            if d.decompress_vec(rest, &mut out).unwrap() == Status::StreamEnd {
+                break;
+            }
+        }
+        assert!(out == data);
+    }
+});
@@ -0,0 +1,456 @@
+//! Poll-based readers and writers driving a `Coder`, independent of which
+//! async I/O traits they end up being exposed through.
+
+// not everything is needed by each of the modules built on top of this
+#![cfg_attr(not(feature = "futures-io"), allow(dead_code))]
+
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
+use std::mem;
+use std::task::{Context, Poll};
+
+use block::Segment;
+use coder::Coder;
+use mem::as_uninit;
+use par::{self, Pending};
+use {BzIndex, Compression, ParConfig, ThreadPool};
+
+macro_rules! ready {
+    ($e:expr) => {
+        match $e {
+            Poll::Ready(t) => t,
+            Poll::Pending => return Poll::Pending,
+        }
+    };
+}
+
+/// A buffered source of bytes.
+pub trait BufSource {
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>>;
+    fn consume(&mut self, amt: usize);
+}
+
+/// An unbuffered source of bytes.
+pub trait Source {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+}
+
+/// A destination for bytes.
+pub trait Sink {
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>;
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// A source which can seek, split into starting a seek and waiting for it
+/// like tokio does.
+pub trait Seeker {
+    fn start_seek(&mut self, pos: u64) -> io::Result<()>;
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<u64>>;
+}
+
+/// Adds a buffer to a `Source`.
+pub struct BufReader<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<R> BufReader<R> {
+    pub fn new(inner: R) -> BufReader<R> {
+        BufReader {
+            inner,
+            buf: vec![0; 32 * 1024].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Source> BufSource for BufReader<R> {
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        if self.pos == self.cap {
+            self.cap = ready!(self.inner.poll_read(cx, &mut self.buf))?;
+            self.pos = 0;
+        }
+        Poll::Ready(Ok(&self.buf[self.pos..self.cap]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.cap);
+    }
+}
+
+/// Codes everything read from `inner`.
+pub struct Reader<R, C> {
+    pub inner: R,
+    pub coder: C,
+    pub budget: Option<usize>,
+}
+
+impl<R: BufSource, C: Coder> Reader<R, C> {
+    pub fn new(inner: R, coder: C) -> Reader<R, C> {
+        Reader {
+            inner,
+            coder,
+            budget: None,
+        }
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() || self.coder.done() {
+            return Poll::Ready(Ok(0));
+        }
+        // bytes consumed in this call without producing anything
+        let mut work = 0;
+        let budget = self.budget.unwrap_or(usize::MAX).max(1);
+        let len = buf.len().min(budget);
+        let buf = &mut buf[..len];
+        loop {
+            if work >= budget {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let input = ready!(self.inner.poll_fill_buf(cx))?;
+            let input = &input[..input.len().min(budget - work)];
+            if input.is_empty() {
+                // the coder produces output or an error until it's done
+                return Poll::Ready(
+                    self.coder
+                        .finish(unsafe { as_uninit(buf) })
+                        .map(|(produced, _)| produced),
+                );
+            }
+            let (consumed, produced) = self.coder.run(input, unsafe { as_uninit(buf) })?;
+            self.inner.consume(consumed);
+            if produced > 0 || self.coder.done() {
+                return Poll::Ready(Ok(produced));
+            }
+            work += consumed;
+        }
+    }
+}
+
+/// Codes everything written into `inner`.
+///
+/// Output is staged in a buffer of our own so that a pending `inner` never
+/// loses data, which makes every method safe to cancel and call again.
+pub struct Writer<W, C> {
+    pub inner: W,
+    pub coder: C,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    flushed: bool,
+    finished: bool,
+}
+
+impl<W: Sink, C: Coder> Writer<W, C> {
+    pub fn new(inner: W, coder: C) -> Writer<W, C> {
+        Writer {
+            inner,
+            coder,
+            buf: vec![0; 32 * 1024].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            flushed: true,
+            finished: false,
+        }
+    }
+
+    fn dump(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.start < self.end {
+            let n = ready!(self.inner.poll_write(cx, &self.buf[self.start..self.end]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.start += n;
+        }
+        self.start = 0;
+        self.end = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.dump(cx))?;
+            let (consumed, produced) = self.coder.run(data, unsafe { as_uninit(&mut self.buf) })?;
+            self.end = produced;
+            self.flushed = false;
+            if consumed > 0 || data.is_empty() || self.coder.done() {
+                return Poll::Ready(Ok(consumed));
+            }
+        }
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.dump(cx))?;
+            if self.flushed {
+                break;
+            }
+            let (produced, done) = self.coder.flush(unsafe { as_uninit(&mut self.buf) })?;
+            self.end = produced;
+            self.flushed = done;
+        }
+        self.inner.poll_flush(cx)
+    }
+
+    pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.dump(cx))?;
+            if self.finished {
+                break;
+            }
+            let (produced, done) = self.coder.finish(unsafe { as_uninit(&mut self.buf) })?;
+            self.end = produced;
+            self.finished = done;
+        }
+        self.inner.poll_close(cx)
+    }
+}
+
+/// Compresses everything written into `inner` on the threads of a pool, one
+/// member per chunk, like `write::ParBzEncoder` does.
+///
+/// The calling task only copies data around, it's woken up by the pool once
+/// the member it waits for has been compressed.
+pub struct ParWriter<W> {
+    pub inner: W,
+    level: Compression,
+    pool: ThreadPool,
+    max_in_flight: usize,
+    chunk_size: usize,
+    chunk: Vec<u8>,
+    pending: VecDeque<Pending<io::Result<Vec<u8>>>>,
+    out: Vec<u8>,
+    pos: usize,
+    members: u64,
+    pub total_in: u64,
+    pub total_out: u64,
+}
+
+impl<W: Sink> ParWriter<W> {
+    pub fn new(inner: W, level: Compression, pool: &ThreadPool, config: ParConfig) -> ParWriter<W> {
+        ParWriter {
+            inner,
+            level,
+            max_in_flight: config.max_in_flight_for(pool),
+            chunk_size: config.chunk_size_or(par::chunk_size(level)),
+            pool: pool.clone(),
+            chunk: Vec::new(),
+            pending: VecDeque::new(),
+            out: Vec::new(),
+            pos: 0,
+            members: 0,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    // Hands the current chunk to the pool, first making room if too many
+    // chunks are in flight already.
+    fn poll_submit(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending.len() >= self.max_in_flight {
+            ready!(self.poll_write_member(cx))?;
+        }
+        let chunk = mem::take(&mut self.chunk);
+        let level = self.level;
+        self.members += 1;
+        self.pending
+            .push_back(self.pool.spawn(move || par::compress_member(&chunk, level)));
+        Poll::Ready(Ok(()))
+    }
+
+    // Writes out the oldest member once it has been compressed.
+    fn poll_write_member(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.pos == self.out.len() {
+            match self.pending.front_mut() {
+                Some(member) => self.out = ready!(member.poll(cx))?,
+                None => return Poll::Ready(Ok(())),
+            }
+            self.pending.pop_front();
+            self.pos = 0;
+        }
+        while self.pos < self.out.len() {
+            let n = ready!(self.inner.poll_write(cx, &self.out[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+            self.total_out += n as u64;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write_all_members(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.out.len() || !self.pending.is_empty() {
+            ready!(self.poll_write_member(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        if self.chunk.len() == self.chunk_size {
+            ready!(self.poll_submit(cx))?;
+        }
+        let n = data.len().min(self.chunk_size - self.chunk.len());
+        self.chunk.extend_from_slice(&data[..n]);
+        self.total_in += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.chunk.is_empty() {
+            ready!(self.poll_submit(cx))?;
+        }
+        ready!(self.poll_write_all_members(cx))?;
+        self.inner.poll_flush(cx)
+    }
+
+    pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // an empty input still needs to produce an (empty) member
+        if !self.chunk.is_empty() || self.members == 0 {
+            ready!(self.poll_submit(cx))?;
+        }
+        ready!(self.poll_write_all_members(cx))?;
+        self.inner.poll_close(cx)
+    }
+}
+
+// Progress of loading a block into `SeekReader`.
+enum Load {
+    Idle,
+    Seeking(usize),
+    Reading(usize, Vec<u8>, usize),
+}
+
+/// Reads from anywhere in the decompressed data of `inner`, like
+/// `read::BzSeekDecoder` does.
+pub struct SeekReader<R> {
+    pub inner: R,
+    pub index: BzIndex,
+    start: u64,
+    len: u64,
+    pos: u64,
+    // the block last read from along with its decompressed data
+    cur: Option<(usize, Vec<u8>)>,
+    load: Load,
+}
+
+impl<R: Source + Seeker> SeekReader<R> {
+    pub fn new(inner: R, start: u64, index: BzIndex) -> SeekReader<R> {
+        SeekReader {
+            inner,
+            start,
+            len: index.uncompressed_size(),
+            index,
+            pos: 0,
+            cur: None,
+            load: Load::Idle,
+        }
+    }
+
+    // Makes block `i` the current one. A seek which is still in progress is
+    // completed first, even if it was for another block.
+    fn poll_load(&mut self, cx: &mut Context<'_>, i: usize) -> Poll<io::Result<()>> {
+        loop {
+            let block = &self.index.blocks()[i];
+            let skip = block.bit_offset % 8;
+            match self.load {
+                Load::Seeking(j) => {
+                    ready!(self.inner.poll_complete(cx))?;
+                    self.load = if i == j {
+                        let len = (skip + block.bits).div_ceil(8) as usize;
+                        Load::Reading(i, vec![0; len], 0)
+                    } else {
+                        Load::Idle
+                    };
+                }
+                Load::Reading(j, ref mut buf, ref mut filled) if i == j => {
+                    while *filled < buf.len() {
+                        let n = ready!(self.inner.poll_read(cx, &mut buf[*filled..]))?;
+                        if n == 0 {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        *filled += n;
+                    }
+                    let data = Segment::new(buf, skip, skip + block.bits).decompress(block.digit);
+                    self.load = Load::Idle;
+                    let data = data?;
+                    if data.len() as u64 != block.len {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "bzip2: block changed since it was indexed",
+                        )));
+                    }
+                    self.cur = Some((i, data));
+                    return Poll::Ready(Ok(()));
+                }
+                Load::Idle | Load::Reading(..) => {
+                    self.inner.start_seek(self.start + block.bit_offset / 8)?;
+                    self.load = Load::Seeking(i);
+                }
+            }
+        }
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Poll::Ready(Ok(0));
+        }
+        let pos = self.pos;
+        let blocks = self.index.blocks();
+        let i = blocks.partition_point(|b| b.offset + b.len <= pos);
+        let offset = blocks[i].offset;
+        match self.cur {
+            Some((j, _)) if i == j => {}
+            _ => ready!(self.poll_load(cx, i))?,
+        }
+        let data = &self.cur.as_ref().unwrap().1;
+        let from = (pos - offset) as usize;
+        let n = buf.len().min(data.len() - from);
+        buf[..n].copy_from_slice(&data[from..from + n]);
+        self.pos += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Moves to a new position, which only takes effect on the next read.
+    pub fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.len, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base.checked_add_signed(delta) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
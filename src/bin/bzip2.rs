@@ -0,0 +1,242 @@
+//! A small `bzip2` command line tool on top of this crate, built with the
+//! `cli` feature.
+//!
+//! It mimics the behavior of the classic `bzip2` and `bzip2recover` tools
+//! closely enough for most scripts: files are replaced by their compressed or
+//! decompressed versions unless `-k` or `-c` is given, and standard input is
+//! used if no files are.
+
+extern crate bzip2;
+
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+
+const USAGE: &str = "\
+usage: bzip2 <command> [options] [FILE]...
+
+commands:
+    compress      compress each FILE into FILE.bz2
+    decompress    decompress each FILE.bz2 into FILE
+    test          check the integrity of each FILE
+    recover       extract the intact blocks of each damaged FILE
+
+options:
+    -1 .. -9      block size in units of 100k when compressing (default 9)
+    -c, --stdout  write to standard output, keeping the input files
+    -f, --force   overwrite existing output files
+    -k, --keep    keep the input files
+
+Standard input is read if no FILE is given.";
+
+#[derive(PartialEq, Eq, Copy, Clone)]
+enum Command {
+    Compress,
+    Decompress,
+    Test,
+    Recover,
+}
+
+struct Options {
+    command: Command,
+    level: Compression,
+    stdout: bool,
+    force: bool,
+    keep: bool,
+    files: Vec<PathBuf>,
+}
+
+fn main() {
+    let opts = match parse(env::args_os().skip(1)) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("bzip2: {}\n\n{}", msg, USAGE);
+            process::exit(1);
+        }
+    };
+
+    let mut failed = false;
+    if opts.files.is_empty() {
+        if let Err(e) = run_stdio(&opts) {
+            eprintln!("bzip2: (stdin): {}", e);
+            failed = true;
+        }
+    }
+    for file in &opts.files {
+        if let Err(e) = run_file(&opts, file) {
+            eprintln!("bzip2: {}: {}", file.display(), e);
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn parse<I: Iterator<Item = std::ffi::OsString>>(mut args: I) -> Result<Options, String> {
+    let command = match args.next().as_ref().and_then(|a| a.to_str()) {
+        Some("compress") => Command::Compress,
+        Some("decompress") => Command::Decompress,
+        Some("test") => Command::Test,
+        Some("recover") => Command::Recover,
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        Some(other) => return Err(format!("unknown command `{}`", other)),
+        None => return Err("no command given".to_string()),
+    };
+    let mut opts = Options {
+        command,
+        level: Compression::best(),
+        stdout: false,
+        force: false,
+        keep: false,
+        files: Vec::new(),
+    };
+    let mut only_files = false;
+    for arg in args {
+        let flag = match arg.to_str() {
+            Some(flag) if !only_files && flag.starts_with('-') && flag.len() > 1 => flag,
+            _ => {
+                opts.files.push(PathBuf::from(arg));
+                continue;
+            }
+        };
+        match flag {
+            "--" => only_files = true,
+            "-c" | "--stdout" => opts.stdout = true,
+            "-f" | "--force" => opts.force = true,
+            "-k" | "--keep" => opts.keep = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => match flag[1..].parse::<u32>() {
+                Ok(level @ 1..=9) if flag.len() == 2 => opts.level = Compression::new(level),
+                _ => return Err(format!("unknown option `{}`", flag)),
+            },
+        }
+    }
+    if opts.command == Command::Recover && opts.files.is_empty() {
+        return Err("recover needs the files to recover from".to_string());
+    }
+    Ok(opts)
+}
+
+fn run_stdio(opts: &Options) -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match opts.command {
+        Command::Compress => {
+            let mut encoder = BzEncoder::new(stdout.lock(), opts.level);
+            io::copy(&mut stdin.lock(), &mut encoder)?;
+            encoder.finish()?.flush()
+        }
+        Command::Decompress => {
+            io::copy(&mut MultiBzDecoder::new(stdin.lock()), &mut stdout.lock())?;
+            stdout.lock().flush()
+        }
+        Command::Test => bzip2::verify(stdin.lock()).map(|_| ()),
+        Command::Recover => unreachable!(),
+    }
+}
+
+fn run_file(opts: &Options, src: &Path) -> io::Result<()> {
+    if opts.command == Command::Test {
+        return bzip2::verify(io::BufReader::new(File::open(src)?)).map(|_| ());
+    }
+    if opts.command == Command::Recover {
+        return recover(src);
+    }
+
+    if opts.stdout {
+        let input = File::open(src)?;
+        let stdout = io::stdout();
+        return if opts.command == Command::Compress {
+            let mut encoder = BzEncoder::new(stdout.lock(), opts.level);
+            io::copy(&mut { input }, &mut encoder)?;
+            encoder.finish()?.flush()
+        } else {
+            let mut decoder = MultiBzDecoder::new(io::BufReader::new(input));
+            io::copy(&mut decoder, &mut stdout.lock())?;
+            stdout.lock().flush()
+        };
+    }
+
+    let dst = if opts.command == Command::Compress {
+        let mut dst = src.as_os_str().to_owned();
+        dst.push(".bz2");
+        PathBuf::from(dst)
+    } else {
+        decompressed_name(src)
+    };
+    if !opts.force && dst.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("output file {} already exists", dst.display()),
+        ));
+    }
+    if opts.command == Command::Compress {
+        bzip2::fs::compress_file(src, &dst, opts.level)?;
+    } else {
+        bzip2::fs::decompress_file(src, &dst)?;
+    }
+    if !opts.keep {
+        fs::remove_file(src)?;
+    }
+    Ok(())
+}
+
+/// Picks the name to decompress `src` to the way `bzip2` does.
+fn decompressed_name(src: &Path) -> PathBuf {
+    let name = src.to_string_lossy();
+    for &(from, to) in &[
+        (".bz2", ""),
+        (".bz", ""),
+        (".tbz2", ".tar"),
+        (".tbz", ".tar"),
+    ] {
+        if name.len() > from.len() && name.ends_with(from) {
+            return PathBuf::from(format!("{}{}", &name[..name.len() - from.len()], to));
+        }
+    }
+    PathBuf::from(format!("{}.out", name))
+}
+
+// what `bzip2recover` does: every block which is followed by another magic
+// number is written out as a stream of its own, whether or not it's intact,
+// so that the damaged ones can be told apart by testing them
+fn recover(src: &Path) -> io::Result<()> {
+    let data = fs::read(src)?;
+    let block_size = match data.get(..4) {
+        Some([b'B', b'Z', b'h', digit @ b'1'..=b'9']) => u32::from(digit - b'0'),
+        _ => 9,
+    };
+
+    let dir = src.parent().unwrap_or_else(|| Path::new(""));
+    let name = src.file_name().unwrap().to_string_lossy();
+    let name = name.strip_suffix(".bz2").unwrap_or(&name);
+    let mut written = 0;
+    for (bit_offset, bits) in bzip2::candidate_blocks(&data) {
+        written += 1;
+        let stream = bzip2::block_stream(&data, bit_offset, bits, block_size);
+        let path = dir.join(format!("rec{:05}{}.bz2", written, name));
+        fs::write(&path, stream)?;
+        eprintln!("bzip2: writing block {} to {}", written, path.display());
+    }
+    if written == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no blocks found to recover",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,538 @@
+//! Bit-level access to the blocks of a bzip2 stream.
+//!
+//! Blocks in a bzip2 stream aren't byte aligned, but each starts with a 48-bit
+//! magic number, and the stream ends with a different one followed by a CRC
+//! over all blocks. Any block can be decompressed on its own by copying its
+//! bits into a new stream which holds only that block.
+
+use std::io;
+use std::io::prelude::*;
+
+use buffers;
+use header::Header;
+use par;
+use {Decompress, Error, Status};
+
+/// The magic number at the start of every block (the BCD digits of pi).
+pub const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+
+/// The magic number marking the end of a stream (the BCD digits of sqrt(pi)).
+pub const EOS_MAGIC: u64 = 0x1772_4538_5090;
+
+/// Length of `BLOCK_MAGIC` and `EOS_MAGIC` in bits.
+pub const MAGIC_BITS: u64 = 48;
+
+/// An upper bound on the length in bits of a block of a stream with block
+/// size digit `digit` (`b'1'` to `b'9'`), magic number included.
+///
+/// A block holds at most `digit` times 100k bytes, each coded as at most one
+/// symbol of at most 20 bits, after a header of at most 18002 selectors and
+/// six code tables.
+pub fn max_block_bits(digit: u8) -> u64 {
+    let symbols = u64::from(digit - b'0') * 100_000 + 1;
+    let header = MAGIC_BITS + 32 + 1 + 24 + 16 + 16 * 16 + 3 + 15;
+    let tables = 18_002 * 7 + 6 * (5 + 258 * 41);
+    header + tables + symbols * 20
+}
+
+/// Which of the magic numbers was found by `find_magic`.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Magic {
+    Block,
+    End,
+}
+
+/// Reads `n` bits (at most 57) starting at bit `pos` of `data`, most
+/// significant bit first. Bits past the end of `data` read as zero.
+pub fn read_bits(data: &[u8], pos: u64, n: u32) -> u64 {
+    debug_assert!(n <= 57);
+    let first = (pos / 8) as usize;
+    let off = (pos % 8) as u32;
+    let nbytes = (off + n).div_ceil(8);
+    let mut v = 0u64;
+    for i in 0..nbytes as usize {
+        v = (v << 8) | u64::from(data.get(first + i).copied().unwrap_or(0));
+    }
+    (v >> (nbytes * 8 - off - n)) & ((1 << n) - 1)
+}
+
+/// Finds the first block or end of stream magic in `data` starting at or
+/// after bit `from`.
+///
+/// The magic numbers may also appear by chance inside of compressed data, so
+/// a match is only a candidate.
+pub fn find_magic(data: &[u8], from: u64) -> Option<(u64, Magic)> {
+    let total = data.len() as u64 * 8;
+    if from + MAGIC_BITS > total {
+        return None;
+    }
+    let mask = (1 << MAGIC_BITS) - 1;
+    let mut window = read_bits(data, from, MAGIC_BITS as u32);
+    let mut pos = from;
+    loop {
+        match window & mask {
+            BLOCK_MAGIC => return Some((pos, Magic::Block)),
+            EOS_MAGIC => return Some((pos, Magic::End)),
+            _ => {}
+        }
+        let next = pos + MAGIC_BITS;
+        if next >= total {
+            return None;
+        }
+        let bit = (data[(next / 8) as usize] >> (7 - next % 8)) & 1;
+        window = (window << 1) | u64::from(bit);
+        pos += 1;
+    }
+}
+
+/// The bits of a single block, starting with its magic number.
+#[derive(Clone)]
+pub struct Segment {
+    bytes: Vec<u8>,
+    start: u8,
+    len: u64,
+    offset: u64,
+}
+
+impl Segment {
+    /// Copies out bits `start..end` of `data`.
+    pub fn new(data: &[u8], start: u64, end: u64) -> Segment {
+        Segment {
+            bytes: data[(start / 8) as usize..end.div_ceil(8) as usize].to_vec(),
+            start: (start % 8) as u8,
+            len: end - start,
+            offset: start,
+        }
+    }
+
+    /// Bit offset of this segment within the data it was read from.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Length of this segment in bits.
+    pub fn bits(&self) -> u64 {
+        self.len
+    }
+
+    /// The CRC of the block's uncompressed data, stored right after its magic.
+    pub fn crc(&self) -> u32 {
+        read_bits(&self.bytes, u64::from(self.start) + MAGIC_BITS, 32) as u32
+    }
+
+    /// Appends `next`, which must directly follow this segment in the stream.
+    pub fn join(&mut self, next: Segment) {
+        let end = u64::from(self.start) + self.len;
+        self.bytes.truncate((end / 8) as usize);
+        self.bytes.extend_from_slice(&next.bytes);
+        self.len += next.len;
+    }
+
+    /// Builds a complete bzip2 stream holding only this block, with the
+    /// given block size digit (`b'1'` to `b'9'`) in its header.
+    pub fn to_stream(&self, digit: u8) -> Vec<u8> {
+        let mut w = BitWriter {
+            out: Vec::with_capacity(self.bytes.len() + 14),
+            acc: 0,
+            bits: 0,
+        };
+        for &b in b"BZh".iter().chain(Some(&digit)) {
+            w.write(u64::from(b), 8);
+        }
+        let mut pos = u64::from(self.start);
+        let end = pos + self.len;
+        while pos < end {
+            let n = (end - pos).min(32) as u32;
+            w.write(read_bits(&self.bytes, pos, n), n);
+            pos += u64::from(n);
+        }
+        // a single block's CRC is also the combined CRC of the stream
+        w.write(EOS_MAGIC, MAGIC_BITS as u32);
+        w.write(u64::from(self.crc()), 32);
+        w.finish()
+    }
+
+    /// Decompresses this block on its own.
+    pub fn decompress(&self, digit: u8) -> io::Result<Vec<u8>> {
+        let member = par::decompress_member(self.to_stream(digit));
+        member.output?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "decompression not finished but EOF reached",
+            )
+        })
+    }
+
+    /// Decompresses this block on its own only to count the bytes it holds.
+    pub fn decompressed_len(&self, digit: u8) -> io::Result<u64> {
+        let input = self.to_stream(digit);
+        let mut d = Decompress::new(false);
+        let mut buf = [0; 32 * 1024];
+        loop {
+            let (consumed, produced) = (d.total_in(), d.total_out());
+            let status = d
+                .decompress(&input[consumed as usize..], &mut buf)
+                .map_err(io::Error::from)?;
+            match status {
+                Status::StreamEnd => return Ok(d.total_out()),
+                Status::MemNeeded => return Err(Error::Mem.into()),
+                _ => {}
+            }
+            if d.total_in() == consumed && d.total_out() == produced {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "decompression not finished but EOF reached",
+                ));
+            }
+        }
+    }
+}
+
+/// What `Scanner::next` found.
+pub enum Item {
+    /// A (candidate) block of the current stream.
+    Block(Segment),
+    /// The end of the current stream, with the combined CRC stored there.
+    End(u32),
+}
+
+/// Splits bzip2 streams read from a reader into their blocks, without
+/// decompressing anything.
+pub struct Scanner<R> {
+    obj: R,
+    // compressed data which hasn't been handed out yet, `base` is the bit
+    // offset of its start within everything read and `cur` the bit offset of
+    // the next item within it
+    input: Vec<u8>,
+    base: u64,
+    cur: u64,
+    scan_from: u64,
+    digit: Option<u8>,
+    // where the next stream starts and the digit of the one which ended, kept
+    // until the next stream is read in case the end was found by chance
+    end: Option<(u64, u8)>,
+    eof: bool,
+    read_size: u64,
+    total_in: u64,
+}
+
+impl<R: Read> Scanner<R> {
+    pub fn new(obj: R) -> Scanner<R> {
+        Scanner {
+            obj,
+            input: Vec::new(),
+            base: 0,
+            cur: 0,
+            scan_from: 0,
+            digit: None,
+            end: None,
+            eof: false,
+            read_size: buffers::chunk_size() as u64,
+            total_in: 0,
+        }
+    }
+
+    /// Sets how much data is read from the underlying reader at once.
+    pub fn set_read_size(&mut self, size: usize) {
+        self.read_size = size as u64;
+    }
+
+    fn read_more(&mut self) -> io::Result<()> {
+        let n = self
+            .obj
+            .by_ref()
+            .take(self.read_size)
+            .read_to_end(&mut self.input)?;
+        self.total_in += n as u64;
+        if n == 0 {
+            self.eof = true;
+        }
+        Ok(())
+    }
+
+    // Makes sure at least `bits` bits of input are buffered.
+    fn need(&mut self, bits: u64) -> io::Result<()> {
+        while (self.input.len() as u64) * 8 < bits {
+            if self.eof {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "decompression not finished but EOF reached",
+                ));
+            }
+            self.read_more()?;
+        }
+        Ok(())
+    }
+
+    // Drops the input before bit `pos`, keeping the byte it falls into.
+    fn consume(&mut self, pos: u64) {
+        self.input.drain(..(pos / 8) as usize);
+        self.base += pos / 8 * 8;
+        self.cur = pos % 8;
+    }
+
+    /// Reads the header of the next stream, returning `false` if the input
+    /// ended instead.
+    pub fn next_stream(&mut self) -> io::Result<bool> {
+        if let Some((end, _)) = self.end.take() {
+            self.consume(end);
+        }
+        debug_assert!(self.digit.is_none() && self.cur == 0);
+        while self.input.len() < 4 && !self.eof {
+            self.read_more()?;
+        }
+        if self.input.is_empty() {
+            return Ok(false);
+        }
+        let header = Header::parse(&self.input)?;
+        self.digit = Some(header.to_bytes()[3]);
+        self.cur = 32;
+        self.scan_from = 32 + MAGIC_BITS;
+        Ok(true)
+    }
+
+    /// Splits the next block off the current stream.
+    ///
+    /// Blocks are split at the next magic number found, so a magic number
+    /// which appears by chance in the compressed data splits a block in two.
+    /// Such a block fails to decompress and has to be joined with the next
+    /// one. The same goes for an end of stream magic number, see `past_end`.
+    pub fn next(&mut self) -> io::Result<Item> {
+        debug_assert!(self.digit.is_some());
+        self.need(self.cur + MAGIC_BITS)?;
+        match read_bits(&self.input, self.cur, MAGIC_BITS as u32) {
+            BLOCK_MAGIC => {}
+            EOS_MAGIC => {
+                let pos = self.cur + MAGIC_BITS;
+                self.need(pos + 32)?;
+                let crc = read_bits(&self.input, pos, 32) as u32;
+                // the next stream starts on a byte boundary
+                let end = (pos + 32).div_ceil(8) * 8;
+                self.end = Some((end, self.digit.take().unwrap()));
+                return Ok(Item::End(crc));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bzip2: corrupt compressed data, no block or stream end marker where \
+                     one was expected",
+                ))
+            }
+        }
+
+        loop {
+            if let Some((end, _)) = find_magic(&self.input, self.scan_from) {
+                let mut segment = Segment::new(&self.input, self.cur, end);
+                segment.offset += self.base;
+                self.consume(end);
+                self.scan_from = self.cur + MAGIC_BITS;
+                return Ok(Item::Block(segment));
+            }
+            // a magic may straddle what's buffered and what's read next
+            self.scan_from = (self.input.len() as u64 * 8)
+                .saturating_sub(MAGIC_BITS - 1)
+                .max(self.cur + MAGIC_BITS);
+            self.need(self.input.len() as u64 * 8 + 1)?;
+        }
+    }
+
+    /// Returns the bits from the end of stream magic number `next` just
+    /// returned up to the next magic number, if there's one within
+    /// `max_bits`.
+    ///
+    /// An end of stream magic number which appeared by chance inside of a
+    /// block splits it just like a block magic number does, so the block
+    /// before it fails to decompress. Joined with the segment returned here it
+    /// may not, in which case the stream hasn't ended after all and `resume`
+    /// moves past the segment. Nothing is consumed until then.
+    pub fn past_end(&mut self, max_bits: u64) -> io::Result<Option<Segment>> {
+        debug_assert!(self.end.is_some());
+        let start = self.cur;
+        let mut from = start + MAGIC_BITS;
+        loop {
+            if let Some((end, _)) = find_magic(&self.input, from) {
+                if end - start > max_bits {
+                    return Ok(None);
+                }
+                let mut segment = Segment::new(&self.input, start, end);
+                segment.offset += self.base;
+                return Ok(Some(segment));
+            }
+            let len = self.input.len() as u64 * 8;
+            if self.eof || len >= start + max_bits + MAGIC_BITS {
+                return Ok(None);
+            }
+            from = len.saturating_sub(MAGIC_BITS - 1).max(start + MAGIC_BITS);
+            self.read_more()?;
+        }
+    }
+
+    /// Continues the stream `next` found the end of after `segment`, which
+    /// `past_end` returned.
+    pub fn resume(&mut self, segment: &Segment) {
+        let (_, digit) = self.end.take().expect("not at the end of a stream");
+        self.consume(segment.offset() + segment.bits() - self.base);
+        self.scan_from = self.cur + MAGIC_BITS;
+        self.digit = Some(digit);
+    }
+}
+
+impl<R> Scanner<R> {
+    /// The block size digit of the current stream, if inside of one.
+    pub fn digit(&self) -> Option<u8> {
+        self.digit
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.obj
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.obj
+    }
+
+    pub fn into_inner(self) -> R {
+        self.obj
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+}
+
+/// Where a block is in a compressed file and where its data is in the
+/// decompressed output.
+pub struct Entry {
+    /// Bit offset of the block's magic number.
+    pub bit_offset: u64,
+    /// Length of the block in bits.
+    pub bits: u64,
+    /// Block size digit of the stream the block is in.
+    pub digit: u8,
+    /// Offset of the block's data in the decompressed output.
+    pub offset: u64,
+    /// Length of the block's decompressed data.
+    pub len: u64,
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, v: u64, n: u32) {
+        self.acc = (self.acc << n) | v;
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.out.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.write(0, pad);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_magic, max_block_bits, read_bits, Magic, Segment, EOS_MAGIC, MAGIC_BITS};
+    use rand::{thread_rng, Rng};
+    use std::io::Read;
+    use {read, BzIndex, Compression};
+
+    #[test]
+    fn bits() {
+        let data = [0b1010_1100, 0b0101_0011];
+        assert_eq!(read_bits(&data, 0, 4), 0b1010);
+        assert_eq!(read_bits(&data, 6, 4), 0b0001);
+        assert_eq!(read_bits(&data, 12, 8), 0b0011_0000);
+    }
+
+    #[test]
+    fn split_blocks() {
+        let mut m = vec![0u8; 250 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        read::BzEncoder::new(&m[..], Compression::fast())
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        let mut pos = 32;
+        let mut data = Vec::new();
+        loop {
+            let (next, magic) = find_magic(&compressed, pos + MAGIC_BITS).unwrap();
+            let segment = Segment::new(&compressed, pos, next);
+            assert!(segment.bits() <= max_block_bits(b'1'));
+            let block = segment.decompress(b'1').unwrap();
+            assert_eq!(segment.decompressed_len(b'1').unwrap(), block.len() as u64);
+            data.extend(block);
+            pos = next;
+            if magic == Magic::End {
+                break;
+            }
+        }
+        assert!(data == m);
+    }
+    #[test]
+    fn chance_end_of_stream() {
+        // a block lists which of the 16 ranges of 16 byte values its data uses
+        // and which values of each of them, with one bit per value, so the
+        // first three ranges spell the end of stream magic number when all
+        // ranges are used by at least one value
+        let mut values = Vec::new();
+        for range in 0..16 {
+            let used = match range {
+                0..=2 => (EOS_MAGIC >> (32 - 16 * range)) & 0xffff,
+                _ => 0x8000,
+            };
+            values.extend(
+                (0..16)
+                    .filter(|i| used & (0x8000 >> i) != 0)
+                    .map(|i| range * 16 + i as u8),
+            );
+        }
+        // no runs, which would add their lengths to the values used
+        let mut rng = thread_rng();
+        let mut m = vec![values[0]];
+        while m.len() < 250 * 1000 {
+            let b = values[rng.gen_range(0..values.len())];
+            if b != m[m.len() - 1] {
+                m.push(b);
+            }
+        }
+        let mut compressed = Vec::new();
+        read::BzEncoder::new(&m[..], Compression::fast())
+            .read_to_end(&mut compressed)
+            .unwrap();
+        let (pos, magic) = find_magic(&compressed, 32 + MAGIC_BITS).unwrap();
+        assert_eq!(
+            (pos, magic),
+            (32 + MAGIC_BITS + 32 + 1 + 24 + 16, Magic::End)
+        );
+
+        let mut data = Vec::new();
+        read::ParBzDecoder::new(&compressed[..], 2)
+            .read_to_end(&mut data)
+            .unwrap();
+        assert!(data == m);
+
+        let mut d = read::ResyncBzDecoder::new(&compressed[..]);
+        let mut data = Vec::new();
+        d.read_to_end(&mut data).unwrap();
+        assert!(data == m);
+        assert!(d.gaps().is_empty());
+
+        let index = BzIndex::build(&compressed[..]).unwrap();
+        assert_eq!(index.block_count(), 3);
+        assert_eq!(index.uncompressed_size(), m.len() as u64);
+        assert_eq!(::verify(&compressed[..]).unwrap().blocks(), 3);
+    }
+}
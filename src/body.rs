@@ -0,0 +1,415 @@
+//! Wrappers compressing or decompressing an `http_body::Body` on the fly.
+//!
+//! These let hyper or axum services send and receive bodies with a
+//! `Content-Encoding` of `bzip2` without collecting them in memory first.
+//! Each wrapper is a `Body` of `Bytes` itself. Data frames of the wrapped
+//! body are coded while trailers are passed on unchanged once the end of
+//! the coded data has been sent.
+//!
+//! Errors of the wrapped body are turned into `io::Error`s, whose
+//! `get_ref` and `into_inner` give access to the original error.
+
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures_core::Stream;
+use http_body::{Body, Frame};
+
+use coder::{Coder, Decoder, Encoder};
+use stream::Chunks;
+use Compression;
+
+/// The data of a body as a stream of chunks, keeping its trailers aside.
+struct Data<B> {
+    body: B,
+    trailers: Option<Frame<Bytes>>,
+}
+
+impl<B> Stream for Data<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        if this.trailers.is_some() {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.body).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                Ok(mut data) => {
+                    let len = data.remaining();
+                    Poll::Ready(Some(Ok(data.copy_to_bytes(len))))
+                }
+                Err(frame) => {
+                    this.trailers = Some(frame.map_data(|_| unreachable!()));
+                    Poll::Ready(None)
+                }
+            },
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::other(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Drives `Chunks` to produce the frames of a body.
+fn poll_frame<B, C>(
+    chunks: &mut Chunks<Data<B>, C>,
+    cx: &mut Context<'_>,
+) -> Poll<Option<io::Result<Frame<Bytes>>>>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+    C: Coder,
+{
+    match chunks.poll_next(cx) {
+        Poll::Ready(Some(Ok(data))) => Poll::Ready(Some(Ok(Frame::data(data)))),
+        Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+        Poll::Ready(None) => Poll::Ready(chunks.inner.trailers.take().map(Ok)),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+/// A body compressing the data of another body.
+pub struct BzEncoder<B> {
+    inner: Chunks<Data<B>, Encoder>,
+}
+
+/// A body decompressing the data of another body.
+///
+/// Only the first bzip2 stream is decoded, anything after it is ignored.
+pub struct BzDecoder<B> {
+    inner: Chunks<Data<B>, Decoder>,
+}
+
+/// A body decompressing all members of a multistream sent as the data of
+/// another body.
+pub struct MultiBzDecoder<B> {
+    inner: Chunks<Data<B>, Decoder>,
+}
+
+impl<B> BzEncoder<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    /// Creates a new encoder compressing the data of `body` at the given
+    /// level.
+    pub fn new(body: B, level: Compression) -> BzEncoder<B> {
+        BzEncoder {
+            inner: Chunks::new(
+                Data {
+                    body,
+                    trailers: None,
+                },
+                Encoder::new(level),
+            ),
+        }
+    }
+}
+
+impl<B> BzEncoder<B> {
+    /// Acquires a reference to the underlying body
+    pub fn get_ref(&self) -> &B {
+        &self.inner.inner.body
+    }
+
+    /// Acquires a mutable reference to the underlying body
+    ///
+    /// Note that polling frames of the body directly results in them being
+    /// missing from the compressed data.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner.inner.body
+    }
+
+    /// Consumes this encoder, returning the underlying body.
+    ///
+    /// Any data which hasn't been compressed yet is lost.
+    pub fn into_inner(self) -> B {
+        self.inner.inner.body
+    }
+
+    /// Returns the number of bytes consumed by the compressor
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes produced by the compressor
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl<B> Body for BzEncoder<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        poll_frame(&mut self.get_mut().inner, cx)
+    }
+}
+
+impl<B> BzDecoder<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    /// Creates a new decoder decompressing the data of `body`.
+    pub fn new(body: B) -> BzDecoder<B> {
+        BzDecoder {
+            inner: Chunks::new(
+                Data {
+                    body,
+                    trailers: None,
+                },
+                Decoder::new(false),
+            ),
+        }
+    }
+}
+
+impl<B> BzDecoder<B> {
+    /// Acquires a reference to the underlying body
+    pub fn get_ref(&self) -> &B {
+        &self.inner.inner.body
+    }
+
+    /// Acquires a mutable reference to the underlying body
+    ///
+    /// Note that polling frames of the body directly results in them being
+    /// missing from the decompressed data.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner.inner.body
+    }
+
+    /// Consumes this decoder, returning the underlying body.
+    ///
+    /// Any data which hasn't been decompressed yet is lost.
+    pub fn into_inner(self) -> B {
+        self.inner.inner.body
+    }
+
+    /// Returns the number of bytes that the decompressor has consumed.
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes that the decompressor has produced.
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl<B> Body for BzDecoder<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        poll_frame(&mut self.get_mut().inner, cx)
+    }
+}
+
+impl<B> MultiBzDecoder<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    /// Creates a new decoder decompressing all members of the multistream
+    /// sent as the data of `body`.
+    pub fn new(body: B) -> MultiBzDecoder<B> {
+        MultiBzDecoder {
+            inner: Chunks::new(
+                Data {
+                    body,
+                    trailers: None,
+                },
+                Decoder::new(true),
+            ),
+        }
+    }
+}
+
+impl<B> MultiBzDecoder<B> {
+    /// Acquires a reference to the underlying body
+    pub fn get_ref(&self) -> &B {
+        &self.inner.inner.body
+    }
+
+    /// Acquires a mutable reference to the underlying body
+    ///
+    /// Note that polling frames of the body directly results in them being
+    /// missing from the decompressed data.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner.inner.body
+    }
+
+    /// Consumes this decoder, returning the underlying body.
+    ///
+    /// Any data which hasn't been decompressed yet is lost.
+    pub fn into_inner(self) -> B {
+        self.inner.inner.body
+    }
+
+    /// Returns the number of bytes that the decompressor has consumed.
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes that the decompressor has produced.
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl<B> Body for MultiBzDecoder<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        poll_frame(&mut self.get_mut().inner, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use bytes::Bytes;
+    use http_body::{Body, Frame};
+    use rand::{thread_rng, Rng};
+
+    use super::{BzDecoder, BzEncoder, MultiBzDecoder};
+    use Compression;
+
+    /// Yields its frames, returning `Pending` before each of them.
+    struct Frames {
+        frames: VecDeque<Result<Frame<Bytes>, String>>,
+        pending: bool,
+    }
+
+    impl Frames {
+        fn new(data: &[u8], size: usize) -> Frames {
+            Frames {
+                frames: data
+                    .chunks(size)
+                    .map(|c| Ok(Frame::data(Bytes::copy_from_slice(c))))
+                    .collect(),
+                pending: false,
+            }
+        }
+    }
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = String;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, String>>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.frames.pop_front())
+        }
+    }
+
+    type Collected = (Vec<u8>, Option<Frame<Bytes>>);
+
+    /// Collects the data of `b` along with its trailers, if any.
+    fn collect<B>(mut b: B) -> Result<Collected, B::Error>
+    where
+        B: Body<Data = Bytes> + Unpin,
+    {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        loop {
+            match Pin::new(&mut b).poll_frame(&mut cx) {
+                Poll::Ready(Some(frame)) => match frame?.into_data() {
+                    Ok(data) => out.extend_from_slice(&data),
+                    Err(trailers) => return Ok((out, Some(trailers))),
+                },
+                Poll::Ready(None) => return Ok((out, None)),
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        let e = BzEncoder::new(Frames::new(&data, 1000), Compression::default());
+        let (compressed, trailers) = collect(e).unwrap();
+        assert!(trailers.is_none());
+        let two = [&compressed[..], &compressed[..]].concat();
+
+        let mut d = BzDecoder::new(Frames::new(&two, 777));
+        assert!(collect(&mut d).unwrap().0 == data);
+        assert_eq!(d.total_in(), compressed.len() as u64);
+
+        let d = MultiBzDecoder::new(Frames::new(&two, 777));
+        assert!(collect(d).unwrap().0 == [&data[..], &data[..]].concat());
+    }
+
+    #[test]
+    fn trailers() {
+        let mut body = Frames::new(b"hello", 2);
+        body.frames
+            .push_back(Ok(Frame::trailers(Default::default())));
+        let (compressed, trailers) = collect(BzEncoder::new(body, Compression::default())).unwrap();
+        assert!(trailers.unwrap().is_trailers());
+
+        let mut body = Frames::new(&compressed, 10);
+        body.frames
+            .push_back(Ok(Frame::trailers(Default::default())));
+        let (data, trailers) = collect(MultiBzDecoder::new(body)).unwrap();
+        assert_eq!(data, b"hello");
+        assert!(trailers.unwrap().is_trailers());
+    }
+
+    #[test]
+    fn errors() {
+        let mut body = Frames::new(b"hello", 2);
+        body.frames[1] = Err("boom".to_string());
+        let err = collect(BzEncoder::new(body, Compression::default())).unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+
+        let d = BzDecoder::new(Frames::new(b"BZh9", 2));
+        let err = collect(d).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
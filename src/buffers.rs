@@ -0,0 +1,319 @@
+//! Crate-wide defaults for the size of internal buffers, and pools to recycle
+//! them from.
+
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// zero is never a valid size, so it stands for the built-in default
+static BUFFER: AtomicUsize = AtomicUsize::new(0);
+static READ_BUFFER: AtomicUsize = AtomicUsize::new(0);
+static CHUNK: AtomicUsize = AtomicUsize::new(0);
+
+const DEFAULT_BUFFER: usize = 32 * 1024;
+const DEFAULT_READ_BUFFER: usize = 8 * 1024;
+const DEFAULT_CHUNK: usize = 128 * 1024;
+
+/// The sizes of the buffers encoders and decoders allocate when they aren't
+/// given one explicitly.
+///
+/// Larger buffers mean fewer calls into libbz2 and the underlying streams at
+/// the cost of memory for every stream, which adds up for programs handling
+/// many streams at once. Installing a configuration with `install` changes
+/// the defaults of every stream created afterwards, while constructors such
+/// as `write::BzEncoder::with_capacity` still take precedence.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct BufferSizes {
+    buffer: usize,
+    read_buffer: usize,
+    chunk: usize,
+}
+
+impl BufferSizes {
+    /// Creates a configuration with the built-in sizes, see the individual
+    /// settings.
+    pub fn new() -> BufferSizes {
+        BufferSizes {
+            buffer: DEFAULT_BUFFER,
+            read_buffer: DEFAULT_READ_BUFFER,
+            chunk: DEFAULT_CHUNK,
+        }
+    }
+
+    /// Returns the configuration currently installed.
+    pub fn current() -> BufferSizes {
+        BufferSizes {
+            buffer: buffer_size(),
+            read_buffer: read_buffer_size(),
+            chunk: chunk_size(),
+        }
+    }
+
+    /// Installs this configuration as the crate-wide default.
+    pub fn install(self) {
+        BUFFER.store(self.buffer, Ordering::Relaxed);
+        READ_BUFFER.store(self.read_buffer, Ordering::Relaxed);
+        CHUNK.store(self.chunk, Ordering::Relaxed);
+    }
+
+    /// Sets the size of the buffers compressed or decompressed data is staged
+    /// in, as by the encoders and decoders of the `write` module and
+    /// `bufread::BzEncoder`. Defaults to 32k.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn buffer(mut self, size: usize) -> BufferSizes {
+        assert!(size > 0, "buffer size must be non-zero");
+        self.buffer = size;
+        self
+    }
+
+    /// Sets the size of the buffer the encoders and decoders of the `read`
+    /// module read from the underlying reader into. Defaults to 8k, like
+    /// `std::io::BufReader`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn read_buffer(mut self, size: usize) -> BufferSizes {
+        assert!(size > 0, "read buffer size must be non-zero");
+        self.read_buffer = size;
+        self
+    }
+
+    /// Sets how much compressed data is read at once by the readers which
+    /// look for block boundaries, such as `read::ParBzDecoder` and
+    /// `read::BzSeekDecoder`. Defaults to 128k.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn chunk(mut self, size: usize) -> BufferSizes {
+        assert!(size > 0, "chunk size must be non-zero");
+        self.chunk = size;
+        self
+    }
+
+    /// Returns the size of staging buffers.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer
+    }
+
+    /// Returns the size of the buffers of readers.
+    pub fn read_buffer_size(&self) -> usize {
+        self.read_buffer
+    }
+
+    /// Returns the size of the chunks read when looking for blocks.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk
+    }
+}
+
+impl Default for BufferSizes {
+    fn default() -> BufferSizes {
+        BufferSizes::new()
+    }
+}
+
+fn get(size: &AtomicUsize, default: usize) -> usize {
+    match size.load(Ordering::Relaxed) {
+        0 => default,
+        n => n,
+    }
+}
+
+pub(crate) fn buffer_size() -> usize {
+    get(&BUFFER, DEFAULT_BUFFER)
+}
+
+pub(crate) fn read_buffer_size() -> usize {
+    get(&READ_BUFFER, DEFAULT_READ_BUFFER)
+}
+
+pub(crate) fn chunk_size() -> usize {
+    get(&CHUNK, DEFAULT_CHUNK)
+}
+
+/// A pool of buffers shared by encoders and decoders, which borrow their
+/// internal buffer from it and give it back when they're dropped.
+///
+/// Every stream allocates a buffer when it's created and frees it when it's
+/// dropped, which becomes noticeable in programs creating many short-lived
+/// streams. Streams created with a pool, such as by
+/// `write::BzEncoder::with_buffer_pool`, reuse buffers returned by earlier
+/// streams instead. Clones of a pool share the same buffers, so one can be
+/// handed to every thread creating streams.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    inner: Arc<Pool>,
+}
+
+#[derive(Debug)]
+struct Pool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    limit: usize,
+}
+
+impl BufferPool {
+    /// Creates an empty pool which keeps up to 64 buffers around.
+    pub fn new() -> BufferPool {
+        BufferPool::with_limit(64)
+    }
+
+    /// Creates an empty pool which keeps up to `limit` buffers around,
+    /// freeing the ones returned to it beyond that.
+    pub fn with_limit(limit: usize) -> BufferPool {
+        BufferPool {
+            inner: Arc::new(Pool {
+                buffers: Mutex::new(Vec::new()),
+                limit,
+            }),
+        }
+    }
+
+    /// Returns the number of buffers waiting in this pool to be reused.
+    pub fn available(&self) -> usize {
+        self.buffers().len()
+    }
+
+    /// Returns an empty buffer from the pool, or a new one if there's none,
+    /// with room for at least `capacity` bytes.
+    fn take(&self, capacity: usize) -> Vec<u8> {
+        let mut buf = self.buffers().pop().unwrap_or_default();
+        buf.reserve_exact(capacity);
+        buf
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.buffers();
+        if buffers.len() < self.inner.limit {
+            buffers.push(buf);
+        }
+    }
+
+    fn buffers(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+        // a panic while holding the lock can't leave the list inconsistent
+        self.inner
+            .buffers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new()
+    }
+}
+
+/// The internal buffer of a stream, which goes back to its pool, if any, on
+/// drop.
+pub(crate) struct Buffer {
+    vec: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl Buffer {
+    pub(crate) fn new(capacity: usize) -> Buffer {
+        Buffer {
+            vec: Vec::with_capacity(capacity),
+            pool: None,
+        }
+    }
+
+    pub(crate) fn pooled(pool: &BufferPool, capacity: usize) -> Buffer {
+        Buffer {
+            vec: pool.take(capacity),
+            pool: Some(pool.clone()),
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.vec
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.vec
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(mem::take(&mut self.vec));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use super::BufferPool;
+    use {bufread, write, Compression};
+
+    #[test]
+    fn pool() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let pool = BufferPool::with_limit(2);
+
+        let mut e = write::BzEncoder::with_buffer_pool(Vec::new(), Compression::fast(), &pool);
+        e.write_all(&data).unwrap();
+        let compressed = e.finish().unwrap();
+        assert_eq!(pool.available(), 1);
+
+        // the encoder's buffer is reused by the decoder, and both by the
+        // next two, which allocate one buffer between them
+        let mut d = write::MultiBzDecoder::with_buffer_pool(Vec::new(), &pool);
+        d.write_all(&compressed).unwrap();
+        assert_eq!(pool.available(), 0);
+        assert!(d.finish().unwrap() == data);
+        let mut r = bufread::BzEncoder::with_buffer_pool(&data[..], Compression::fast(), &pool);
+        let mut d = write::BzDecoder::with_buffer_pool(Vec::new(), &pool);
+        assert_eq!(pool.available(), 0);
+        assert_eq!(copy(&mut r, &mut d), compressed.len());
+        assert!(d.finish().unwrap() == data);
+        drop((r, d));
+        assert_eq!(pool.available(), 2);
+
+        // the limit drops the third one, and a buffer taken out with
+        // `into_parts` isn't returned
+        let a = write::BzDecoder::with_buffer_pool(Vec::new(), &pool);
+        let b = write::BzDecoder::with_buffer_pool(Vec::new(), &pool);
+        let c = write::BzDecoder::with_buffer_pool(Vec::new(), &pool);
+        drop((a, b, c));
+        assert_eq!(pool.available(), 2);
+        let e = write::BzEncoder::with_buffer_pool(Vec::new(), Compression::fast(), &pool);
+        e.into_parts();
+        assert_eq!(pool.available(), 1);
+    }
+
+    fn copy<R: BufRead, W: Write>(r: &mut R, w: &mut W) -> usize {
+        let mut n = 0;
+        loop {
+            let len = {
+                let buf = r.fill_buf().unwrap();
+                w.write_all(buf).unwrap();
+                buf.len()
+            };
+            if len == 0 {
+                return n;
+            }
+            r.consume(len);
+            n += len;
+        }
+    }
+}
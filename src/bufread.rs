@@ -2,22 +2,52 @@
 
 use std::io;
 use std::io::prelude::*;
+use std::mem::{self, MaybeUninit};
 
 #[cfg(feature = "tokio")]
 use futures::Poll;
 #[cfg(feature = "tokio")]
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use {Action, Compress, Compression, Decompress, Status};
+use buffers::{self, Buffer, BufferPool};
+use mem::{as_uninit, limit_input, limit_output};
+use {Action, Compress, Compression, Decompress, Error, Stats, Status, Timer, WorkFactor};
+
+// Same as `BufRead::fill_buf`, but going around the enclosing loop again on
+// `Interrupted` errors the way `read_to_end` does, so that a signal doesn't
+// cut a stream short. A function couldn't hand back the buffer from a retry
+// loop without the borrow checker making it ask for it twice.
+macro_rules! fill_buf {
+    ($obj:expr) => {
+        match $obj.fill_buf() {
+            Ok(input) => input,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    };
+}
 
 /// A bz2 encoder, or compressor.
 ///
 /// This structure implements a `BufRead` interface and will read uncompressed
 /// data from an underlying stream and emit a stream of compressed data.
+///
+/// It also implements `BufRead` itself, compressing into a buffer of its own
+/// which `fill_buf` hands out, so that `io::copy` and the like can write out
+/// the compressed data without copying it first. Plain reads compress
+/// straight into the caller's buffer once that buffer is drained.
 pub struct BzEncoder<R> {
     obj: R,
     data: Compress,
+    level: Compression,
+    work_factor: WorkFactor,
+    // the value of the work factor `data` was created with
+    factor: u32,
     done: bool,
+    timer: Timer,
+    buf: Buffer,
+    pos: usize,
+    end: usize,
 }
 
 /// A bz2 decoder, or decompressor.
@@ -29,16 +59,90 @@ pub struct BzDecoder<R> {
     data: Decompress,
     done: bool,
     multi: bool,
+    allow_empty: bool,
+    // input gathered from `obj` until there's at least `min_fill` bytes of
+    // it, of which the first `staged` have been decompressed
+    min_fill: usize,
+    stage: Vec<u8>,
+    staged: usize,
 }
 
 impl<R: BufRead> BzEncoder<R> {
     /// Creates a new encoder which will read uncompressed data from the given
     /// stream and emit the compressed stream.
     pub fn new(r: R, level: Compression) -> BzEncoder<R> {
+        BzEncoder::with_buffer(r, level, Buffer::new(0))
+    }
+
+    /// Same as `new`, but the buffer `fill_buf` hands out is borrowed from
+    /// `pool`, which it's returned to once this encoder is dropped.
+    pub fn with_buffer_pool(r: R, level: Compression, pool: &BufferPool) -> BzEncoder<R> {
+        BzEncoder::with_buffer(r, level, Buffer::pooled(pool, 0))
+    }
+
+    fn with_buffer(r: R, level: Compression, buf: Buffer) -> BzEncoder<R> {
         BzEncoder {
             obj: r,
             data: Compress::new(level, 30),
+            level,
+            work_factor: WorkFactor::default(),
+            factor: WorkFactor::default().factor(),
             done: false,
+            timer: Timer::default(),
+            buf,
+            pos: 0,
+            end: 0,
+        }
+    }
+
+    /// Sets the work factor of the compressor, see `WorkFactor`.
+    ///
+    /// It has no effect once data has been read from this encoder, and is the
+    /// default of libbz2 unless set.
+    pub fn set_work_factor(&mut self, work_factor: WorkFactor) {
+        self.work_factor = work_factor;
+    }
+
+    fn compress(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        self.timer.start();
+        if self.done {
+            return Ok(0);
+        }
+        loop {
+            let (read, consumed, eof, ret);
+            {
+                let input = fill_buf!(self.obj);
+                eof = input.is_empty();
+                // the work factor is settled by the first input
+                if self.data.total_in() == 0 && self.data.total_out() == 0 && !eof {
+                    let factor = self.work_factor.for_input(input);
+                    if factor != self.factor {
+                        self.data = Compress::new(self.level, factor);
+                        self.factor = factor;
+                    }
+                }
+                let before_out = self.data.total_out();
+                let before_in = self.data.total_in();
+                let action = if eof { Action::Finish } else { Action::Run };
+                let (input, action) = limit_input(input, action);
+                ret = self.data.compress_uninit(input, limit_output(buf), action);
+                read = (self.data.total_out() - before_out) as usize;
+                consumed = (self.data.total_in() - before_in) as usize;
+            }
+            self.obj.consume(consumed);
+
+            let ret = ret.map_err(io::Error::from)?;
+
+            // If we haven't ready any data and we haven't hit EOF yet, then we
+            // need to keep asking for more data because if we return that 0
+            // bytes of data have been read then it will be interpreted as EOF.
+            if read == 0 && !eof && !buf.is_empty() {
+                continue;
+            }
+            if ret == Status::StreamEnd {
+                self.done = true;
+            }
+            return Ok(read);
         }
     }
 }
@@ -80,42 +184,53 @@ impl<R> BzEncoder<R> {
     pub fn total_in(&self) -> u64 {
         self.data.total_in()
     }
+
+    /// Returns a summary of the compression stream.
+    ///
+    /// Once this encoder has been read to the end, the summary covers the
+    /// whole stream.
+    pub fn stats(&self) -> Stats {
+        let members = if self.done { 1 } else { 0 };
+        Stats::new(
+            self.total_in(),
+            self.total_out(),
+            members,
+            self.timer.elapsed(),
+        )
+    }
 }
 
 impl<R: BufRead> Read for BzEncoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.done {
-            return Ok(0);
+        if self.pos == self.end {
+            return self.compress(unsafe { as_uninit(buf) });
         }
-        loop {
-            let (read, consumed, eof, ret);
-            {
-                let input = self.obj.fill_buf()?;
-                eof = input.is_empty();
-                let before_out = self.data.total_out();
-                let before_in = self.data.total_in();
-                let action = if eof { Action::Finish } else { Action::Run };
-                ret = self.data.compress(input, buf, action);
-                read = (self.data.total_out() - before_out) as usize;
-                consumed = (self.data.total_in() - before_in) as usize;
-            }
-            self.obj.consume(consumed);
-
-            // we should never get the sequence error that's possible to be
-            // returned from compression
-            let ret = ret.unwrap();
+        let n = buf.len().min(self.end - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
 
-            // If we haven't ready any data and we haven't hit EOF yet, then we
-            // need to keep asking for more data because if we return that 0
-            // bytes of data have been read then it will be interpreted as EOF.
-            if read == 0 && !eof && buf.len() > 0 {
-                continue;
-            }
-            if ret == Status::StreamEnd {
-                self.done = true;
-            }
-            return Ok(read);
+impl<R: BufRead> BufRead for BzEncoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.end {
+            self.pos = 0;
+            self.end = 0;
+            // libbz2 only ever writes to the buffer, so it isn't zeroed
+            let mut buf = mem::take(&mut *self.buf);
+            buf.clear();
+            buf.reserve_exact(buffers::buffer_size());
+            let res = self.compress(buf.spare_capacity_mut());
+            unsafe { buf.set_len(*res.as_ref().unwrap_or(&0)) };
+            *self.buf = buf;
+            self.end = res?;
         }
+        Ok(&self.buf[self.pos..self.end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.end);
     }
 }
 
@@ -148,6 +263,10 @@ impl<R: BufRead> BzDecoder<R> {
             data: Decompress::new(false),
             done: false,
             multi: false,
+            allow_empty: false,
+            min_fill: 0,
+            stage: Vec::new(),
+            staged: 0,
         }
     }
 
@@ -158,6 +277,39 @@ impl<R: BufRead> BzDecoder<R> {
 }
 
 impl<R> BzDecoder<R> {
+    /// Configures whether input without any data at all decompresses to
+    /// nothing rather than failing with an `UnexpectedEof` error.
+    ///
+    /// Empty input isn't a valid bzip2 stream, but some tools create empty
+    /// files in place of compressing empty ones, so this can be enabled to
+    /// accept them. It's disabled by default.
+    pub fn set_allow_empty(&mut self, allow: bool) {
+        self.allow_empty = allow;
+    }
+
+    /// Configures whether streams switch to the small mode of libbz2 when
+    /// there isn't enough memory otherwise, see
+    /// `Decompress::set_small_fallback`. It's disabled by default.
+    pub fn set_small_fallback(&mut self, fallback: bool) {
+        self.data.set_small_fallback(fallback);
+    }
+
+    /// Sets the minimum amount of input handed to libbz2 at once.
+    ///
+    /// Each time the decoder needs input it takes whatever the underlying
+    /// reader has buffered, which from slow sources may be only a few bytes,
+    /// going back and forth between the reader and libbz2 for each of them.
+    /// With a minimum set, input is gathered into a buffer of this decoder
+    /// until at least `min` bytes are there or the input ends. Readers which
+    /// already have that much buffered are still decompressed directly.
+    ///
+    /// Note that input gathered past the end of the stream stays in this
+    /// decoder rather than the underlying reader. It's 0, for no minimum, by
+    /// default.
+    pub fn set_min_fill(&mut self, min: usize) {
+        self.min_fill = min;
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         &self.obj
@@ -190,15 +342,33 @@ impl<R> BzDecoder<R> {
     }
 }
 
-impl<R: BufRead> Read for BzDecoder<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+impl<R: BufRead> BzDecoder<R> {
+    /// Runs `decompress` on the buffered input until it produces something,
+    /// the stream ends or the input does, returning how much it produced.
+    ///
+    /// `empty` is whether there's no room for any output, in which case a
+    /// single call is made.
+    fn decode<F>(&mut self, empty: bool, mut decompress: F) -> io::Result<usize>
+    where
+        F: FnMut(&mut Decompress, &[u8]) -> Result<Status, Error>,
+    {
         loop {
             if self.done && !self.multi {
                 return Ok(0);
             }
             let (read, consumed, remaining, ret);
+            let staged = fill_stage(
+                &mut self.obj,
+                &mut self.stage,
+                &mut self.staged,
+                self.min_fill,
+            )?;
             {
-                let input = self.obj.fill_buf()?;
+                let input = if staged {
+                    &self.stage[self.staged..]
+                } else {
+                    fill_buf!(self.obj)
+                };
                 if self.done {
                     assert!(self.multi);
                     if input.is_empty() {
@@ -206,36 +376,113 @@ impl<R: BufRead> Read for BzDecoder<R> {
                         return Ok(0);
                     } else {
                         // previous stream ended, more data follows => create new decompressor
-                        self.data = Decompress::new(false);
+                        self.data = self.data.restart();
                         self.done = false;
                     }
                 }
                 let before_out = self.data.total_out();
                 let before_in = self.data.total_in();
-                ret = self.data.decompress(input, buf);
+                let input = limit_input(input, Action::Run).0;
+                ret = decompress(&mut self.data, input);
                 read = (self.data.total_out() - before_out) as usize;
                 consumed = (self.data.total_in() - before_in) as usize;
                 remaining = input.len() - consumed;
             }
-            self.obj.consume(consumed);
+            if staged {
+                self.staged += consumed;
+            } else {
+                self.obj.consume(consumed);
+            }
 
-            let ret = ret.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let ret = ret.map_err(io::Error::from)?;
             if ret == Status::StreamEnd {
                 self.done = true;
             } else if consumed == 0 && remaining == 0 && read == 0 {
+                // a fresh stream is only started for more input, so nothing
+                // consumed here means there was no input at all
+                if self.allow_empty && self.data.total_in() == 0 {
+                    self.done = true;
+                    return Ok(0);
+                }
                 return Err(io::Error::new(
                     io::ErrorKind::UnexpectedEof,
                     "decompression not finished but EOF reached",
                 ));
             }
 
-            if read > 0 || buf.len() == 0 {
+            if read > 0 || empty {
                 return Ok(read);
             }
         }
     }
 }
 
+impl<R: BufRead> Read for BzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let empty = buf.is_empty();
+        self.decode(empty, |data, input| {
+            data.decompress(input, limit_output(buf))
+        })
+    }
+
+    /// Decompresses straight into the spare capacity of `buf`, growing it by
+    /// as much as the input buffered so far is expected to decompress to,
+    /// going by the compression ratio of the stream up to here.
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        loop {
+            let n = self.decode(false, |data, input| {
+                if buf.len() == buf.capacity() {
+                    buf.reserve_exact(reserve_hint(data, input.len(), buf.len() - start));
+                }
+                data.decompress_vec(input, buf)
+            })?;
+            if n == 0 {
+                return Ok(buf.len() - start);
+            }
+        }
+    }
+}
+
+/// How many bytes `read_to_end` reserves for the output of `buffered` bytes
+/// of input, after `read` bytes of output so far.
+fn reserve_hint(data: &Decompress, buffered: usize, read: usize) -> usize {
+    let guess = match data.total_in() {
+        0 => 0,
+        total_in => (buffered as u64).saturating_mul(data.total_out()) / total_in,
+    };
+    // Never reserve too far beyond what was actually produced, so that a
+    // small input can't make this claim memory on the grounds of a ratio it
+    // only kept up for a while.
+    let max = read.max(1 << 20).saturating_mul(16) as u64;
+    // and grow by a quarter at least, as libbz2 reads ahead of its output,
+    // to keep the number of reallocations down when the guess falls short
+    (guess.min(max) as usize).max(read / 4).max(32 * 1024)
+}
+
+/// Tops up `stage`, of which the first `staged` bytes are used up, from
+/// `obj` until it holds at least `min` bytes or `obj` ends, returning whether
+/// to take input from `stage` rather than straight from `obj`.
+fn fill_stage<R: BufRead>(
+    obj: &mut R,
+    stage: &mut Vec<u8>,
+    staged: &mut usize,
+    min: usize,
+) -> io::Result<bool> {
+    stage.drain(..*staged);
+    *staged = 0;
+    while stage.len() < min {
+        let input = fill_buf!(obj);
+        if input.is_empty() || (stage.is_empty() && input.len() >= min) {
+            break;
+        }
+        stage.extend_from_slice(input);
+        let len = input.len();
+        obj.consume(len);
+    }
+    Ok(!stage.is_empty())
+}
+
 #[cfg(feature = "tokio")]
 impl<R: AsyncRead + BufRead> AsyncRead for BzDecoder<R> {}
 
@@ -271,6 +518,24 @@ impl<R: BufRead> MultiBzDecoder<R> {
 }
 
 impl<R> MultiBzDecoder<R> {
+    /// Configures whether input without any data at all decompresses to
+    /// nothing, see `BzDecoder::set_allow_empty`.
+    pub fn set_allow_empty(&mut self, allow: bool) {
+        self.0.set_allow_empty(allow);
+    }
+
+    /// Configures whether streams switch to small mode when there isn't
+    /// enough memory otherwise, see `BzDecoder::set_small_fallback`.
+    pub fn set_small_fallback(&mut self, fallback: bool) {
+        self.0.set_small_fallback(fallback);
+    }
+
+    /// Sets the minimum amount of input handed to libbz2 at once, see
+    /// `BzDecoder::set_min_fill`.
+    pub fn set_min_fill(&mut self, min: usize) {
+        self.0.set_min_fill(min);
+    }
+
     /// Acquires a reference to the underlying reader.
     pub fn get_ref(&self) -> &R {
         self.0.get_ref()
@@ -294,6 +559,10 @@ impl<R: BufRead> Read for MultiBzDecoder<R> {
     fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
         self.0.read(into)
     }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.0.read_to_end(buf)
+    }
 }
 
 #[cfg(feature = "tokio")]
@@ -316,10 +585,80 @@ impl<R: AsyncWrite + BufRead> AsyncWrite for MultiBzDecoder<R> {
     }
 }
 
+/// An iterator over the data decompressed from a `BufRead`, in chunks of a
+/// fixed size.
+///
+/// Every stream of a multistream is decoded, as by `MultiBzDecoder`. All
+/// chunks but the last one hold exactly the requested number of bytes, and
+/// iteration ends after the first error.
+pub struct BzChunks<R> {
+    inner: MultiBzDecoder<R>,
+    size: usize,
+    done: bool,
+}
+
+impl<R: BufRead> BzChunks<R> {
+    /// Creates an iterator decompressing the data read from `r` into chunks
+    /// of `size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new(r: R, size: usize) -> BzChunks<R> {
+        assert!(size > 0, "chunks must not be empty");
+        BzChunks {
+            inner: MultiBzDecoder::new(r),
+            size,
+            done: false,
+        }
+    }
+}
+
+impl<R> BzChunks<R> {
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    /// Consumes this iterator, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: BufRead> Iterator for BzChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = Vec::with_capacity(self.size);
+        match (&mut self.inner)
+            .take(self.size as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(_) if chunk.len() == self.size => Some(Ok(chunk)),
+            Ok(_) => {
+                self.done = true;
+                if chunk.is_empty() {
+                    None
+                } else {
+                    Some(Ok(chunk))
+                }
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::MultiBzDecoder;
-    use std::io::{BufReader, Read};
+    use super::{BzChunks, BzDecoder, BzEncoder, MultiBzDecoder};
+    use std::io::{self, BufRead, BufReader, Read};
 
     #[test]
     fn bug_61() {
@@ -333,4 +672,230 @@ mod tests {
         assert_eq!(d.read_to_end(&mut data).unwrap(), uncompressed_bytes.len());
         assert_eq!(data, uncompressed_bytes);
     }
+
+    #[test]
+    fn read_to_end() {
+        let data = (0..3_000_000u32)
+            .map(|i| (i % 7 * (i / 1000) % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut compressed = ::compress(&data, ::Compression::fast());
+
+        let mut out = b"prefix".to_vec();
+        let n = BzDecoder::new(&compressed[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(n, data.len());
+        assert!(out[..6] == b"prefix"[..] && out[6..] == data[..]);
+        // one guess at the size of the rest from the start of the stream
+        // rather than doubling up to it
+        assert!(out.capacity() < data.len() / 2 * 3, "{}", out.capacity());
+
+        compressed.extend(::compress(b"more", ::Compression::fast()));
+        let mut out = Vec::new();
+        MultiBzDecoder::new(BufReader::new(&compressed[..]))
+            .read_to_end(&mut out)
+            .unwrap();
+        assert!(out[..data.len()] == data[..] && out.ends_with(b"more"));
+
+        let mut out = Vec::new();
+        let truncated = &compressed[..compressed.len() / 2];
+        let err = BzDecoder::new(truncated).read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!out.is_empty() && data.starts_with(&out));
+    }
+
+    #[test]
+    fn chunks() {
+        let data = (0..10_000u32).map(|i| (i % 101) as u8).collect::<Vec<_>>();
+        let mut compressed = ::compress(&data[..6_000], ::Compression::fast());
+        compressed.extend(::compress(&data[6_000..], ::Compression::fast()));
+
+        let chunks = BzChunks::new(&compressed[..], 4096)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let lens = chunks.iter().map(|c| c.len()).collect::<Vec<_>>();
+        assert_eq!(lens, [4096, 4096, 1808]);
+        assert!(chunks.concat() == data);
+
+        let mut chunks = BzChunks::new(&compressed[..compressed.len() - 1], 4096);
+        assert_eq!(chunks.next().unwrap().unwrap().len(), 4096);
+        assert_eq!(chunks.next().unwrap().unwrap().len(), 4096);
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+
+        assert!(BzChunks::new(&[][..], 1).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn allow_empty() {
+        let mut d = BzDecoder::new(&[][..]);
+        assert_eq!(
+            d.read(&mut [0; 10]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut d = BzDecoder::new(&[][..]);
+        d.set_allow_empty(true);
+        assert_eq!(d.read(&mut [0; 10]).unwrap(), 0);
+        let mut d = MultiBzDecoder::new(&[][..]);
+        d.set_allow_empty(true);
+        assert_eq!(d.read(&mut [0; 10]).unwrap(), 0);
+
+        // a truncated stream is still an error
+        let compressed = ::compress(b"hello", ::Compression::fast());
+        let mut d = MultiBzDecoder::new(&compressed[..10]);
+        d.set_allow_empty(true);
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn min_fill() {
+        // hands out a few bytes at a time, and fails every now and then
+        struct Trickle<'a>(&'a [u8], usize);
+
+        impl<'a> Read for Trickle<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.fill_buf()?.read(buf)?;
+                self.consume(n);
+                Ok(n)
+            }
+        }
+
+        impl<'a> BufRead for Trickle<'a> {
+            fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                self.1 += 1;
+                if self.1.is_multiple_of(5) {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok(&self.0[..self.0.len().min(7)])
+            }
+
+            fn consume(&mut self, amt: usize) {
+                self.0 = &self.0[amt..];
+            }
+        }
+
+        let data = (0..50_000u32).map(|i| (i % 61) as u8).collect::<Vec<_>>();
+        let mut compressed = ::compress(&data[..20_000], ::Compression::fast());
+        let first = compressed.len();
+        compressed.extend(::compress(&data[20_000..], ::Compression::fast()));
+        for &min in &[0, 1, 100, 1 << 20] {
+            let mut d = MultiBzDecoder::new(Trickle(&compressed, 0));
+            d.set_min_fill(min);
+            let mut out = Vec::new();
+            let mut buf = [0; 1000];
+            loop {
+                match d.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => out.extend_from_slice(&buf[..n]),
+                    Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+                }
+            }
+            assert!(out == data, "min fill {}", min);
+        }
+
+        // readers buffering enough are read from directly
+        let mut d = BzDecoder::new(&compressed[..]);
+        d.set_min_fill(100);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out[..] == data[..20_000]);
+        assert!(d.stage.is_empty());
+        assert!(d.into_inner() == &compressed[first..]);
+    }
+
+    #[test]
+    fn interrupted() {
+        // interrupts every other call, and counts those that found the end
+        struct Interrupting<'a> {
+            data: &'a [u8],
+            calls: usize,
+            eof: usize,
+        }
+
+        impl<'a> Read for Interrupting<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.fill_buf()?.read(buf)?;
+                self.consume(n);
+                Ok(n)
+            }
+        }
+
+        impl<'a> BufRead for Interrupting<'a> {
+            fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                self.calls += 1;
+                if self.calls % 2 == 1 {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                if self.data.is_empty() {
+                    self.eof += 1;
+                }
+                Ok(&self.data[..self.data.len().min(100)])
+            }
+
+            fn consume(&mut self, amt: usize) {
+                self.data = &self.data[amt..];
+            }
+        }
+
+        fn read_all<R: Read>(mut r: R) -> Vec<u8> {
+            let mut out = Vec::new();
+            let mut buf = [0; 1 << 16];
+            loop {
+                match r.read(&mut buf).unwrap() {
+                    0 => return out,
+                    n => out.extend_from_slice(&buf[..n]),
+                }
+            }
+        }
+
+        let data = (0..10_000u32).map(|i| (i % 61) as u8).collect::<Vec<_>>();
+        let mut e = BzEncoder::new(
+            Interrupting {
+                data: &data,
+                calls: 0,
+                eof: 0,
+            },
+            ::Compression::fast(),
+        );
+        let compressed = read_all(&mut e);
+        assert_eq!(e.get_ref().eof, 1);
+        assert!(::decompress(&compressed).unwrap() == data);
+
+        let mut d = BzDecoder::new(Interrupting {
+            data: &compressed,
+            calls: 0,
+            eof: 0,
+        });
+        assert!(read_all(&mut d) == data);
+        assert_eq!(d.get_ref().eof, 0);
+    }
+
+    #[test]
+    fn encoder_buf_read() {
+        let data = (0..200_000u32).map(|i| (i % 41) as u8).collect::<Vec<_>>();
+        let expected = ::compress(&data, ::Compression::fast());
+
+        let mut e = BzEncoder::new(&data[..], ::Compression::fast());
+        let mut out = Vec::new();
+        io::copy(&mut e, &mut out).unwrap();
+        assert!(out == expected);
+
+        // mixing reads with fill_buf and consume
+        let mut e = BzEncoder::new(&data[..], ::Compression::fast());
+        let mut out = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            let n = e.read(&mut buf).unwrap();
+            out.extend_from_slice(&buf[..n]);
+            let chunk = e.fill_buf().unwrap();
+            if n == 0 && chunk.is_empty() {
+                break;
+            }
+            let half = chunk.len().div_ceil(2);
+            out.extend_from_slice(&chunk[..half]);
+            e.consume(half);
+        }
+        assert!(out == expected);
+    }
 }
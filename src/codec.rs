@@ -0,0 +1,175 @@
+//! A `tokio_util::codec` for framed transports.
+//!
+//! Every frame is sent as a bzip2 stream of its own, so the receiving end
+//! can decode a frame as soon as it has arrived in full and the frame
+//! boundaries survive the trip. The bytes on the wire are a regular bzip2
+//! multistream and can be decoded with a `MultiBzDecoder` as well.
+
+use std::io::{self, Write};
+use std::mem;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use write::BzEncoder;
+use {Compression, Decompress, Status};
+
+/// Compresses and decompresses frames of bytes, one bzip2 stream per frame.
+///
+/// Use it with `Framed`, `FramedRead` or `FramedWrite` of `tokio_util`.
+pub struct BzCodec {
+    level: Compression,
+    data: Option<Decompress>,
+    frame: Vec<u8>,
+    max_frame_length: usize,
+}
+
+impl BzCodec {
+    /// Creates a new codec compressing frames at the given level.
+    pub fn new(level: Compression) -> BzCodec {
+        BzCodec {
+            level,
+            data: None,
+            frame: Vec::new(),
+            max_frame_length: usize::MAX,
+        }
+    }
+
+    /// Sets the length in bytes of the longest frame decoded, there's no
+    /// limit by default.
+    ///
+    /// Decoding a longer frame fails with `InvalidData` once that many bytes
+    /// have been decompressed, rather than buffering all of it.
+    pub fn max_frame_length(mut self, len: usize) -> BzCodec {
+        self.max_frame_length = len;
+        self
+    }
+}
+
+impl Default for BzCodec {
+    fn default() -> BzCodec {
+        BzCodec::new(Compression::default())
+    }
+}
+
+impl Encoder<Bytes> for BzCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let mut w = BzEncoder::new(dst.writer(), self.level);
+        w.write_all(&item)?;
+        w.finish()?;
+        Ok(())
+    }
+}
+
+impl Decoder for BzCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        while !src.is_empty() {
+            let data = self.data.get_or_insert_with(|| Decompress::new(false));
+            // no more than one byte past the limit, to notice it's exceeded
+            let room = self.max_frame_length - self.frame.len();
+            self.frame.reserve(room.saturating_add(1).min(32 * 1024));
+            let before = data.total_in();
+            let status = data
+                .decompress_vec(src, &mut self.frame)
+                .map_err(io::Error::from)?;
+            src.advance((data.total_in() - before) as usize);
+            if self.frame.len() > self.max_frame_length {
+                self.data = None;
+                self.frame = Vec::new();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bzip2 frame longer than the maximum frame length",
+                ));
+            }
+            if status == Status::StreamEnd {
+                self.data = None;
+                return Ok(Some(Bytes::from(mem::take(&mut self.frame))));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if self.data.is_some() => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "bzip2 frame cut short by the end of the stream",
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::BzCodec;
+    use read::MultiBzDecoder;
+
+    #[test]
+    fn frames() {
+        let frames = [&b"hello"[..], b"", &[7; 100_000][..], b"world"];
+        let mut codec = BzCodec::default();
+        let mut wire = BytesMut::new();
+        for frame in frames.iter() {
+            codec
+                .encode(Bytes::copy_from_slice(frame), &mut wire)
+                .unwrap();
+        }
+
+        // whatever is on the wire is a plain multistream
+        let mut all = Vec::new();
+        MultiBzDecoder::new(&wire[..])
+            .read_to_end(&mut all)
+            .unwrap();
+        assert_eq!(all, frames.concat());
+
+        // frames come out one at a time, however the bytes are split up
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in wire.chunks(1000) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut codec = BzCodec::default();
+        let mut wire = BytesMut::new();
+        codec.encode(Bytes::from("hello"), &mut wire).unwrap();
+        let mut src = BytesMut::from(&wire[..wire.len() - 4]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        let err = codec.decode_eof(&mut src).unwrap_err();
+        assert_eq!(err.kind(), ::std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn max_frame_length() {
+        let mut codec = BzCodec::default().max_frame_length(1000);
+        let mut wire = BytesMut::new();
+        let frames = [&[1; 1000][..], &[2; 100_000][..]];
+        for frame in frames.iter() {
+            codec
+                .encode(Bytes::copy_from_slice(frame), &mut wire)
+                .unwrap();
+        }
+        assert_eq!(codec.decode(&mut wire).unwrap().unwrap(), frames[0]);
+        let err = codec.decode(&mut wire).unwrap_err();
+        assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,215 @@
+//! Compression and decompression state machines working on caller-provided
+//! buffers, shared by the async adapters and the `poll` module.
+
+use std::io;
+use std::mem::MaybeUninit;
+
+use mem::{limit_input, limit_output};
+use {Action, Compress, Compression, Decompress, Error, Status};
+
+/// One direction of a bzip2 stream, fed and drained through plain slices.
+///
+/// Output is only ever written to, so it doesn't need to be initialized.
+pub trait Coder {
+    /// Codes as much of `input` into `output` as possible, returning the
+    /// number of bytes consumed and produced.
+    fn run(&mut self, input: &[u8], output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, usize)>;
+
+    /// Writes out everything which has been fed in so far, returning the
+    /// number of bytes produced and whether the flush is complete. Must be
+    /// called until it is before `run` makes progress again.
+    fn flush(&mut self, output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)>;
+
+    /// Ends the stream, returning the number of bytes produced and whether
+    /// the stream is complete.
+    fn finish(&mut self, output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)>;
+
+    /// Whether the end of the stream has been reached and no further input
+    /// will be accepted.
+    fn done(&self) -> bool;
+
+    /// Number of bytes consumed so far.
+    fn total_in(&self) -> u64;
+
+    /// Number of bytes produced so far.
+    fn total_out(&self) -> u64;
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum State {
+    Running,
+    Flushing,
+    Finishing,
+    Done,
+}
+
+/// Compresses data into a single stream.
+pub struct Encoder {
+    data: Compress,
+    state: State,
+}
+
+impl Encoder {
+    pub fn new(level: Compression) -> Encoder {
+        Encoder {
+            data: Compress::new(level, 30),
+            state: State::Running,
+        }
+    }
+
+    fn compress(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+        action: Action,
+    ) -> io::Result<(usize, usize, Status)> {
+        let (before_in, before_out) = (self.data.total_in(), self.data.total_out());
+        let (input, action) = limit_input(input, action);
+        let status = self
+            .data
+            .compress_uninit(input, limit_output(output), action)?;
+        let consumed = (self.data.total_in() - before_in) as usize;
+        let produced = (self.data.total_out() - before_out) as usize;
+        Ok((consumed, produced, status))
+    }
+}
+
+impl Coder for Encoder {
+    fn run(&mut self, input: &[u8], output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, usize)> {
+        match self.state {
+            State::Running => {
+                let (consumed, produced, _) = self.compress(input, output, Action::Run)?;
+                Ok((consumed, produced))
+            }
+            // an interrupted flush has to complete first
+            State::Flushing => Ok((0, self.flush(output)?.0)),
+            State::Finishing | State::Done => Err(Error::Sequence.into()),
+        }
+    }
+
+    fn flush(&mut self, output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)> {
+        match self.state {
+            State::Running => self.state = State::Flushing,
+            State::Flushing => {}
+            State::Finishing | State::Done => return Ok((0, true)),
+        }
+        let (_, produced, status) = self.compress(&[], output, Action::Flush)?;
+        if status == Status::RunOk {
+            self.state = State::Running;
+        }
+        Ok((produced, self.state == State::Running))
+    }
+
+    fn finish(&mut self, output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)> {
+        match self.state {
+            State::Running => self.state = State::Finishing,
+            // libbz2 doesn't allow switching actions in the middle of a flush
+            State::Flushing => return Ok((self.flush(output)?.0, false)),
+            State::Finishing => {}
+            State::Done => return Ok((0, true)),
+        }
+        let (_, produced, status) = self.compress(&[], output, Action::Finish)?;
+        if status == Status::StreamEnd {
+            self.state = State::Done;
+        }
+        Ok((produced, self.state == State::Done))
+    }
+
+    fn done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn total_in(&self) -> u64 {
+        self.data.total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        self.data.total_out()
+    }
+}
+
+/// Decompresses a single stream, or all streams of a multistream.
+pub struct Decoder {
+    data: Decompress,
+    multi: bool,
+    done: bool,
+    // totals of the streams decoded before the current one
+    total_in: u64,
+    total_out: u64,
+}
+
+impl Decoder {
+    pub fn new(multi: bool) -> Decoder {
+        Decoder {
+            data: Decompress::new(false),
+            multi,
+            done: false,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+    ) -> io::Result<(usize, usize)> {
+        let (before_in, before_out) = (self.data.total_in(), self.data.total_out());
+        let status = self
+            .data
+            .decompress_uninit(limit_input(input, Action::Run).0, limit_output(output))
+            .map_err(io::Error::from)?;
+        if status == Status::StreamEnd {
+            self.done = true;
+        }
+        let consumed = (self.data.total_in() - before_in) as usize;
+        let produced = (self.data.total_out() - before_out) as usize;
+        Ok((consumed, produced))
+    }
+}
+
+impl Coder for Decoder {
+    fn run(&mut self, input: &[u8], output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, usize)> {
+        if self.done {
+            if !self.multi || input.is_empty() {
+                return Ok((0, 0));
+            }
+            // the previous stream ended and more data follows
+            self.total_in += self.data.total_in();
+            self.total_out += self.data.total_out();
+            self.data = Decompress::new(false);
+            self.done = false;
+        }
+        self.decompress(input, output)
+    }
+
+    fn flush(&mut self, _output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)> {
+        Ok((0, true))
+    }
+
+    fn finish(&mut self, output: &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)> {
+        if self.done {
+            return Ok((0, true));
+        }
+        match self.decompress(&[], output)? {
+            (_, 0) if !self.done => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "decompression not finished but EOF reached",
+            )),
+            (_, produced) => Ok((produced, self.done)),
+        }
+    }
+
+    fn done(&self) -> bool {
+        // a multistream can always be continued by another stream
+        self.done && !self.multi
+    }
+
+    fn total_in(&self) -> u64 {
+        self.total_in + self.data.total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        self.total_out + self.data.total_out()
+    }
+}
@@ -0,0 +1,270 @@
+//! Compressing and decompressing whole files by path.
+//!
+//! These take care of what most command line tools need around the encoders
+//! and decoders: buffering, and writing the output to a temporary file next
+//! to it that is synced to disk and renamed into place only once it's
+//! complete, so that neither a truncated file is left behind if anything
+//! fails along the way nor a file that was already there lost.
+//!
+//! With the `mmap` feature, compressed files can also be memory mapped to be
+//! decompressed or indexed in one go, without any reads through a buffer.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+use bufread::MultiBzDecoder;
+use write::BzEncoder;
+#[cfg(feature = "mmap")]
+use BzIndex;
+use Compression;
+
+/// Compresses the file at `src` into a new file at `dst`, replacing it if
+/// it exists, returning the number of bytes read from `src`.
+///
+/// Fails with `InvalidInput` if `src` and `dst` are the same file.
+pub fn compress_file<P, Q>(src: P, dst: Q, level: Compression) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut input = File::open(src.as_ref())?;
+    create(src.as_ref(), dst.as_ref(), |output| {
+        let mut encoder = BzEncoder::new(io::BufWriter::new(output), level);
+        let n = io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        Ok(n)
+    })
+}
+
+/// Decompresses the file at `src`, which may hold several bzip2 streams back
+/// to back, into a new file at `dst`, replacing it if it exists, returning
+/// the number of bytes written to `dst`.
+///
+/// Fails with `InvalidInput` if `src` and `dst` are the same file.
+pub fn decompress_file<P, Q>(src: P, dst: Q) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let input = io::BufReader::new(File::open(src.as_ref())?);
+    create(src.as_ref(), dst.as_ref(), |output| {
+        let mut decoder = MultiBzDecoder::new(input);
+        let mut output = io::BufWriter::new(output);
+        let n = io::copy(&mut decoder, &mut output)?;
+        output.flush()?;
+        Ok(n)
+    })
+}
+
+/// Decompresses the file at `path`, which may hold several bzip2 streams back
+/// to back, by memory mapping it and passing it to `decompress` as a whole.
+///
+/// The file must not be modified while this runs, which is undefined
+/// behavior as far as Rust is concerned.
+#[cfg(feature = "mmap")]
+pub fn decompress_mapped<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let map = map(path.as_ref())?;
+    Ok(::decompress(&map)?)
+}
+
+/// Builds the `BzIndex` of the file at `path` by memory mapping it, which
+/// spares copying all of it through a buffer to find its blocks.
+///
+/// The file must not be modified while this runs, as for
+/// `decompress_mapped`.
+#[cfg(feature = "mmap")]
+pub fn index_mapped<P: AsRef<Path>>(path: P) -> io::Result<BzIndex> {
+    let map = map(path.as_ref())?;
+    BzIndex::build(&map[..])
+}
+
+#[cfg(feature = "mmap")]
+fn map(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // the file being changed underneath us is documented to be the caller's
+    // responsibility
+    unsafe { Mmap::map(&file) }
+}
+
+/// Has `write` fill in a temporary file next to `path`, then syncs it to
+/// disk and renames it over `path`, removing only the temporary file if any
+/// of that fails.
+fn create<F>(src: &Path, path: &Path, write: F) -> io::Result<u64>
+where
+    F: FnOnce(&File) -> io::Result<u64>,
+{
+    if same_file(src, path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "source and destination are the same file",
+        ));
+    }
+    let (file, tmp) = temp_file(path)?;
+    let res = write(&file).and_then(|n| file.sync_all().map(|()| n));
+    drop(file);
+    match res.and_then(|n| fs::rename(&tmp, path).map(|()| n)) {
+        Ok(n) => Ok(n),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Whether `dst` exists and is `src` under another name, through a symlink
+/// or a hard link.
+fn same_file(src: &Path, dst: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(src), fs::metadata(dst)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    // without file ids to compare hard links can't be told apart, but the
+    // same path or symlinks to it can
+    #[cfg(not(unix))]
+    {
+        match (fs::canonicalize(src), fs::canonicalize(dst)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Creates a new file in the directory of `path` to be renamed over it.
+fn temp_file(path: &Path) -> io::Result<(File, PathBuf)> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "destination is not a file"))?;
+    // `process::id` panics on WASI, the time keeps concurrent runs apart
+    // just as well
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    for i in 0u32.. {
+        let mut tmp = OsString::from(".");
+        tmp.push(name);
+        tmp.push(format!(".{:x}.tmp", now.wrapping_add(i)));
+        let tmp = path.with_file_name(tmp);
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => return Ok((file, tmp)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{compress_file, decompress_file};
+    use Compression;
+
+    fn path(name: &str) -> PathBuf {
+        // `temp_dir` and `process::id` panic on WASI, where the runtime has
+        // to pass in a directory
+        let dir = env::var_os("TMPDIR").map_or_else(env::temp_dir, PathBuf::from);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        dir.join(format!("bzip2-fs-{}-{}", now.as_nanos(), name))
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (plain, compressed, out) = (path("plain"), path("bz2"), path("out"));
+        fs::write(&plain, &data).unwrap();
+
+        let n = compress_file(&plain, &compressed, Compression::fast()).unwrap();
+        assert_eq!(n, data.len() as u64);
+        let n = decompress_file(&compressed, &out).unwrap();
+        assert_eq!(n, data.len() as u64);
+        assert!(fs::read(&out).unwrap() == data);
+
+        for path in [plain, compressed, out] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn errors_remove_output() {
+        let (corrupt, out) = (path("corrupt"), path("corrupt-out"));
+        let mut compressed = ::compress(&[1; 10_000], Compression::default());
+        compressed.truncate(compressed.len() / 2);
+        fs::write(&corrupt, &compressed).unwrap();
+
+        assert!(decompress_file(&corrupt, &out).is_err());
+        assert!(!out.exists());
+
+        let missing = path("missing");
+        let err = compress_file(&missing, &out, Compression::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!out.exists());
+
+        // a file that was already there is left alone, and so is its
+        // directory
+        fs::write(&out, b"keep").unwrap();
+        assert!(decompress_file(&corrupt, &out).is_err());
+        assert_eq!(fs::read(&out).unwrap(), b"keep");
+        let prefix = format!(".{}", out.file_name().unwrap().to_str().unwrap());
+        for entry in fs::read_dir(out.parent().unwrap()).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert!(!name.to_str().unwrap_or("").starts_with(&prefix));
+        }
+        fs::remove_file(out).unwrap();
+        fs::remove_file(corrupt).unwrap();
+    }
+
+    // hard links are only told apart where files have ids to compare
+    #[cfg(unix)]
+    #[test]
+    fn same_file() {
+        let (plain, link) = (path("same"), path("same-link"));
+        fs::write(&plain, b"data").unwrap();
+        fs::hard_link(&plain, &link).unwrap();
+
+        for dst in [&plain, &link] {
+            let err = compress_file(&plain, dst, Compression::default()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let err = decompress_file(&plain, dst).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(fs::read(&plain).unwrap(), b"data");
+
+        for path in [plain, link] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped() {
+        let data = (0..300_000u32).map(|i| (i % 97) as u8).collect::<Vec<_>>();
+        let path = path("mapped");
+        fs::write(&path, ::compress(&data, Compression::fast())).unwrap();
+
+        assert!(super::decompress_mapped(&path).unwrap() == data);
+        let index = super::index_mapped(&path).unwrap();
+        assert_eq!(index.uncompressed_size(), data.len() as u64);
+        assert_eq!(index.block_count(), 4);
+
+        fs::write(&path, b"").unwrap();
+        assert!(super::decompress_mapped(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1251 @@
+//! Encoders and decoders for the `AsyncRead`, `AsyncBufRead` and
+//! `AsyncWrite` traits of the `futures-io` crate.
+//!
+//! The types in here mirror the blocking ones of the `bufread`, `read` and
+//! `write` modules of this crate, only that reading and writing returns
+//! `Poll::Pending` instead of blocking whenever the underlying stream isn't
+//! ready. They work with any executor.
+//!
+//! Unlike the blocking writers, the writers in here are not finished when
+//! they're dropped; `poll_close` has to be called (e.g. through
+//! `AsyncWriteExt::close`) to write out the end of the stream.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io_crate::{AsyncBufRead, AsyncRead, AsyncSeek, AsyncWrite};
+
+use aio;
+
+/// Adapts the `futures-io` traits to the ones the `aio` module works with.
+struct Compat<T>(T);
+
+impl<T: AsyncBufRead + Unpin> aio::BufSource for Compat<T> {
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.0).poll_fill_buf(cx)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        Pin::new(&mut self.0).consume(amt)
+    }
+}
+
+impl<T: AsyncRead + Unpin> aio::Source for Compat<T> {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+/// Like `Compat`, also remembering where a seek goes since `poll_seek` is
+/// repeated with the same position until it completes.
+struct SeekCompat<T>(T, u64);
+
+impl<T: AsyncRead + Unpin> aio::Source for SeekCompat<T> {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncSeek + Unpin> aio::Seeker for SeekCompat<T> {
+    fn start_seek(&mut self, pos: u64) -> io::Result<()> {
+        self.1 = pos;
+        Ok(())
+    }
+
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.0).poll_seek(cx, io::SeekFrom::Start(self.1))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> aio::Sink for Compat<T> {
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+pub mod bufread {
+    //! Async encoders and decoders reading from an `AsyncBufRead`.
+
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_io_crate::{AsyncBufRead, AsyncRead};
+
+    use super::Compat;
+    use aio::Reader;
+    use coder::{Coder, Decoder, Encoder};
+    use Compression;
+
+    /// A bz2 encoder, or compressor.
+    ///
+    /// This structure implements `AsyncRead` and will read uncompressed data
+    /// from an underlying stream and emit a stream of compressed data.
+    pub struct BzEncoder<R> {
+        inner: Reader<Compat<R>, Encoder>,
+    }
+
+    /// A bz2 decoder, or decompressor.
+    ///
+    /// This structure implements `AsyncRead` and takes a stream of
+    /// compressed data as input, providing the decompressed data when read
+    /// from. Only the first bzip2 stream is decoded.
+    pub struct BzDecoder<R> {
+        inner: Reader<Compat<R>, Decoder>,
+    }
+
+    /// A bzip2 streaming decoder that decodes all members of a multistream.
+    pub struct MultiBzDecoder<R> {
+        inner: Reader<Compat<R>, Decoder>,
+    }
+
+    impl<R: AsyncBufRead + Unpin> BzEncoder<R> {
+        /// Creates a new encoder which will read uncompressed data from the
+        /// given stream and emit the compressed stream.
+        pub fn new(r: R, level: Compression) -> BzEncoder<R> {
+            BzEncoder {
+                inner: Reader::new(Compat(r), Encoder::new(level)),
+            }
+        }
+    }
+
+    impl<R> BzEncoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this encoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this encoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes consumed by the compressor
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes produced by the compressor
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncRead for BzEncoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> BzDecoder<R> {
+        /// Creates a new decoder which will decompress data read from the
+        /// given stream.
+        pub fn new(r: R) -> BzDecoder<R> {
+            BzDecoder {
+                inner: Reader::new(Compat(r), Decoder::new(false)),
+            }
+        }
+    }
+
+    impl<R> BzDecoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this decoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncRead for BzDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> MultiBzDecoder<R> {
+        /// Creates a new decoder from the given reader. If the bzip2 stream
+        /// contains multiple members all will be decoded.
+        pub fn new(r: R) -> MultiBzDecoder<R> {
+            MultiBzDecoder {
+                inner: Reader::new(Compat(r), Decoder::new(true)),
+            }
+        }
+    }
+
+    impl<R> MultiBzDecoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this decoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncRead for MultiBzDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+}
+
+pub mod read {
+    //! Async encoders and decoders reading from an `AsyncRead`.
+
+    use std::io::{self, SeekFrom};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_io_crate::{AsyncRead, AsyncSeek};
+
+    use super::{Compat, SeekCompat};
+    use aio::{BufReader, Reader, SeekReader};
+    use coder::{Coder, Decoder, Encoder};
+    use {BzIndex, Compression};
+
+    /// A bz2 encoder, or compressor.
+    ///
+    /// This structure implements `AsyncRead` and will read uncompressed data
+    /// from an underlying stream and emit a stream of compressed data.
+    pub struct BzEncoder<R> {
+        inner: Reader<BufReader<Compat<R>>, Encoder>,
+    }
+
+    /// A bz2 decoder, or decompressor.
+    ///
+    /// This structure implements `AsyncRead` and takes a stream of
+    /// compressed data as input, providing the decompressed data when read
+    /// from. Only the first bzip2 stream is decoded.
+    pub struct BzDecoder<R> {
+        inner: Reader<BufReader<Compat<R>>, Decoder>,
+    }
+
+    /// A bzip2 streaming decoder that decodes all members of a multistream.
+    pub struct MultiBzDecoder<R> {
+        inner: Reader<BufReader<Compat<R>>, Decoder>,
+    }
+
+    impl<R: AsyncRead + Unpin> BzEncoder<R> {
+        /// Creates a new encoder which will read uncompressed data from the
+        /// given stream and emit the compressed stream.
+        pub fn new(r: R, level: Compression) -> BzEncoder<R> {
+            BzEncoder {
+                inner: Reader::new(BufReader::new(Compat(r)), Encoder::new(level)),
+            }
+        }
+    }
+
+    impl<R> BzEncoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.get_ref().0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this encoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.get_mut().0
+        }
+
+        /// Consumes this encoder, returning the underlying reader.
+        ///
+        /// Any data read from the reader but not yet compressed is lost.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.into_inner().0
+        }
+
+        /// Returns the number of bytes consumed by the compressor
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes produced by the compressor
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for BzEncoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> BzDecoder<R> {
+        /// Creates a new decoder which will decompress data read from the
+        /// given stream.
+        pub fn new(r: R) -> BzDecoder<R> {
+            BzDecoder {
+                inner: Reader::new(BufReader::new(Compat(r)), Decoder::new(false)),
+            }
+        }
+    }
+
+    impl<R> BzDecoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.get_ref().0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this decoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.get_mut().0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        ///
+        /// Any data read from the reader but not yet decompressed is lost.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.into_inner().0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for BzDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> MultiBzDecoder<R> {
+        /// Creates a new decoder from the given reader. If the bzip2 stream
+        /// contains multiple members all will be decoded.
+        pub fn new(r: R) -> MultiBzDecoder<R> {
+            MultiBzDecoder {
+                inner: Reader::new(BufReader::new(Compat(r)), Decoder::new(true)),
+            }
+        }
+    }
+
+    impl<R> MultiBzDecoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.get_ref().0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this decoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.get_mut().0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        ///
+        /// Any data read from the reader but not yet decompressed is lost.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.into_inner().0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for MultiBzDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+
+    /// A decompression stream which can seek to any offset of the
+    /// decompressed data, like `bzip2::read::BzSeekDecoder`.
+    ///
+    /// This implements `AsyncRead` and `AsyncSeek`, and only decompresses the
+    /// block holding the data asked for. There's no async scan of the input,
+    /// the index of its blocks has to be built beforehand, e.g. with
+    /// `BzIndex::build` on a blocking reader, or loaded with
+    /// `BzIndex::read_from`.
+    ///
+    /// Blocks are decompressed on the task reading from the decoder, which
+    /// takes a while for the larger block sizes.
+    pub struct BzSeekDecoder<R> {
+        inner: SeekReader<SeekCompat<R>>,
+    }
+
+    impl<R: AsyncRead + AsyncSeek + Unpin> BzSeekDecoder<R> {
+        /// Creates a new decoder for the compressed data in `r` starting at
+        /// offset `start`, using an index built earlier for the same data.
+        ///
+        /// No data is read until the first read from the decoder. If `index`
+        /// doesn't match the data reads return errors.
+        pub fn with_index(r: R, start: u64, index: BzIndex) -> BzSeekDecoder<R> {
+            BzSeekDecoder {
+                inner: SeekReader::new(SeekCompat(r, 0), start, index),
+            }
+        }
+    }
+
+    impl<R> BzSeekDecoder<R> {
+        /// Returns the index of the blocks of the underlying data.
+        pub fn index(&self) -> &BzIndex {
+            &self.inner.index
+        }
+
+        /// Acquires a reference to the underlying reader.
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream.
+        ///
+        /// Note that the position of the stream is changed by reads from
+        /// this decoder, and that changing its data results in errors or
+        /// garbage.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+    }
+
+    impl<R: AsyncRead + AsyncSeek + Unpin> AsyncRead for BzSeekDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_read(cx, buf)
+        }
+    }
+
+    impl<R: AsyncRead + AsyncSeek + Unpin> AsyncSeek for BzSeekDecoder<R> {
+        fn poll_seek(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            pos: SeekFrom,
+        ) -> Poll<io::Result<u64>> {
+            Poll::Ready(self.get_mut().inner.seek(pos))
+        }
+    }
+}
+
+pub mod write {
+    //! Async encoders and decoders writing into an `AsyncWrite`.
+    //!
+    //! These are cancellation safe in the same way as the ones of
+    //! `bzip2::tokio1::write`: coded output the underlying writer didn't
+    //! accept yet stays buffered until the next call, so dropping a future
+    //! half way through a write or close loses nothing, and an interrupted
+    //! `poll_close` is resumed by calling it again.
+
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_io_crate::AsyncWrite;
+
+    use super::Compat;
+    use aio::{ParWriter, Writer};
+    use coder::{Coder, Decoder, Encoder};
+    use {Compression, ParConfig, ThreadPool};
+
+    /// A bz2 encoder, or compressor.
+    ///
+    /// This structure implements `AsyncWrite` and will compress all data
+    /// written to it into the underlying stream. The stream is only complete
+    /// once `poll_close` has returned `Ready`.
+    pub struct BzEncoder<W> {
+        inner: Writer<Compat<W>, Encoder>,
+    }
+
+    /// A bz2 decoder, or decompressor.
+    ///
+    /// This structure implements `AsyncWrite` and will decompress all data
+    /// written to it into the underlying stream. Only the first bzip2 stream
+    /// is decoded, writes after its end return `Ok(0)`.
+    pub struct BzDecoder<W> {
+        inner: Writer<Compat<W>, Decoder>,
+    }
+
+    /// A bzip2 streaming decoder that decodes all members of a multistream
+    /// written to it.
+    pub struct MultiBzDecoder<W> {
+        inner: Writer<Compat<W>, Decoder>,
+    }
+
+    impl<W: AsyncWrite + Unpin> BzEncoder<W> {
+        /// Create a new compression stream which will compress at the given
+        /// level to write compress output to the give output stream.
+        pub fn new(w: W, level: Compression) -> BzEncoder<W> {
+            BzEncoder {
+                inner: Writer::new(Compat(w), Encoder::new(level)),
+            }
+        }
+    }
+
+    impl<W> BzEncoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this encoder, returning the underlying writer.
+        ///
+        /// The stream is not finished, call `poll_close` first for that.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes consumed by the compressor
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes produced by the compressor
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for BzEncoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> BzDecoder<W> {
+        /// Create a new decoding stream which will decompress all data
+        /// written to it into `w`.
+        pub fn new(w: W) -> BzDecoder<W> {
+            BzDecoder {
+                inner: Writer::new(Compat(w), Decoder::new(false)),
+            }
+        }
+    }
+
+    impl<W> BzDecoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying writer.
+        ///
+        /// Decompressed data which hasn't been written out yet is lost.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for BzDecoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> MultiBzDecoder<W> {
+        /// Create a new decoding stream which will decompress all members of
+        /// the multistream written to it into `w`.
+        pub fn new(w: W) -> MultiBzDecoder<W> {
+            MultiBzDecoder {
+                inner: Writer::new(Compat(w), Decoder::new(true)),
+            }
+        }
+    }
+
+    impl<W> MultiBzDecoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying writer.
+        ///
+        /// Decompressed data which hasn't been written out yet is lost.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for MultiBzDecoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+
+    /// A compression stream which compresses data on several threads at once.
+    ///
+    /// This is the async counterpart of `bzip2::write::ParBzEncoder`: data is
+    /// split into chunks which are compressed as independent members on the
+    /// threads of a `ThreadPool`, so the task writing to it only does the I/O
+    /// and never blocks its executor on compression. The output is a
+    /// multistream.
+    ///
+    /// Once as many chunks as allowed by the `ParConfig` are in flight,
+    /// writes return `Poll::Pending` until the oldest one has been written
+    /// out; the backpressure mode of the config is ignored. The stream is
+    /// only complete once `poll_close` has returned `Ready`.
+    pub struct ParBzEncoder<W> {
+        inner: ParWriter<Compat<W>>,
+    }
+
+    impl<W: AsyncWrite + Unpin> ParBzEncoder<W> {
+        /// Create a new compression stream which will compress at the given
+        /// level using `threads` worker threads, writing compressed output
+        /// to `w`.
+        ///
+        /// Passing 0 for `threads` uses as many as `available_threads` returns.
+        pub fn new(w: W, level: Compression, threads: usize) -> ParBzEncoder<W> {
+            ParBzEncoder::with_config(w, level, threads, ParConfig::default())
+        }
+
+        /// Same as `new`, but with the limits of `config` on the chunks being
+        /// compressed at once.
+        ///
+        /// Passing 0 for `threads` uses as many as `available_threads` returns.
+        pub fn with_config(
+            w: W,
+            level: Compression,
+            threads: usize,
+            config: ParConfig,
+        ) -> ParBzEncoder<W> {
+            ParBzEncoder::with_thread_pool(w, level, &ThreadPool::new(threads), config)
+        }
+
+        /// Same as `new`, but compresses on the global rayon thread pool
+        /// instead of spawning dedicated threads.
+        #[cfg(feature = "rayon")]
+        pub fn with_rayon(w: W, level: Compression) -> ParBzEncoder<W> {
+            ParBzEncoder::with_thread_pool(w, level, &ThreadPool::rayon(), ParConfig::default())
+        }
+
+        /// Same as `with_config`, but compresses on the threads of `pool`,
+        /// which may be shared with other encoders and decoders.
+        pub fn with_thread_pool(
+            w: W,
+            level: Compression,
+            pool: &ThreadPool,
+            config: ParConfig,
+        ) -> ParBzEncoder<W> {
+            ParBzEncoder {
+                inner: ParWriter::new(Compat(w), level, pool, config),
+            }
+        }
+    }
+
+    impl<W> ParBzEncoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this encoder, returning the underlying writer.
+        ///
+        /// The stream is not finished, call `poll_close` first for that.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes written to this stream.
+        pub fn total_in(&self) -> u64 {
+            self.inner.total_in
+        }
+
+        /// Returns the number of compressed bytes written to the underlying
+        /// writer.
+        pub fn total_out(&self) -> u64 {
+            self.inner.total_out
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for ParBzEncoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use futures_io_crate::{AsyncBufRead, AsyncRead, AsyncSeek, AsyncWrite};
+    use rand::{thread_rng, Rng};
+
+    use super::{bufread, read, write};
+    use {BzIndex, Compression};
+
+    /// Hands out a few bytes at a time, returning `Pending` in between.
+    struct Trickle<T> {
+        inner: T,
+        pending: bool,
+    }
+
+    impl<T> Trickle<T> {
+        fn new(inner: T) -> Trickle<T> {
+            Trickle {
+                inner,
+                pending: true,
+            }
+        }
+
+        fn poll_turn(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    impl AsyncRead for Trickle<&[u8]> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.poll_turn(cx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+            let n = buf.len().min(7);
+            Pin::new(&mut self.inner).poll_read(cx, &mut buf[..n])
+        }
+    }
+
+    impl AsyncRead for Trickle<io::Cursor<&[u8]>> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.poll_turn(cx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+            let n = buf.len().min(7);
+            Poll::Ready(io::Read::read(&mut self.inner, &mut buf[..n]))
+        }
+    }
+
+    impl AsyncSeek for Trickle<io::Cursor<&[u8]>> {
+        fn poll_seek(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            pos: io::SeekFrom,
+        ) -> Poll<io::Result<u64>> {
+            match self.poll_turn(cx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+            Poll::Ready(io::Seek::seek(&mut self.inner, pos))
+        }
+    }
+
+    impl AsyncBufRead for Trickle<&[u8]> {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            let this = self.get_mut();
+            match this.poll_turn(cx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+            let n = this.inner.len().min(7);
+            Poll::Ready(Ok(&this.inner[..n]))
+        }
+
+        fn consume(mut self: Pin<&mut Self>, amt: usize) {
+            let inner = &mut self.inner;
+            *inner = &inner[amt..];
+        }
+    }
+
+    impl AsyncWrite for Trickle<Vec<u8>> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.poll_turn(cx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+            let n = buf.len().min(7);
+            self.inner.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Polls `f` until it's ready, the tests' sources wake immediately.
+    fn poll<T>(mut f: impl FnMut(&mut Context<'_>) -> Poll<T>) -> T {
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(t) = f(&mut cx) {
+                return t;
+            }
+        }
+    }
+
+    fn read_to_end<R: AsyncRead + Unpin>(mut r: R) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = poll(|cx| Pin::new(&mut r).poll_read(cx, &mut buf))?;
+            if n == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    fn write_all<W: AsyncWrite + Unpin>(w: &mut W, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let n = poll(|cx| Pin::new(&mut *w).poll_write(cx, data))?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            data = &data[n..];
+        }
+        poll(|cx| Pin::new(&mut *w).poll_flush(cx))?;
+        poll(|cx| Pin::new(&mut *w).poll_close(cx))
+    }
+
+    fn data() -> Vec<u8> {
+        let mut v = vec![0; 100_000];
+        thread_rng().fill(&mut v[..50_000]);
+        v
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut w = ::write::BzEncoder::new(Vec::new(), Compression::default());
+        ::std::io::Write::write_all(&mut w, data).unwrap();
+        w.finish().unwrap()
+    }
+
+    #[test]
+    fn bufread() {
+        let data = data();
+        let r = bufread::BzEncoder::new(Trickle::new(&data[..]), Compression::default());
+        let compressed = read_to_end(r).unwrap();
+
+        let two = [&compressed[..], &compressed[..]].concat();
+        let r = bufread::BzDecoder::new(Trickle::new(&two[..]));
+        assert!(read_to_end(r).unwrap() == data);
+        let r = bufread::MultiBzDecoder::new(Trickle::new(&two[..]));
+        assert!(read_to_end(r).unwrap() == [&data[..], &data[..]].concat());
+
+        let r = bufread::BzDecoder::new(Trickle::new(&compressed[..100]));
+        let err = read_to_end(r).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn budget() {
+        let data = vec![0; 1_000_000];
+        let mut r = bufread::BzEncoder::new(&data[..], Compression::default());
+        r.set_budget(Some(10_000));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut buf = [0; 1024];
+        let mut pending = 0;
+        let mut compressed = Vec::new();
+        loop {
+            match Pin::new(&mut r).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(n)) => compressed.extend_from_slice(&buf[..n]),
+                Poll::Ready(Err(e)) => panic!("{}", e),
+                Poll::Pending => pending += 1,
+            }
+        }
+        // the source never is, but the encoder yielded every 10_000 bytes
+        assert!(pending >= 90);
+        assert!(compressed == compress(&data));
+
+        let mut r = bufread::BzDecoder::new(&compressed[..]);
+        r.set_budget(Some(100));
+        let n = poll(|cx| Pin::new(&mut r).poll_read(cx, &mut buf)).unwrap();
+        assert_eq!(n, 100);
+        assert!(read_to_end(r).unwrap().len() == data.len() - 100);
+    }
+
+    #[test]
+    fn read() {
+        let data = data();
+        let r = read::BzEncoder::new(Trickle::new(&data[..]), Compression::default());
+        let compressed = read_to_end(r).unwrap();
+        assert!(compressed == compress(&data));
+
+        let two = [&compressed[..], &compressed[..]].concat();
+        let mut r = read::MultiBzDecoder::new(Trickle::new(&two[..]));
+        assert!(read_to_end(&mut r).unwrap() == [&data[..], &data[..]].concat());
+        assert_eq!(r.total_in(), two.len() as u64);
+        assert_eq!(r.total_out(), 2 * data.len() as u64);
+    }
+
+    #[test]
+    fn seek() {
+        let mut data = vec![0; 250_000];
+        thread_rng().fill(&mut data[..]);
+        let compressed = {
+            let mut w = ::write::BzEncoder::new(Vec::new(), Compression::fast());
+            ::std::io::Write::write_all(&mut w, &data).unwrap();
+            w.finish().unwrap()
+        };
+        let index = BzIndex::build(&compressed[..]).unwrap();
+        assert!(index.blocks().len() > 1);
+
+        let src = Trickle::new(io::Cursor::new(&compressed[..]));
+        let mut r = read::BzSeekDecoder::with_index(src, 0, index);
+        let mut buf = [0; 1000];
+        for &pos in &[200_000, 10, 249_500, 99_500] {
+            let n = poll(|cx| Pin::new(&mut r).poll_seek(cx, io::SeekFrom::Start(pos))).unwrap();
+            assert_eq!(n, pos);
+            let n = poll(|cx| Pin::new(&mut r).poll_read(cx, &mut buf)).unwrap();
+            let pos = pos as usize;
+            assert!(n > 0);
+            assert_eq!(&buf[..n], &data[pos..pos + n]);
+        }
+        let end = poll(|cx| Pin::new(&mut r).poll_seek(cx, io::SeekFrom::End(0))).unwrap();
+        assert_eq!(end, data.len() as u64);
+        assert_eq!(
+            poll(|cx| Pin::new(&mut r).poll_read(cx, &mut buf)).unwrap(),
+            0
+        );
+        let err = poll(|cx| Pin::new(&mut r).poll_seek(cx, io::SeekFrom::Current(-300_000)));
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let src = Trickle::new(io::Cursor::new(&compressed[..]));
+        let r = read::BzSeekDecoder::with_index(src, 0, BzIndex::build(&compressed[..]).unwrap());
+        assert!(read_to_end(r).unwrap() == data);
+    }
+
+    #[test]
+    fn write() {
+        let data = data();
+        let mut w = write::BzEncoder::new(Trickle::new(Vec::new()), Compression::default());
+        write_all(&mut w, &data).unwrap();
+        let compressed = w.into_inner().inner;
+        assert!(compressed == compress(&data));
+
+        let mut w = write::BzDecoder::new(Trickle::new(Vec::new()));
+        write_all(&mut w, &compressed).unwrap();
+        assert!(w.into_inner().inner == data);
+
+        let two = [&compressed[..], &compressed[..]].concat();
+        let mut w = write::MultiBzDecoder::new(Trickle::new(Vec::new()));
+        write_all(&mut w, &two).unwrap();
+        assert!(w.into_inner().inner == [&data[..], &data[..]].concat());
+    }
+
+    #[test]
+    fn parallel() {
+        let data = data();
+        let config = ::ParConfig::new().chunk_size(7_000).max_in_flight(2);
+        let mut w = write::ParBzEncoder::with_config(
+            Trickle::new(Vec::new()),
+            Compression::fast(),
+            2,
+            config,
+        );
+        write_all(&mut w, &data).unwrap();
+        assert_eq!(w.total_in(), data.len() as u64);
+        let compressed = w.into_inner().inner;
+
+        let mut out = Vec::new();
+        ::std::io::Read::read_to_end(&mut ::read::MultiBzDecoder::new(&compressed[..]), &mut out)
+            .unwrap();
+        assert!(out == data);
+
+        // an empty stream is still a valid bzip2 file
+        let mut w = write::ParBzEncoder::new(Vec::new(), Compression::fast(), 1);
+        write_all(&mut w, b"").unwrap();
+        assert!(::is_bzip2(w.get_ref()));
+    }
+
+    #[test]
+    fn flush() {
+        let mut w = write::BzEncoder::new(Trickle::new(Vec::new()), Compression::default());
+        let n = poll(|cx| Pin::new(&mut w).poll_write(cx, b"hello")).unwrap();
+        assert_eq!(n, 5);
+        poll(|cx| Pin::new(&mut w).poll_flush(cx)).unwrap();
+
+        // the block was written out without closing the stream
+        let flushed = w.get_ref().inner.len();
+        assert!(flushed > 4);
+        poll(|cx| Pin::new(&mut w).poll_close(cx)).unwrap();
+        let compressed = w.into_inner().inner;
+        assert!(compressed.len() > flushed);
+
+        let mut d = ::read::BzDecoder::new(&compressed[..]);
+        let mut out = Vec::new();
+        ::std::io::Read::read_to_end(&mut d, &mut out).unwrap();
+        assert_eq!(out, b"hello");
+    }
+}
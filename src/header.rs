@@ -0,0 +1,159 @@
+//! Parsing of the header at the start of every bzip2 stream.
+//!
+//! A stream starts with the magic `BZh` followed by a digit from `1` to `9`
+//! giving the block size used, in units of 100k. Reading the header doesn't
+//! require any decompression, which makes it cheap to check what kind of
+//! data a file holds.
+
+use std::io;
+use std::io::prelude::*;
+
+use {Compression, Error};
+
+/// The magic number at the start of every stream.
+pub const MAGIC: &[u8; 3] = b"BZh";
+
+/// The header of a bzip2 stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    block_size: u8,
+}
+
+impl Header {
+    /// Length of an encoded header in bytes.
+    pub const LEN: usize = 4;
+
+    /// Parses the header at the start of `data`.
+    ///
+    /// Returns an error of kind `UnexpectedEof` if `data` is too short to
+    /// hold a header, or `InvalidData` if it doesn't start with one.
+    pub fn parse(data: &[u8]) -> io::Result<Header> {
+        if data.len() < Header::LEN {
+            if !MAGIC.starts_with(&data[..data.len().min(MAGIC.len())]) {
+                return Err(missing());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "bzip2: stream too short for a header",
+            ));
+        }
+        let digit = data[3];
+        if &data[..3] != MAGIC || !(b'1'..=b'9').contains(&digit) {
+            return Err(missing());
+        }
+        Ok(Header {
+            block_size: digit - b'0',
+        })
+    }
+
+    /// Reads and parses a header from `r`, consuming exactly `Header::LEN`
+    /// bytes if there are that many.
+    pub fn read<R: Read>(mut r: R) -> io::Result<Header> {
+        let mut buf = [0; Header::LEN];
+        let mut n = 0;
+        while n < buf.len() {
+            match r.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(m) => n += m,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Header::parse(&buf[..n])
+    }
+
+    /// Returns the block size (1-9, in units of 100k) used by the stream.
+    pub fn block_size(&self) -> u32 {
+        u32::from(self.block_size)
+    }
+
+    /// Returns the block size used by the stream in bytes.
+    pub fn max_block_len(&self) -> usize {
+        self.block_size as usize * 100_000
+    }
+
+    /// Returns the compression level which produces streams with this
+    /// header.
+    pub fn compression(&self) -> Compression {
+        Compression::new(self.block_size())
+    }
+
+    /// Returns this header encoded as it appears in a stream.
+    pub fn to_bytes(&self) -> [u8; Header::LEN] {
+        [MAGIC[0], MAGIC[1], MAGIC[2], b'0' + self.block_size]
+    }
+}
+
+/// Returns whether `data` starts with a bzip2 stream header.
+///
+/// This only checks the first `Header::LEN` bytes, so data which passes may
+/// still fail to decompress.
+pub fn is_bzip2(data: &[u8]) -> bool {
+    Header::parse(data).is_ok()
+}
+
+/// Returns whether the data buffered by `r` starts with a bzip2 stream
+/// header, without consuming anything.
+///
+/// Only what `r.fill_buf()` returns is checked, so `false` is also returned
+/// if `r` buffers fewer than `Header::LEN` bytes at once.
+pub fn is_bzip2_buf<R: BufRead>(r: &mut R) -> io::Result<bool> {
+    Ok(is_bzip2(r.fill_buf()?))
+}
+
+fn missing() -> io::Error {
+    Error::DataMagic.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_bzip2, is_bzip2_buf, Header};
+    use std::io;
+    use std::io::prelude::*;
+    use {bufread, read, Compression};
+
+    #[test]
+    fn parse() {
+        for level in 1..10 {
+            let mut data = Vec::new();
+            read::BzEncoder::new(&b"hello"[..], Compression::new(level))
+                .read_to_end(&mut data)
+                .unwrap();
+            let header = Header::parse(&data).unwrap();
+            assert_eq!(header.block_size(), level);
+            assert_eq!(header.max_block_len(), level as usize * 100_000);
+            assert_eq!(header.compression().level(), level);
+            assert_eq!(header.to_bytes()[..], data[..4]);
+
+            let mut r = &data[..];
+            assert_eq!(Header::read(&mut r).unwrap(), header);
+            assert_eq!(r.len(), data.len() - Header::LEN);
+        }
+
+        let kind = |data: &[u8]| Header::parse(data).unwrap_err().kind();
+        assert_eq!(kind(b"BZ"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(kind(b""), io::ErrorKind::UnexpectedEof);
+        assert_eq!(kind(b"BZh0"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"BZx"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"PK\x03\x04"), io::ErrorKind::InvalidData);
+        assert!(Header::read(&b"BZh"[..]).is_err());
+    }
+
+    #[test]
+    fn sniff() {
+        let mut data = Vec::new();
+        read::BzEncoder::new(&b"hello"[..], Compression::default())
+            .read_to_end(&mut data)
+            .unwrap();
+        assert!(is_bzip2(&data));
+        assert!(!is_bzip2(b"BZh"));
+        assert!(!is_bzip2(b"\x1f\x8b\x08\x00"));
+
+        let mut r = io::BufReader::new(&data[..]);
+        assert!(is_bzip2_buf(&mut r).unwrap());
+        let mut out = Vec::new();
+        bufread::BzDecoder::new(r).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+        assert!(!is_bzip2_buf(&mut &b"hello"[..]).unwrap());
+    }
+}
@@ -0,0 +1,657 @@
+//! Information about the blocks in bzip2 compressed data.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+
+use block::{self, Entry, Item, Magic, Scanner, Segment};
+
+/// Magic number at the start of a serialized index, followed by the format
+/// version.
+const MAGIC: &[u8; 4] = b"BZIX";
+const VERSION: u8 = 1;
+
+/// Where each block of some compressed data starts, and which part of the
+/// decompressed data it holds.
+///
+/// An index is built by scanning the compressed data once. It can then be
+/// passed to `read::BzSeekDecoder::with_index` to seek within the same data
+/// without scanning it again, and can be saved next to the compressed data
+/// with `write_to` and loaded back with `read_from`.
+pub struct BzIndex {
+    blocks: Vec<Entry>,
+}
+
+impl BzIndex {
+    /// Builds an index of all streams read from `r`.
+    ///
+    /// This decompresses all of the data once, returning an error if it is
+    /// corrupt or truncated. Offsets in the index are relative to the position
+    /// `r` starts reading from.
+    pub fn build<R: Read>(r: R) -> io::Result<BzIndex> {
+        let blocks = Blocks::new(r)
+            .map(|b| {
+                b.map(|b| Entry {
+                    bit_offset: b.bit_offset,
+                    bits: b.bits,
+                    digit: b.digit,
+                    offset: b.offset,
+                    len: b.len,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(BzIndex { blocks })
+    }
+
+    /// Returns the number of blocks in the indexed data.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the size of the indexed data once decompressed.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.blocks.last().map(|b| b.offset + b.len).unwrap_or(0)
+    }
+
+    pub(crate) fn blocks(&self) -> &[Entry] {
+        &self.blocks
+    }
+
+    /// Builds an index from the block offsets used by the `indexed_bzip2` and
+    /// `ratarmount` tools.
+    ///
+    /// Each pair maps the bit offset of a block within the compressed data to
+    /// the offset of its data in the decompressed output. Like those tools
+    /// produce them, the pairs must also hold the offset of the end of stream
+    /// marker of each stream, which maps to the same decompressed offset as
+    /// whatever follows it, so that the end of each block is known. These are
+    /// the rows of the `bzip2blocks` table in a `ratarmount` index, and the
+    /// dictionary returned by `block_offsets()` in `indexed_bzip2`.
+    ///
+    /// The block size of each stream isn't part of these offsets, so blocks
+    /// are decompressed as if they were from a stream with the largest block
+    /// size, which works for all streams.
+    pub fn from_block_offsets<I>(offsets: I) -> io::Result<BzIndex>
+    where
+        I: IntoIterator<Item = (u64, u64)>,
+    {
+        let offsets = offsets.into_iter().collect::<BTreeMap<_, _>>();
+        let mut blocks = Vec::new();
+        let mut iter = offsets.iter().peekable();
+        while let Some((&bit_offset, &offset)) = iter.next() {
+            let (end, next) = match iter.peek() {
+                Some(&(&end, &next)) => (end, next),
+                None => break,
+            };
+            if next < offset || end - bit_offset < block::MAGIC_BITS {
+                return Err(invalid());
+            }
+            // an end of stream marker holds no data
+            if next == offset {
+                continue;
+            }
+            blocks.push(Entry {
+                bit_offset,
+                bits: end - bit_offset,
+                digit: b'9',
+                offset,
+                len: next - offset,
+            });
+        }
+        Ok(BzIndex { blocks })
+    }
+
+    /// Returns the block offsets of this index in the form used by the
+    /// `indexed_bzip2` and `ratarmount` tools, see `from_block_offsets`.
+    pub fn block_offsets(&self) -> BTreeMap<u64, u64> {
+        let mut offsets = BTreeMap::new();
+        for (i, b) in self.blocks.iter().enumerate() {
+            offsets.insert(b.bit_offset, b.offset);
+            let end = b.bit_offset + b.bits;
+            // the end of stream marker directly follows the last block of
+            // each stream
+            match self.blocks.get(i + 1) {
+                Some(next) if next.bit_offset == end => {}
+                _ => {
+                    offsets.insert(end, b.offset + b.len);
+                }
+            }
+        }
+        offsets
+    }
+
+    /// Serializes this index to `w`.
+    ///
+    /// The format starts with the magic `BZIX` and a version byte, followed
+    /// by the number of blocks and, for each block, its block size digit and
+    /// the distance to its start, its compressed size in bits and its
+    /// decompressed size as LEB128 numbers. This takes a few bytes per block.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(8 + self.blocks.len() * 10);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        write_varint(&mut buf, self.blocks.len() as u64);
+        let mut end = 0;
+        for b in &self.blocks {
+            buf.push(b.digit);
+            write_varint(&mut buf, b.bit_offset - end);
+            write_varint(&mut buf, b.bits);
+            write_varint(&mut buf, b.len);
+            end = b.bit_offset + b.bits;
+        }
+        w.write_all(&buf)
+    }
+
+    /// Deserializes an index written by `write_to` from `r`.
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<BzIndex> {
+        let mut header = [0; 5];
+        r.read_exact(&mut header)?;
+        if header[..4] != MAGIC[..] || header[4] != VERSION {
+            return Err(invalid());
+        }
+        let count = read_varint(&mut r)?;
+        let mut blocks = Vec::with_capacity(count.min(1 << 16) as usize);
+        let mut end = 0u64;
+        let mut offset = 0u64;
+        for _ in 0..count {
+            let mut digit = [0];
+            r.read_exact(&mut digit)?;
+            let bit_offset = end.checked_add(read_varint(&mut r)?).ok_or_else(invalid)?;
+            let bits = read_varint(&mut r)?;
+            let len = read_varint(&mut r)?;
+            if !(b'1'..=b'9').contains(&digit[0]) || bits < block::MAGIC_BITS {
+                return Err(invalid());
+            }
+            blocks.push(Entry {
+                bit_offset,
+                bits,
+                digit: digit[0],
+                offset,
+                len,
+            });
+            end = bit_offset.checked_add(bits).ok_or_else(invalid)?;
+            offset = offset.checked_add(len).ok_or_else(invalid)?;
+        }
+        Ok(BzIndex { blocks })
+    }
+}
+
+/// Details of a single block, as returned by `Blocks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    bit_offset: u64,
+    bits: u64,
+    digit: u8,
+    offset: u64,
+    len: u64,
+    crc: u32,
+}
+
+impl BlockInfo {
+    /// Returns the bit offset of the block's magic number within the
+    /// compressed data.
+    pub fn bit_offset(&self) -> u64 {
+        self.bit_offset
+    }
+
+    /// Returns the compressed size of the block in bits.
+    pub fn compressed_bits(&self) -> u64 {
+        self.bits
+    }
+
+    /// Returns the block size (1-9, in units of 100k) of the block's stream.
+    pub fn block_size(&self) -> u32 {
+        u32::from(self.digit - b'0')
+    }
+
+    /// Returns the offset of the block's data within the decompressed output.
+    pub fn uncompressed_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the size of the block's data once decompressed.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the CRC of the block's data stored in the block.
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+}
+
+/// An iterator over the blocks of all streams read from a reader.
+///
+/// Each block is decompressed to learn its size and check its CRC, but the
+/// decompressed data is thrown away right away. The combined CRC of each
+/// stream is checked at its end. Iteration stops after the first error.
+pub struct Blocks<R> {
+    scanner: Scanner<R>,
+    digit: u8,
+    combined_crc: u32,
+    offset: u64,
+    streams: u64,
+    done: bool,
+}
+
+impl<R: Read> Blocks<R> {
+    /// Creates an iterator over the blocks read from `r`.
+    pub fn new(r: R) -> Blocks<R> {
+        Blocks {
+            scanner: Scanner::new(r),
+            digit: 0,
+            combined_crc: 0,
+            offset: 0,
+            streams: 0,
+            done: false,
+        }
+    }
+
+    fn next_block(&mut self) -> io::Result<Option<BlockInfo>> {
+        loop {
+            if self.digit == 0 {
+                if !self.scanner.next_stream()? {
+                    return Ok(None);
+                }
+                self.digit = self.scanner.digit().unwrap();
+                self.combined_crc = 0;
+                self.streams += 1;
+            }
+            let mut segment = match self.scanner.next()? {
+                Item::Block(segment) => segment,
+                Item::End(crc) if crc == self.combined_crc => {
+                    self.digit = 0;
+                    continue;
+                }
+                Item::End(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "bzip2: combined CRC mismatch at the end of a stream",
+                    ))
+                }
+            };
+            // a block which fails to decompress may have been split at a magic
+            // number of either kind which appeared by chance inside of it,
+            // unless it's already longer than any block can be
+            let max = block::max_block_bits(self.digit);
+            let len = loop {
+                let e = match segment.decompressed_len(self.digit) {
+                    Ok(len) => break len,
+                    Err(e) => e,
+                };
+                let next = match self.scanner.next()? {
+                    Item::Block(next) => next,
+                    Item::End(_) => {
+                        match self.scanner.past_end(max.saturating_sub(segment.bits()))? {
+                            Some(next) => {
+                                self.scanner.resume(&next);
+                                next
+                            }
+                            None => return Err(e),
+                        }
+                    }
+                };
+                if segment.bits() + next.bits() > max {
+                    return Err(e);
+                }
+                segment.join(next);
+            };
+            let crc = segment.crc();
+            self.combined_crc = self.combined_crc.rotate_left(1) ^ crc;
+            let info = BlockInfo {
+                bit_offset: segment.offset(),
+                bits: segment.bits(),
+                digit: self.digit,
+                offset: self.offset,
+                len,
+                crc,
+            };
+            self.offset += len;
+            return Ok(Some(info));
+        }
+    }
+}
+
+impl<R> Blocks<R> {
+    /// Returns the number of streams started so far.
+    pub fn streams(&self) -> u64 {
+        self.streams
+    }
+
+    /// Returns the number of bytes read from the underlying stream.
+    pub fn total_in(&self) -> u64 {
+        self.scanner.total_in()
+    }
+
+    /// Consumes this iterator, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.scanner.into_inner()
+    }
+}
+
+impl<R: Read> Iterator for Blocks<R> {
+    type Item = io::Result<BlockInfo>;
+
+    fn next(&mut self) -> Option<io::Result<BlockInfo>> {
+        if self.done {
+            return None;
+        }
+        match self.next_block() {
+            Ok(Some(info)) => Some(Ok(info)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Copies the block at bits `bit_offset..bit_offset + bits` of `data`,
+/// starting with its magic number, into a complete bzip2 stream holding only
+/// that block, with `block_size` (1-9) in its header.
+///
+/// This is how `bzip2recover` gets at the intact blocks of a damaged file,
+/// and the values of a `BlockInfo` can be passed in as well.
+///
+/// # Panics
+///
+/// Panics if the bits aren't all within `data`, or if `block_size` isn't
+/// between 1 and 9.
+pub fn block_stream(data: &[u8], bit_offset: u64, bits: u64, block_size: u32) -> Vec<u8> {
+    assert!(
+        (1..=9).contains(&block_size),
+        "block size must be between 1 and 9"
+    );
+    assert!(
+        bit_offset + bits <= data.len() as u64 * 8,
+        "bits out of range"
+    );
+    Segment::new(data, bit_offset, bit_offset + bits).to_stream(b'0' + block_size as u8)
+}
+
+/// Returns the bit offset and length of everything in `data` which may be a
+/// block: the bits from each block magic number up to the next magic number
+/// of either kind.
+///
+/// Unlike `Blocks`, this doesn't decompress anything, so a magic number
+/// which appeared by chance inside of a block splits it, and damaged blocks
+/// are returned all the same. Each can be passed to `block_stream`.
+pub fn candidate_blocks(data: &[u8]) -> Vec<(u64, u64)> {
+    let mut blocks = Vec::new();
+    let mut next = block::find_magic(data, 0);
+    while let Some((start, magic)) = next {
+        next = block::find_magic(data, start + 1);
+        if let (Magic::Block, Some((end, _))) = (magic, next) {
+            blocks.push((start, end - start));
+        }
+    }
+    blocks
+}
+
+/// What `verify` found in the data it checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyReport {
+    streams: u64,
+    blocks: u64,
+    compressed_size: u64,
+    uncompressed_size: u64,
+}
+
+impl VerifyReport {
+    /// Returns the number of streams in the data.
+    pub fn streams(&self) -> u64 {
+        self.streams
+    }
+
+    /// Returns the number of blocks in all streams.
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Returns the number of compressed bytes read.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Returns the size of the data once decompressed.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
+    }
+}
+
+/// Checks the integrity of all streams read from `r`, like `bzip2 -t` does.
+///
+/// The data is decompressed and thrown away, checking the CRC of every block
+/// and the combined CRC of every stream. An error is returned if any check
+/// fails, if the data is truncated, or if anything other than bzip2 streams
+/// follows them.
+pub fn verify<R: Read>(r: R) -> io::Result<VerifyReport> {
+    let mut blocks = Blocks::new(r);
+    let mut report = VerifyReport {
+        streams: 0,
+        blocks: 0,
+        compressed_size: 0,
+        uncompressed_size: 0,
+    };
+    for block in blocks.by_ref() {
+        report.blocks += 1;
+        report.uncompressed_size += block?.uncompressed_size();
+    }
+    report.streams = blocks.streams();
+    report.compressed_size = blocks.total_in();
+    Ok(report)
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "bzip2: invalid index")
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut b = [0];
+        r.read_exact(&mut b)?;
+        v |= u64::from(b[0] & 0x7f) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_stream, candidate_blocks, verify, Blocks, BzIndex};
+    use rand::{thread_rng, Rng};
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+    use {read, Compression};
+
+    #[test]
+    fn roundtrip() {
+        let mut m = vec![0u8; 250 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        read::BzEncoder::new(&m[..], Compression::fast())
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        let index = BzIndex::build(&compressed[..]).unwrap();
+        assert_eq!(index.block_count(), 3);
+        assert_eq!(index.uncompressed_size(), m.len() as u64);
+
+        let mut buf = Vec::new();
+        index.write_to(&mut buf).unwrap();
+        assert!(buf.len() < 40);
+        let read = BzIndex::read_from(&buf[..]).unwrap();
+        assert_eq!(read.block_count(), 3);
+        for (a, b) in index.blocks().iter().zip(read.blocks()) {
+            assert_eq!(a.bit_offset, b.bit_offset);
+            assert_eq!(a.bits, b.bits);
+            assert_eq!(a.digit, b.digit);
+            assert_eq!(a.offset, b.offset);
+            assert_eq!(a.len, b.len);
+        }
+
+        assert!(BzIndex::read_from(&buf[..buf.len() - 1]).is_err());
+        assert!(BzIndex::read_from(&b"BZIX\x02\x00"[..]).is_err());
+    }
+
+    #[test]
+    fn block_offsets() {
+        let mut m = vec![0u8; 150 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        for _ in 0..2 {
+            read::BzEncoder::new(&m[..], Compression::fast())
+                .read_to_end(&mut compressed)
+                .unwrap();
+        }
+
+        let index = BzIndex::build(&compressed[..]).unwrap();
+        let offsets = index.block_offsets();
+        // two blocks and an end of stream marker per stream
+        assert_eq!(offsets.len(), 6);
+        assert_eq!(offsets.iter().next(), Some((&32, &0)));
+        assert_eq!(offsets.values().last(), Some(&(2 * m.len() as u64)));
+
+        let imported = BzIndex::from_block_offsets(offsets.clone()).unwrap();
+        assert_eq!(imported.block_count(), 4);
+        assert_eq!(imported.uncompressed_size(), 2 * m.len() as u64);
+        assert_eq!(imported.block_offsets(), offsets);
+
+        let mut d = read::BzSeekDecoder::with_index(io::Cursor::new(&compressed), 0, imported);
+        d.seek(SeekFrom::Start(140_000)).unwrap();
+        let mut data = Vec::new();
+        d.read_to_end(&mut data).unwrap();
+        assert!(data[..10_000] == m[140_000..]);
+        assert!(data[10_000..] == m[..]);
+
+        assert!(BzIndex::from_block_offsets(vec![(32, 10), (100, 0)]).is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        let mut m = vec![0u8; 250 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        read::BzEncoder::new(&m[..], Compression::fast())
+            .read_to_end(&mut compressed)
+            .unwrap();
+        let first = compressed.len() as u64;
+        read::BzEncoder::new(&m[..1000], Compression::best())
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        let blocks = Blocks::new(&compressed[..])
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].bit_offset(), 32);
+        assert_eq!(blocks[0].block_size(), 1);
+        assert_eq!(
+            blocks[1].bit_offset(),
+            blocks[0].bit_offset() + blocks[0].compressed_bits()
+        );
+        assert_eq!(blocks[3].bit_offset(), first * 8 + 32);
+        assert_eq!(blocks[3].block_size(), 9);
+        assert_eq!(blocks[3].uncompressed_offset(), m.len() as u64);
+        assert_eq!(blocks[3].uncompressed_size(), 1000);
+        let total = blocks.iter().map(|b| b.uncompressed_size()).sum::<u64>();
+        assert_eq!(total, 251 * 1000);
+
+        let mut crc = [0; 4];
+        crc.copy_from_slice(&compressed[first as usize + 10..first as usize + 14]);
+        assert_eq!(blocks[3].crc(), u32::from_be_bytes(crc));
+
+        let mut iter = Blocks::new(&compressed[..compressed.len() - 1]);
+        assert!(iter.by_ref().any(|b| b.is_err()));
+        assert!(iter.next().is_none());
+        assert!(Blocks::new(&b"BZh9garbage"[..]).next().unwrap().is_err());
+        assert!(Blocks::new(&[][..]).next().is_none());
+    }
+
+    #[test]
+    fn corrupt_block() {
+        let mut m = vec![0u8; 2_000 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        read::BzEncoder::new(&m[..], Compression::fast())
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        // the first block isn't joined with all of the ones after it
+        compressed[1000] ^= 0x55;
+        let mut iter = Blocks::new(&compressed[..]);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.total_in() < compressed.len() as u64 / 2);
+        assert!(verify(&compressed[..]).is_err());
+        assert!(BzIndex::build(&compressed[..]).is_err());
+    }
+
+    #[test]
+    fn recover_blocks() {
+        let mut m = vec![0u8; 250 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        read::BzEncoder::new(&m[..], Compression::fast())
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        let blocks = Blocks::new(&compressed[..])
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let candidates = candidate_blocks(&compressed);
+        assert_eq!(candidates.len(), blocks.len());
+        let mut data = Vec::new();
+        for (block, &(bit_offset, bits)) in blocks.iter().zip(&candidates) {
+            assert_eq!(
+                (block.bit_offset(), block.compressed_bits()),
+                (bit_offset, bits)
+            );
+            let stream = block_stream(&compressed, bit_offset, bits, block.block_size());
+            data.extend(::decompress(&stream).unwrap());
+        }
+        assert!(data == m);
+
+        // a damaged block is still found, it just doesn't decompress
+        compressed[1000] ^= 0x55;
+        let (bit_offset, bits) = candidate_blocks(&compressed)[0];
+        assert!(::decompress(&block_stream(&compressed, bit_offset, bits, 1)).is_err());
+        assert!(candidate_blocks(b"BZh9").is_empty());
+    }
+
+    #[test]
+    fn verify_streams() {
+        let mut m = vec![0u8; 150 * 1000];
+        thread_rng().fill(&mut m[..]);
+        let mut compressed = Vec::new();
+        for &data in &[&m[..], &[][..], &m[..10]] {
+            read::BzEncoder::new(data, Compression::fast())
+                .read_to_end(&mut compressed)
+                .unwrap();
+        }
+
+        let report = verify(&compressed[..]).unwrap();
+        assert_eq!(report.streams(), 3);
+        assert_eq!(report.blocks(), 3);
+        assert_eq!(report.compressed_size(), compressed.len() as u64);
+        assert_eq!(report.uncompressed_size(), m.len() as u64 + 10);
+
+        compressed[1000] ^= 1;
+        assert!(verify(&compressed[..]).is_err());
+    }
+}
@@ -9,6 +9,12 @@
 //! Access to the raw decompression/compression stream is also provided through
 //! the `raw` module which has a much closer interface to libbz2.
 //!
+//! By default libbz2 is linked through the `bzip2-sys` crate, building its C
+//! sources if no system library is found. With default features disabled and
+//! the `libbz2-rs-sys` feature enabled, the pure-Rust implementation of the
+//! `libbz2-rs-sys` crate is used instead, e.g. for targets without a C
+//! toolchain. The API of this crate is the same either way.
+//!
 //! # Example
 //!
 //! ```
@@ -27,6 +33,17 @@
 //! assert_eq!(contents, "Hello, World!");
 //! ```
 //!
+//! For data which is already in memory, `compress` and `decompress` do the
+//! same in one call each:
+//!
+//! ```
+//! let compressed = bzip2::compress(b"Hello, World!", bzip2::Compression::best());
+//! let decompressed = bzip2::decompress(&compressed).unwrap();
+//! assert_eq!(decompressed, b"Hello, World!");
+//! ```
+//!
+//! The `fs` module does the same for whole files given their paths.
+//!
 //! # Multistreams (e.g. Wikipedia or pbzip2)
 //!
 //! Some tools such as pbzip2 or data from sources such as Wikipedia
@@ -40,8 +57,45 @@
 //! incomplete and exactly 900K bytes, you probably need a
 //! `MultiBzDecoder`.
 //!
+//! Splitting data into independent members also allows it to be compressed
+//! on several threads at once, which is what `read::ParBzEncoder` and
+//! `write::ParBzEncoder` do. With the `rayon` feature of this crate enabled
+//! they can also run on the global rayon thread pool instead of spawning
+//! threads of their own, and a `ThreadPool` can be shared by any number of
+//! them. Decompression can be spread the same way:
+//! `read::ParMultiBzDecoder` decodes the members of a multistream
+//! concurrently, and `read::ParBzDecoder` decodes the blocks of a single
+//! stream concurrently. Given 0 threads, all of them use as many as
+//! `available_threads` reports the process can run at once.
+//!
+//! Since each block can be decompressed on its own, `read::BzSeekDecoder`
+//! can also seek within the decompressed data of a file, only decompressing
+//! the blocks which are read from. The `BzIndex` it builds to do so can be
+//! saved and loaded again to skip scanning the same file twice.
+//!
+//! The `CompressStream` and `DecompressStream` traits, implemented by the
+//! encoder and decoder of the `poll` module, let code be generic over the
+//! compression format when other crates' codecs implement them too.
+//!
+//! # Untrusted input
+//!
+//! Decompressing data, however damaged or malicious it is, never panics:
+//! anything libbz2 rejects, or any status it returns which doesn't make sense
+//! at that point, comes back as an `Error` or an `io::Error` wrapping one.
+//! Panics are left to misuse which is documented as such, like the `# Panics`
+//! sections of constructors given zero sizes or of `Compress::new` and
+//! `Decompress::new` finding a libbz2 older than 1.0.6 at runtime, and to
+//! running out of memory outside of libbz2. Limits such as `decompress_with_limit` guard against
+//! input decompressing to far more than expected.
+//!
 //! # Async I/O
 //!
+//! The `poll` module has an encoder and decoder working on caller-provided
+//! buffers which report through `std::task::Poll` whether they need more
+//! room or more input, for integrating with any runtime by hand. The `owned`
+//! module does the same with owned buffers handed back and forth instead,
+//! as completion-based runtimes like tokio-uring or monoio need.
+//!
 //! This crate optionally can support async I/O streams with the Tokio stack via
 //! the `tokio` feature of this crate:
 //!
@@ -59,11 +113,42 @@
 //! flushed/written when they are dropped, and this is not always a suitable
 //! time to perform I/O. If I/O streams are flushed before drop, however, then
 //! these operations will be a noop.
+//!
+//! With the `futures-io` feature enabled, the `futures_io` module provides
+//! encoders and decoders for the `AsyncRead`, `AsyncBufRead` and `AsyncWrite`
+//! traits of the `futures` 0.3 ecosystem instead, which are usable from any
+//! executor.
+//!
+//! The `tokio1` feature does the same for tokio 1.x in the `tokio1` module,
+//! whose `bufread` types decode straight out of an `AsyncBufRead` such as a
+//! `tokio::io::BufReader`. Both modules also have a `write::ParBzEncoder`,
+//! which compresses on a `ThreadPool` while the async task only does the
+//! I/O, and a `read::BzSeekDecoder` seeking within the decompressed data of
+//! a source which both reads and seeks asynchronously, given its `BzIndex`.
+//!
+//! For framed transports, the `codec` feature adds `codec::BzCodec`, a
+//! `tokio_util::codec` encoder and decoder sending each frame as a bzip2
+//! stream of its own. And the `stream` feature adds the `stream` module,
+//! whose adapters compress or decompress a `futures::Stream` of `Bytes`
+//! chunks such as an HTTP body, while with the `sink` feature
+//! `sink::BzEncoder` compresses the chunks sent into a `futures::Sink`.
+//! Wrappers implementing `http_body::Body` directly, e.g. for serving or
+//! accepting `Content-Encoding: bzip2` with hyper, are in the `body` module
+//! of the `body` feature, and the `tower` feature adds a middleware doing
+//! so for every request and response of a service.
+//!
+//! For porting C code, the `stdio` feature builds libbz2 with its `FILE *`
+//! based functions, which the `stdio` module wraps in a reader and writer.
 
 #![deny(missing_docs)]
 #![doc(html_root_url = "https://docs.rs/bzip2/")]
 
+#[cfg(not(feature = "libbz2-rs-sys"))]
 extern crate bzip2_sys as ffi;
+#[cfg(feature = "libbz2-rs-sys")]
+extern crate libbz2_rs_sys as ffi;
+#[cfg(not(any(feature = "bzip2-sys", feature = "libbz2-rs-sys")))]
+compile_error!("either the `bzip2-sys` or the `libbz2-rs-sys` feature has to be enabled");
 extern crate libc;
 #[cfg(test)]
 extern crate partial_io;
@@ -74,15 +159,76 @@ extern crate rand;
 #[cfg(feature = "tokio")]
 #[macro_use]
 extern crate tokio_io;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 #[cfg(feature = "tokio")]
 extern crate futures;
+#[cfg(feature = "stream")]
+extern crate futures_core;
+#[cfg(feature = "futures-io")]
+extern crate futures_io as futures_io_crate;
+#[cfg(feature = "sink")]
+extern crate futures_sink;
+#[cfg(feature = "tower")]
+extern crate http;
+#[cfg(feature = "body")]
+extern crate http_body;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "tokio1")]
+extern crate tokio1 as tokio_crate;
+#[cfg(feature = "codec")]
+extern crate tokio_util;
+#[cfg(feature = "tower")]
+extern crate tower_layer;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+
+use std::cmp;
+use std::os::raw::c_uint;
+use std::time::{Duration, Instant};
 
+pub use buffers::{BufferPool, BufferSizes};
+pub use header::is_bzip2;
+pub use index::{block_stream, candidate_blocks, verify, BlockInfo, Blocks, BzIndex, VerifyReport};
 pub use mem::{Action, Compress, Decompress, Error, Status};
+pub use par::{available_threads, Backpressure, ParConfig, ThreadPool};
+pub use traits::{CompressStream, DecompressStream};
 
+#[cfg(any(feature = "futures-io", feature = "tokio1"))]
+mod aio;
+mod block;
+mod buffers;
+mod coder;
+mod index;
 mod mem;
+mod par;
+mod traits;
 
+#[cfg(feature = "body")]
+pub mod body;
 pub mod bufread;
+#[cfg(feature = "codec")]
+pub mod codec;
+pub mod fs;
+#[cfg(feature = "futures-io")]
+pub mod futures_io;
+pub mod header;
+pub mod owned;
+pub mod poll;
 pub mod read;
+#[cfg(feature = "sink")]
+pub mod sink;
+#[cfg(feature = "stdio")]
+pub mod stdio;
+#[cfg(feature = "stream")]
+pub mod stream;
+#[cfg(feature = "tokio1")]
+pub mod tokio1;
+#[cfg(feature = "tower")]
+pub mod tower;
 pub mod write;
 
 /// When compressing data, the compression level can be specified by a value in
@@ -115,6 +261,26 @@ impl Compression {
     pub fn level(&self) -> u32 {
         self.0
     }
+
+    /// Returns the lowest level up to this one whose blocks still fit all of
+    /// `len` bytes of input.
+    ///
+    /// The level sets the size of the blocks, from 100k for level 1 to 900k
+    /// for level 9, and the compressor takes up memory for a whole block
+    /// however little input there is. Input which fits in a block compresses
+    /// the same in a smaller one, so payloads of a known size, or a known
+    /// bound on it, can be compressed at this level with less memory and no
+    /// loss. For example 50k of input needs no more than level 1.
+    pub fn for_size(&self, len: u64) -> Compression {
+        if self.0 == 0 {
+            return *self;
+        }
+        // runs of 4 bytes are stored as 5 in a block, and libbz2 leaves 19
+        // bytes of each block unused
+        let needed = len.saturating_add(len / 4).saturating_add(19);
+        let level = needed.div_ceil(100_000).clamp(1, u64::from(self.0));
+        Compression(level as u32)
+    }
 }
 
 impl Default for Compression {
@@ -123,3 +289,609 @@ impl Default for Compression {
         Compression(6)
     }
 }
+
+/// How hard the compressor tries its standard sorting algorithm on a block
+/// before falling back to a slower one, see `Compress::new`.
+///
+/// The standard algorithm is fast on typical data but slows down
+/// dramatically on highly repetitive input, while the fallback is about
+/// three times slower in general but never degrades. With `adaptive` the
+/// start of each stream is checked for such input and the factor lowered to
+/// that of `robust` when it's found.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct WorkFactor {
+    factor: u32,
+    adaptive: bool,
+}
+
+impl WorkFactor {
+    /// Creates a work factor with the given value, from 1 to 250.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `factor` is zero or above 250.
+    pub fn new(factor: u32) -> WorkFactor {
+        assert!(
+            (1..=250).contains(&factor),
+            "work factor must be between 1 and 250"
+        );
+        WorkFactor {
+            factor,
+            adaptive: false,
+        }
+    }
+
+    /// Falls back as soon as the standard algorithm runs into trouble,
+    /// bounding the time spent on adversarial input at the cost of
+    /// compressing some ordinary data more slowly.
+    pub fn robust() -> WorkFactor {
+        WorkFactor::new(1)
+    }
+
+    /// Sticks with the standard algorithm for longer than the default,
+    /// which is faster on mildly repetitive data but much slower on the
+    /// worst cases.
+    pub fn throughput() -> WorkFactor {
+        WorkFactor::new(100)
+    }
+
+    /// Lowers this work factor to that of `robust` for streams which start
+    /// out highly repetitive.
+    pub fn adaptive(self) -> WorkFactor {
+        WorkFactor {
+            adaptive: true,
+            ..self
+        }
+    }
+
+    /// Returns the value of the work factor, as passed to `Compress::new`.
+    pub fn factor(&self) -> u32 {
+        self.factor
+    }
+
+    /// Returns whether this work factor is adapted to the input.
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    /// Returns the value of the work factor to compress a stream starting
+    /// with `data` with, which is that of `robust` if this one is adaptive
+    /// and `data` looks like it would make the standard algorithm struggle.
+    pub fn for_input(&self, data: &[u8]) -> u32 {
+        if self.adaptive && repetitive(data) {
+            cmp::min(self.factor, WorkFactor::robust().factor)
+        } else {
+            self.factor
+        }
+    }
+}
+
+impl Default for WorkFactor {
+    /// The default of libbz2, 30.
+    fn default() -> WorkFactor {
+        WorkFactor::new(30)
+    }
+}
+
+/// Whether the start of `data` consists of only a few distinct 4 byte
+/// sequences, as long runs and short periodic patterns do.
+fn repetitive(data: &[u8]) -> bool {
+    // nothing this short takes long to sort either way
+    if data.len() < 4096 {
+        return false;
+    }
+    let sample = &data[..cmp::min(data.len(), 64 * 1024)];
+    // an approximate count of the distinct sequences by their hashes
+    let mut seen = vec![0u64; 1024];
+    let mut distinct = 0;
+    for window in sample.windows(4) {
+        let word = u32::from_le_bytes([window[0], window[1], window[2], window[3]]);
+        let hash = (word.wrapping_mul(0x9e37_79b1) >> 16) as usize;
+        let (slot, bit) = (hash / 64, 1 << (hash % 64));
+        if seen[slot] & bit == 0 {
+            seen[slot] |= bit;
+            distinct += 1;
+        }
+    }
+    distinct * 64 < sample.len()
+}
+
+/// Summary of a compression stream, as returned by `finish_with_stats` on the
+/// writer-based encoders and `stats` on the reader-based ones.
+#[derive(Copy, Clone, Debug)]
+pub struct Stats {
+    total_in: u64,
+    total_out: u64,
+    members: u64,
+    elapsed: Duration,
+}
+
+impl Stats {
+    fn new(total_in: u64, total_out: u64, members: u64, elapsed: Duration) -> Stats {
+        Stats {
+            total_in,
+            total_out,
+            members,
+            elapsed,
+        }
+    }
+
+    /// Number of uncompressed bytes consumed by the encoder.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Number of compressed bytes produced by the encoder.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// The compression ratio, `total_out() / total_in()`.
+    ///
+    /// Returns 0.0 if no input was consumed.
+    pub fn ratio(&self) -> f64 {
+        if self.total_in == 0 {
+            0.0
+        } else {
+            self.total_out as f64 / self.total_in as f64
+        }
+    }
+
+    /// Number of bzip2 members (complete streams) produced.
+    pub fn members(&self) -> u64 {
+        self.members
+    }
+
+    /// Time elapsed between the encoder first compressing data and the point
+    /// these statistics were taken.
+    ///
+    /// This is always zero on `wasm32-unknown-unknown`, which has no clock.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+/// When an encoder started compressing, for `Stats::elapsed`.
+///
+/// The time is only taken once there's data, and never on
+/// `wasm32-unknown-unknown` where `Instant::now` panics.
+#[derive(Default)]
+struct Timer(Option<Instant>);
+
+impl Timer {
+    fn start(&mut self) {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            if self.0.is_none() {
+                self.0 = Some(Instant::now());
+            }
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.0.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+/// Compresses `data` at the given level into a single bzip2 stream.
+///
+/// This is for payloads which are already in memory; the encoders in the
+/// `read`, `bufread` and `write` modules don't need to hold all of the data
+/// at once. Payloads smaller than a block at `level` are compressed with the
+/// smaller blocks of `level.for_size(data.len())`, which only changes the
+/// block size recorded in the header of the output.
+pub fn compress(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut c = Compress::new(level.for_size(data.len() as u64), 30);
+    // worst case size of a bzip2 stream, as documented for
+    // `BZ2_bzBuffToBuffCompress`
+    let mut out = Vec::with_capacity(data.len() + data.len() / 100 + 600);
+    loop {
+        let rest = &data[c.total_in() as usize..];
+        // libbz2 takes at most `c_uint::MAX` bytes of input per call, and
+        // `Finish` may only be asked for once all of it is handed over
+        let len = cmp::min(rest.len(), c_uint::MAX as usize);
+        let action = if len == rest.len() {
+            Action::Finish
+        } else {
+            Action::Run
+        };
+        match c.compress_vec(&rest[..len], &mut out, action) {
+            Ok(Status::StreamEnd) => return out,
+            Ok(_) => {}
+            Err(e) => panic!("bzip2 compression failed: {}", e),
+        }
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+    }
+}
+
+/// Decompresses all of `data`, which holds one or more bzip2 streams back to
+/// back, as `read::MultiBzDecoder` would.
+///
+/// Returns `Error::DataMagic` if `data` or the bytes following one of its
+/// streams don't start with a bzip2 header, and `Error::Data` if a stream is
+/// corrupt or `data` ends within one.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    decompress_with_limit(data, usize::MAX)
+}
+
+/// Same as `decompress`, but returns `Error::OutputLimit` as soon as the
+/// decompressed data grows past `limit` bytes.
+///
+/// This guards against small inputs expanding to huge outputs, as bzip2 data
+/// can by a factor of tens of millions when decoding untrusted data.
+pub fn decompress_with_limit(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    // room for one byte past the limit tells whether there's any more output
+    let max = limit.saturating_add(1);
+    let mut out = Vec::with_capacity(cmp::min(data.len().saturating_mul(4), max));
+    let mut start = 0;
+    loop {
+        let mut d = Decompress::new(false);
+        loop {
+            let rest = &data[start + d.total_in() as usize..];
+            // libbz2 takes at most `c_uint::MAX` bytes of input per call
+            let len = cmp::min(rest.len(), c_uint::MAX as usize);
+            let status = d.decompress_vec(&rest[..len], &mut out)?.into_result()?;
+            if out.len() > limit {
+                return Err(Error::OutputLimit);
+            }
+            if status.is_stream_end() {
+                break;
+            }
+            if out.len() == out.capacity() {
+                let additional = cmp::max(out.capacity(), 4096);
+                out.reserve_exact(cmp::min(additional, max - out.len()));
+            } else if start + d.total_in() as usize == data.len() {
+                return Err(Error::Data);
+            }
+        }
+        start += d.total_in() as usize;
+        if start == data.len() {
+            return Ok(out);
+        }
+    }
+}
+
+/// Compresses `data` at the given level into `output`, returning the number
+/// of bytes written to it.
+///
+/// Returns `Error::OutputLimit` if the stream doesn't fit, which it always
+/// does given `data.len() + data.len() / 100 + 600` bytes. Small payloads
+/// are compressed with small blocks, as by `compress`.
+pub fn compress_into(data: &[u8], output: &mut [u8], level: Compression) -> Result<usize, Error> {
+    let mut c = Compress::new(level.for_size(data.len() as u64), 30);
+    loop {
+        let rest = &data[c.total_in() as usize..];
+        let len = cmp::min(rest.len(), c_uint::MAX as usize);
+        let action = if len == rest.len() {
+            Action::Finish
+        } else {
+            Action::Run
+        };
+        let out = &mut output[c.total_out() as usize..];
+        // the end of the stream is only reported once all of it is written
+        if out.is_empty() {
+            return Err(Error::OutputLimit);
+        }
+        let room = cmp::min(out.len(), c_uint::MAX as usize);
+        if c.compress(&rest[..len], &mut out[..room], action)? == Status::StreamEnd {
+            return Ok(c.total_out() as usize);
+        }
+    }
+}
+
+/// Decompresses all of `data` into `output` as `decompress` does, returning
+/// the number of bytes written to it.
+///
+/// Returns `Error::OutputLimit` if the decompressed data doesn't fit.
+pub fn decompress_into(data: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    let (mut start, mut written) = (0, 0);
+    loop {
+        let mut d = Decompress::new(false);
+        loop {
+            let (before_in, before_out) = (d.total_in(), d.total_out());
+            let rest = &data[start + before_in as usize..];
+            let len = cmp::min(rest.len(), c_uint::MAX as usize);
+            let out = &mut output[written + before_out as usize..];
+            let room = cmp::min(out.len(), c_uint::MAX as usize);
+            if d.decompress(&rest[..len], &mut out[..room])?
+                .into_result()?
+                .is_stream_end()
+            {
+                break;
+            }
+            if (d.total_in(), d.total_out()) == (before_in, before_out) {
+                // with all of the input consumed it's truncated, whether or
+                // not there's more output to come
+                if room == 0 && start + (before_in as usize) < data.len() {
+                    return Err(Error::OutputLimit);
+                }
+                return Err(Error::Data);
+            }
+        }
+        start += d.total_in() as usize;
+        written += d.total_out() as usize;
+        if start == data.len() {
+            return Ok(written);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::os::raw::c_uint;
+
+    use super::{
+        compress, compress_into, decompress, decompress_into, decompress_with_limit, Action,
+        Compress, Compression, Decompress, Error, Status, WorkFactor,
+    };
+
+    #[test]
+    fn compress_roundtrip() {
+        let data = (0..250_000u32).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        for input in [&[][..], b"hello", &data] {
+            let compressed = compress(input, Compression::fast());
+            let mut out = Vec::new();
+            ::read::BzDecoder::new(&compressed[..])
+                .read_to_end(&mut out)
+                .unwrap();
+            assert!(out == input);
+        }
+    }
+
+    #[test]
+    fn decompress_roundtrip() {
+        let data = (0..250_000u32).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        for input in [&[][..], b"hello", &data] {
+            let compressed = compress(input, Compression::fast());
+            assert!(decompress(&compressed).unwrap() == input);
+        }
+
+        let mut multi = compress(b"hello ", Compression::default());
+        multi.extend(compress(b"world", Compression::default()));
+        assert_eq!(decompress(&multi).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn decompress_errors() {
+        let compressed = compress(b"hello", Compression::default());
+        assert_eq!(decompress(&[]), Err(Error::Data));
+        assert_eq!(
+            decompress(&compressed[..compressed.len() - 1]),
+            Err(Error::Data)
+        );
+        assert_eq!(decompress(b"definitely not bzip2"), Err(Error::DataMagic));
+
+        let mut trailing = compressed.clone();
+        trailing.extend_from_slice(b"garbage");
+        assert_eq!(decompress(&trailing), Err(Error::DataMagic));
+
+        let mut corrupt = compressed;
+        corrupt[20] ^= 0xff;
+        assert!(decompress(&corrupt).is_err());
+    }
+
+    #[test]
+    fn decompress_limit() {
+        let data = vec![0; 100_000];
+        let compressed = compress(&data, Compression::default());
+        assert!(decompress_with_limit(&compressed, data.len()).unwrap() == data);
+        assert_eq!(
+            decompress_with_limit(&compressed, data.len() - 1),
+            Err(Error::OutputLimit)
+        );
+        assert_eq!(
+            decompress_with_limit(&compressed, 0),
+            Err(Error::OutputLimit)
+        );
+
+        let empty = compress(&[], Compression::default());
+        assert_eq!(decompress_with_limit(&empty, 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn into_slices() {
+        let data = (0..100_000u32).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        let mut compressed = vec![0; data.len() + data.len() / 100 + 600];
+        let n = compress_into(&data, &mut compressed, Compression::default()).unwrap();
+        compressed.truncate(n);
+        assert!(compressed == compress(&data, Compression::default()));
+        assert_eq!(
+            compress_into(&data, &mut vec![0; n - 1], Compression::default()),
+            Err(Error::OutputLimit)
+        );
+        assert_eq!(
+            compress_into(&data, &mut compressed.clone(), Compression::default()),
+            Ok(n)
+        );
+
+        let mut out = vec![0; data.len()];
+        assert_eq!(decompress_into(&compressed, &mut out), Ok(data.len()));
+        assert!(out == data);
+        assert_eq!(
+            decompress_into(&compressed, &mut out[..data.len() - 1]),
+            Err(Error::OutputLimit)
+        );
+        assert_eq!(
+            decompress_into(&compressed[..n - 1], &mut out),
+            Err(Error::Data)
+        );
+
+        let mut multi = compressed.clone();
+        multi.extend_from_slice(&compressed);
+        let mut out = vec![0; data.len() * 2 + 1];
+        assert_eq!(decompress_into(&multi, &mut out), Ok(data.len() * 2));
+    }
+
+    #[test]
+    fn error_kinds() {
+        use std::io::{self, ErrorKind};
+
+        fn kinds(data: &[u8]) -> [ErrorKind; 4] {
+            let read = |mut r: Box<dyn Read + '_>| r.read_to_end(&mut Vec::new()).unwrap_err();
+            let mut w = ::write::BzDecoder::new(Vec::new());
+            let write = w.write_all(data).and_then(|()| w.finish().map(|_| ()));
+            [
+                read(Box::new(::read::BzDecoder::new(data))).kind(),
+                read(Box::new(::read::MultiBzDecoder::new(data))).kind(),
+                read(Box::new(::bufread::BzDecoder::new(data))).kind(),
+                write.unwrap_err().kind(),
+            ]
+        }
+
+        let data = (0..100_000u32).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        let compressed = compress(&data, Compression::fast());
+        let mut corrupt = compressed.clone();
+        corrupt[100] ^= 0xff;
+        assert_eq!(kinds(&corrupt), [ErrorKind::InvalidData; 4]);
+        assert_eq!(kinds(b"definitely not bzip2"), [ErrorKind::InvalidData; 4]);
+        let truncated = &compressed[..compressed.len() / 2];
+        assert_eq!(kinds(truncated), [ErrorKind::UnexpectedEof; 4]);
+
+        assert_eq!(
+            io::Error::from(Error::Param).kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn not_bzip2() {
+        fn errors(data: &[u8]) -> Vec<Option<Error>> {
+            let read = |mut r: Box<dyn Read + '_>| r.read_to_end(&mut Vec::new()).unwrap_err();
+            let mut w = ::write::BzDecoder::new(Vec::new());
+            let write = w.write_all(data).and_then(|()| w.finish().map(|_| ()));
+            [
+                read(Box::new(::read::BzDecoder::new(data))),
+                read(Box::new(::read::MultiBzDecoder::new(data))),
+                read(Box::new(::read::ParBzDecoder::new(data, 2))),
+                read(Box::new(::read::ParMultiBzDecoder::new(data, 2))),
+                write.unwrap_err(),
+            ]
+            .iter()
+            .map(Error::from_io)
+            .collect()
+        }
+
+        let gzip = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03";
+        assert_eq!(errors(gzip), [Some(Error::DataMagic); 5]);
+
+        let mut corrupt = compress(&[7; 10_000], Compression::fast());
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 0xff;
+        assert!(errors(&corrupt)
+            .iter()
+            .all(|e| *e != Some(Error::DataMagic)));
+    }
+
+    #[test]
+    fn damaged_input_never_panics() {
+        let data = (0..50_000u32).map(|i| (i % 31) as u8).collect::<Vec<_>>();
+        let compressed = compress(&data, Compression::fast());
+        let mut out = vec![0; data.len()];
+        for i in (0..compressed.len()).step_by(7) {
+            let mut damaged = compressed.clone();
+            damaged[i] ^= 1 << (i % 8);
+            for input in [&damaged[..], &compressed[..i]] {
+                let _ = decompress(input);
+                let _ = decompress_into(input, &mut out);
+                let _ = ::read::MultiBzDecoder::new(input).read_to_end(&mut Vec::new());
+                let _ = ::write::BzDecoder::new(Vec::new()).write_all(input);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn oversized_buffers() {
+        // zeroed allocations are only backed by memory once they're touched,
+        // which none of these get to
+        let mut huge = vec![0; c_uint::MAX as usize + 1];
+        let mut c = Compress::new(Compression::fast(), 30);
+        assert_eq!(
+            c.compress(&huge, &mut [0; 10], Action::Run),
+            Err(Error::Param)
+        );
+        assert_eq!(
+            c.compress(b"hello", &mut huge, Action::Finish),
+            Err(Error::Param)
+        );
+        let mut d = Decompress::new(false);
+        assert_eq!(d.decompress(&huge, &mut [0; 10]), Err(Error::Param));
+        assert_eq!(d.decompress(&[], &mut huge), Err(Error::Param));
+        assert_eq!((c.total_in(), d.total_in()), (0, 0));
+    }
+
+    #[test]
+    fn work_factor() {
+        assert_eq!(WorkFactor::default().factor(), 30);
+        let adaptive = WorkFactor::throughput().adaptive();
+        assert!(adaptive.is_adaptive() && !WorkFactor::throughput().is_adaptive());
+
+        let periodic = b"abcabd".repeat(20_000);
+        let mut x = 1u32;
+        let noisy = (0..100_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect::<Vec<_>>();
+        let text = b"the quick brown fox jumps over the lazy dog "
+            .iter()
+            .cycle()
+            .zip(&noisy)
+            .map(|(&c, &n)| if n < 40 { n } else { c })
+            .collect::<Vec<_>>();
+        assert_eq!(adaptive.for_input(&periodic), 1);
+        assert_eq!(adaptive.for_input(&[0; 100_000]), 1);
+        assert_eq!(adaptive.for_input(&periodic[..1000]), 100);
+        assert_eq!(adaptive.for_input(&noisy), 100);
+        assert_eq!(adaptive.for_input(&text), 100);
+        assert_eq!(WorkFactor::throughput().for_input(&periodic), 100);
+
+        // the work factor doesn't change the output, only how it's sorted
+        let mut w = ::write::BzEncoder::new(Vec::new(), Compression::fast());
+        w.set_work_factor(adaptive);
+        w.write_all(&periodic).unwrap();
+        let compressed = w.finish().unwrap();
+        assert!(compressed == compress(&periodic, Compression::fast()));
+        let mut r = ::read::BzEncoder::new(&periodic[..], Compression::fast());
+        r.set_work_factor(WorkFactor::robust());
+        let mut out = Vec::new();
+        r.read_to_end(&mut out).unwrap();
+        assert!(out == compressed);
+    }
+
+    #[test]
+    fn status() {
+        assert!(Status::StreamEnd.is_stream_end() && !Status::Ok.is_stream_end());
+        assert!(Status::RunOk.is_ok() && !Status::MemNeeded.is_ok());
+        assert_eq!(Status::FinishOk.into_result(), Ok(Status::FinishOk));
+        assert_eq!(Status::MemNeeded.into_result(), Err(Error::Mem));
+        assert_eq!(Status::MemNeeded.to_string(), Error::Mem.to_string(),);
+    }
+
+    #[test]
+    fn error_codes() {
+        let errors = [
+            Error::Sequence,
+            Error::Param,
+            Error::Mem,
+            Error::Data,
+            Error::DataMagic,
+            Error::Io,
+            Error::UnexpectedEof,
+            Error::OutputLimit,
+            Error::Config,
+        ];
+        for (i, e) in errors.iter().enumerate() {
+            assert_eq!(e.code(), -(i as i32) - 1);
+            assert_eq!(Error::from_code(e.code()), Some(*e));
+        }
+        assert_eq!(Error::from_code(0), None);
+        assert!(Error::DataMagic.is_data_error() && !Error::Param.is_data_error());
+    }
+}
@@ -1,12 +1,20 @@
 //! Raw low-level manipulations of bz streams.
 
+#[cfg(feature = "bytes")]
+use bytes::{Buf, BufMut};
+use std::cmp;
 use std::error;
+#[cfg(not(feature = "libbz2-rs-sys"))]
+use std::ffi::CStr;
 use std::fmt;
 use std::marker;
-use std::mem;
+use std::mem::{self, MaybeUninit};
+use std::os::raw::{c_int, c_uint};
 use std::slice;
-
-use libc::{c_int, c_uint};
+#[cfg(not(feature = "libbz2-rs-sys"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(feature = "libbz2-rs-sys"))]
+use std::sync::Once;
 
 use {ffi, Compression};
 
@@ -23,6 +31,11 @@ pub struct Compress {
 /// data.
 pub struct Decompress {
     inner: Stream<DirDecompress>,
+    // whether small mode was asked for, rather than fallen back to
+    small: bool,
+    small_fallback: bool,
+    // the stream header, to feed a stream started over in small mode
+    header: [u8; 4],
 }
 
 struct Stream<D: Direction> {
@@ -78,22 +91,53 @@ pub enum Status {
 /// Fatal errors encountered when compressing/decompressing bytes.
 ///
 /// These errors indicate that progress could not be made in any form due to
-/// input or output parameters.
+/// input or output parameters. There's one for each of the `BZ_*` error codes
+/// of libbz2, which `code` and `from_code` convert between, and more may be
+/// added in the future.
 #[derive(PartialEq, Eq, Copy, Debug, Clone)]
+#[non_exhaustive]
 pub enum Error {
     /// The sequence of operations called on a decompression/compression stream
-    /// were invalid. See methods for details.
+    /// were invalid. See methods for details (`BZ_SEQUENCE_ERROR`).
+    ///
+    /// The writers and encoders of this crate return this inside of an
+    /// `io::Error` too when data is written to them after they were finished.
     Sequence,
 
     /// The data being decompressed was invalid, or it was not a valid bz2
-    /// stream.
+    /// stream (`BZ_DATA_ERROR`).
     Data,
 
-    /// The magic bz2 header wasn't present when decompressing.
+    /// The magic bz2 header wasn't present when decompressing
+    /// (`BZ_DATA_ERROR_MAGIC`).
+    ///
+    /// This means the data isn't bzip2 at all rather than corrupt, so code
+    /// accepting several formats can fall back to another one. The decoders
+    /// in `read`, `bufread` and `write` return it inside of an `io::Error`,
+    /// which `Error::from_io` gets it back out of.
     DataMagic,
 
-    /// The parameters to this function were invalid.
+    /// The parameters to this function were invalid (`BZ_PARAM_ERROR`).
     Param,
+
+    /// The output was larger than the limit it was allowed to reach, or than
+    /// the buffer it was written to (`BZ_OUTBUFF_FULL`).
+    OutputLimit,
+
+    /// libbz2 couldn't allocate the memory it needs (`BZ_MEM_ERROR`).
+    Mem,
+
+    /// Reading or writing a `FILE` failed in the stdio functions of libbz2
+    /// (`BZ_IO_ERROR`).
+    Io,
+
+    /// The data ended before the end of the stream in the stdio functions of
+    /// libbz2 (`BZ_UNEXPECTED_EOF`).
+    UnexpectedEof,
+
+    /// libbz2 was built for a platform with other sizes of its integer types
+    /// than this one (`BZ_CONFIG_ERROR`).
+    Config,
 }
 
 impl Compress {
@@ -116,8 +160,15 @@ impl Compress {
     /// circumstances.
     ///
     /// Allowable values range from 0 to 250 inclusive. 0 is a special case,
-    /// equivalent to using the default value of 30.
+    /// equivalent to using the default value of 30. `WorkFactor` has presets
+    /// for it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the libbz2 linked against at runtime is older than 1.0.6,
+    /// every time it's called.
     pub fn new(lvl: Compression, work_factor: u32) -> Compress {
+        check_version();
         unsafe {
             let mut raw = Box::new(mem::zeroed());
             assert_eq!(
@@ -126,7 +177,7 @@ impl Compress {
             );
             Compress {
                 inner: Stream {
-                    raw: raw,
+                    raw,
                     _marker: marker::PhantomData,
                 },
             }
@@ -137,18 +188,36 @@ impl Compress {
     ///
     /// If anything other than BZ_OK is seen, `Err` is returned. The action
     /// given must be one of Run, Flush or Finish.
+    ///
+    /// libbz2 takes at most `c_uint::MAX` bytes for each of `input` and
+    /// `output`, and `Error::Param` is returned for anything longer rather
+    /// than silently truncating it. `compress_vec` and the encoders of this
+    /// crate split such buffers up instead.
     pub fn compress(
         &mut self,
         input: &[u8],
         output: &mut [u8],
         action: Action,
+    ) -> Result<Status, Error> {
+        self.compress_uninit(input, unsafe { as_uninit(output) }, action)
+    }
+
+    // Same as `compress`, with `output` possibly not initialized yet.
+    pub(crate) fn compress_uninit(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+        action: Action,
     ) -> Result<Status, Error> {
         // apparently 0-length compression requests which don't actually make
         // any progress are returned as BZ_PARAM_ERROR, which we don't want, to
         // just translate to a success here.
-        if input.len() == 0 && action == Action::Run {
+        if input.is_empty() && action == Action::Run {
             return Ok(Status::RunOk);
         }
+        if input.len() > MAX_LEN || output.len() > MAX_LEN {
+            return Err(Error::Param);
+        }
         self.inner.raw.next_in = input.as_ptr() as *mut _;
         self.inner.raw.avail_in = input.len() as c_uint;
         self.inner.raw.next_out = output.as_mut_ptr() as *mut _;
@@ -159,8 +228,7 @@ impl Compress {
                 ffi::BZ_FLUSH_OK => Ok(Status::FlushOk),
                 ffi::BZ_FINISH_OK => Ok(Status::FinishOk),
                 ffi::BZ_STREAM_END => Ok(Status::StreamEnd),
-                ffi::BZ_SEQUENCE_ERROR => Err(Error::Sequence),
-                c => panic!("unknown return status: {}", c),
+                c => Err(unexpected(c)),
             }
         }
     }
@@ -176,19 +244,47 @@ impl Compress {
         output: &mut Vec<u8>,
         action: Action,
     ) -> Result<Status, Error> {
-        let cap = output.capacity();
         let len = output.len();
 
-        unsafe {
-            let before = self.total_out();
-            let ret = {
-                let ptr = output.as_mut_ptr().offset(len as isize);
-                let out = slice::from_raw_parts_mut(ptr, cap - len);
-                self.compress(input, out, action)
-            };
-            output.set_len((self.total_out() - before) as usize + len);
-            return ret;
-        }
+        let (input, action) = limit_input(input, action);
+        let before = self.total_out();
+        let ret = self.compress_uninit(input, limit_output(output.spare_capacity_mut()), action);
+        unsafe { output.set_len((self.total_out() - before) as usize + len) };
+        ret
+    }
+
+    /// Compress the current chunk of `input` into the current chunk of
+    /// `output`, advancing both past the bytes consumed and produced.
+    ///
+    /// Like `compress_vec` this makes a single call into libbz2, so it has to
+    /// be called again while there's input left which is split into several
+    /// chunks, or while `output` fills up. With `Action::Finish`, the stream
+    /// is only finished once `input` has no chunks left after the current
+    /// one.
+    #[cfg(feature = "bytes")]
+    pub fn compress_buf<B, M>(
+        &mut self,
+        input: &mut B,
+        output: &mut M,
+        action: Action,
+    ) -> Result<Status, Error>
+    where
+        B: Buf,
+        M: BufMut,
+    {
+        let (before_in, before_out) = (self.total_in(), self.total_out());
+        let last = input.chunk().len() == input.remaining();
+        let action = if action == Action::Finish && !last {
+            Action::Run
+        } else {
+            action
+        };
+        let (chunk, action) = limit_input(input.chunk(), action);
+        let out = unsafe { uninit_chunk(output) };
+        let ret = self.compress_uninit(chunk, limit_output(out), action);
+        input.advance((self.total_in() - before_in) as usize);
+        unsafe { output.advance_mut((self.total_out() - before_out) as usize) };
+        ret
     }
 
     /// Total number of bytes processed as input
@@ -209,21 +305,91 @@ impl Decompress {
     /// decompression algorithm which uses less memory but at the cost of
     /// decompressing more slowly (roughly speaking, half the speed, but the
     /// maximum memory requirement drops to around 2300k). See
+    ///
+    /// # Panics
+    ///
+    /// Panics if the libbz2 linked against at runtime is older than 1.0.6,
+    /// every time it's called.
     pub fn new(small: bool) -> Decompress {
+        check_version();
         unsafe {
             let mut raw = Box::new(mem::zeroed());
             assert_eq!(ffi::BZ2_bzDecompressInit(&mut *raw, 0, small as c_int), 0);
             Decompress {
                 inner: Stream {
-                    raw: raw,
+                    raw,
                     _marker: marker::PhantomData,
                 },
+                small,
+                small_fallback: false,
+                header: [0; 4],
             }
         }
     }
 
+    /// Configures whether a stream which isn't decompressed in small mode
+    /// switches to it when there isn't enough memory otherwise.
+    ///
+    /// libbz2 allocates the memory for the blocks of a stream once it has
+    /// read the header at its start, which fails with `Status::MemNeeded`
+    /// when that much memory can't be had. With the fallback enabled the
+    /// stream is started over in small mode instead, which needs less than
+    /// two thirds of the memory, and only surfaces the error if that fails
+    /// as well. It's disabled by default.
+    pub fn set_small_fallback(&mut self, fallback: bool) {
+        self.small_fallback = fallback;
+    }
+
     /// Decompress a block of input into a block of output.
+    ///
+    /// As with `Compress::compress`, `Error::Param` is returned if `input` or
+    /// `output` is longer than the `c_uint::MAX` bytes libbz2 takes.
     pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<Status, Error> {
+        self.decompress_uninit(input, unsafe { as_uninit(output) })
+    }
+
+    // Same as `decompress`, with `output` possibly not initialized yet.
+    pub(crate) fn decompress_uninit(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+    ) -> Result<Status, Error> {
+        if input.len() > MAX_LEN || output.len() > MAX_LEN {
+            return Err(Error::Param);
+        }
+        let before = self.total_in() as usize;
+        let ret = self.run(input, output);
+        let header = self.header.len();
+        if before < header {
+            let end = cmp::min(self.total_in() as usize, header);
+            self.header[before..end].copy_from_slice(&input[..end - before]);
+        }
+        // the header is the only thing read before the blocks are allocated
+        if ret == Ok(Status::MemNeeded)
+            && self.small_fallback
+            && !self.small
+            && self.total_in() as usize == header
+        {
+            let mut small = Decompress::new(true);
+            let header = self.header;
+            if small.run(&header, &mut [])? != Status::Ok || small.total_in() != 4 {
+                return ret;
+            }
+            self.inner = small.inner;
+            return self.run(&input[header.len() - before..], output);
+        }
+        ret
+    }
+
+    /// Returns a new stream for the next member of a multistream, set up like
+    /// this one.
+    pub(crate) fn restart(&self) -> Decompress {
+        let mut d = Decompress::new(self.small);
+        d.small_fallback = self.small_fallback;
+        d
+    }
+
+    fn run(&mut self, input: &[u8], output: &mut [MaybeUninit<u8>]) -> Result<Status, Error> {
         self.inner.raw.next_in = input.as_ptr() as *mut _;
         self.inner.raw.avail_in = input.len() as c_uint;
         self.inner.raw.next_out = output.as_mut_ptr() as *mut _;
@@ -233,11 +399,7 @@ impl Decompress {
                 ffi::BZ_OK => Ok(Status::Ok),
                 ffi::BZ_MEM_ERROR => Ok(Status::MemNeeded),
                 ffi::BZ_STREAM_END => Ok(Status::StreamEnd),
-                ffi::BZ_PARAM_ERROR => Err(Error::Param),
-                ffi::BZ_DATA_ERROR => Err(Error::Data),
-                ffi::BZ_DATA_ERROR_MAGIC => Err(Error::DataMagic),
-                ffi::BZ_SEQUENCE_ERROR => Err(Error::Sequence),
-                c => panic!("wut: {}", c),
+                c => Err(unexpected(c)),
             }
         }
     }
@@ -248,19 +410,33 @@ impl Decompress {
     /// its current length up to its capacity. The length of the vector will be
     /// adjusted appropriately.
     pub fn decompress_vec(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<Status, Error> {
-        let cap = output.capacity();
         let len = output.len();
 
-        unsafe {
-            let before = self.total_out();
-            let ret = {
-                let ptr = output.as_mut_ptr().offset(len as isize);
-                let out = slice::from_raw_parts_mut(ptr, cap - len);
-                self.decompress(input, out)
-            };
-            output.set_len((self.total_out() - before) as usize + len);
-            return ret;
-        }
+        let before = self.total_out();
+        let input = limit_input(input, Action::Run).0;
+        let ret = self.decompress_uninit(input, limit_output(output.spare_capacity_mut()));
+        unsafe { output.set_len((self.total_out() - before) as usize + len) };
+        ret
+    }
+
+    /// Decompress the current chunk of `input` into the current chunk of
+    /// `output`, advancing both past the bytes consumed and produced.
+    ///
+    /// Like `decompress_vec` this makes a single call into libbz2, so it has
+    /// to be called again until the end of the stream is reached.
+    #[cfg(feature = "bytes")]
+    pub fn decompress_buf<B, M>(&mut self, input: &mut B, output: &mut M) -> Result<Status, Error>
+    where
+        B: Buf,
+        M: BufMut,
+    {
+        let (before_in, before_out) = (self.total_in(), self.total_out());
+        let out = unsafe { uninit_chunk(output) };
+        let chunk = limit_input(input.chunk(), Action::Run).0;
+        let ret = self.decompress_uninit(chunk, limit_output(out));
+        input.advance((self.total_in() - before_in) as usize);
+        unsafe { output.advance_mut((self.total_out() - before_out) as usize) };
+        ret
     }
 
     /// Total number of bytes processed as input
@@ -274,6 +450,46 @@ impl Decompress {
     }
 }
 
+/// Panics if the libbz2 linked against is older than 1.0.6, whose
+/// predecessors have known bugs and vulnerabilities.
+///
+/// The build script already refuses such a system library when it's found
+/// through pkg-config, but it may still be picked up at runtime.
+#[cfg(not(feature = "libbz2-rs-sys"))]
+fn check_version() {
+    static CHECK: Once = Once::new();
+    static TOO_OLD: AtomicBool = AtomicBool::new(false);
+    // panicking inside of `call_once` would poison it, and have every later
+    // caller panic about that instead
+    CHECK.call_once(|| {
+        let version = version();
+        let mut parts = version
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|p| p.parse::<u32>());
+        if let (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) =
+            (parts.next(), parts.next(), parts.next())
+        {
+            TOO_OLD.store((major, minor, patch) < (1, 0, 6), Ordering::Relaxed);
+        }
+    });
+    if TOO_OLD.load(Ordering::Relaxed) {
+        panic!(
+            "libbz2 {} is too old, at least version 1.0.6 is required",
+            version()
+        );
+    }
+}
+
+#[cfg(not(feature = "libbz2-rs-sys"))]
+fn version() -> String {
+    let version = unsafe { CStr::from_ptr(ffi::BZ2_bzlibVersion()) };
+    version.to_string_lossy().into_owned()
+}
+
+// libbz2-rs-sys is always recent enough
+#[cfg(feature = "libbz2-rs-sys")]
+fn check_version() {}
+
 impl<D: Direction> Stream<D> {
     fn total_in(&self) -> u64 {
         (self.raw.total_in_lo32 as u64) | ((self.raw.total_in_hi32 as u64) << 32)
@@ -284,23 +500,194 @@ impl<D: Direction> Stream<D> {
     }
 }
 
+impl Error {
+    /// Returns the error for a `BZ_*` error code returned by libbz2, or
+    /// `None` if `code` isn't one of them.
+    pub fn from_code(code: c_int) -> Option<Error> {
+        Some(match code {
+            ffi::BZ_SEQUENCE_ERROR => Error::Sequence,
+            ffi::BZ_PARAM_ERROR => Error::Param,
+            ffi::BZ_MEM_ERROR => Error::Mem,
+            ffi::BZ_DATA_ERROR => Error::Data,
+            ffi::BZ_DATA_ERROR_MAGIC => Error::DataMagic,
+            ffi::BZ_IO_ERROR => Error::Io,
+            ffi::BZ_UNEXPECTED_EOF => Error::UnexpectedEof,
+            ffi::BZ_OUTBUFF_FULL => Error::OutputLimit,
+            ffi::BZ_CONFIG_ERROR => Error::Config,
+            _ => return None,
+        })
+    }
+
+    /// Returns the `BZ_*` error code of libbz2 for this error.
+    pub fn code(&self) -> c_int {
+        match self {
+            Error::Sequence => ffi::BZ_SEQUENCE_ERROR,
+            Error::Param => ffi::BZ_PARAM_ERROR,
+            Error::Mem => ffi::BZ_MEM_ERROR,
+            Error::Data => ffi::BZ_DATA_ERROR,
+            Error::DataMagic => ffi::BZ_DATA_ERROR_MAGIC,
+            Error::Io => ffi::BZ_IO_ERROR,
+            Error::UnexpectedEof => ffi::BZ_UNEXPECTED_EOF,
+            Error::OutputLimit => ffi::BZ_OUTBUFF_FULL,
+            Error::Config => ffi::BZ_CONFIG_ERROR,
+        }
+    }
+
+    /// Returns the error wrapped by an `io::Error` returned from one of the
+    /// decoders of this crate, or `None` if it didn't come from libbz2.
+    ///
+    /// ```
+    /// use bzip2::read::BzDecoder;
+    /// use bzip2::Error;
+    /// use std::io::Read;
+    ///
+    /// let err = BzDecoder::new(&b"\x1f\x8b gzip"[..])
+    ///     .read_to_end(&mut Vec::new())
+    ///     .unwrap_err();
+    /// assert_eq!(Error::from_io(&err), Some(Error::DataMagic));
+    /// ```
+    pub fn from_io(err: &std::io::Error) -> Option<Error> {
+        err.get_ref()?.downcast_ref::<Error>().copied()
+    }
+
+    /// Returns whether this error means that the data being decompressed is
+    /// corrupt or isn't bzip2 data at all.
+    pub fn is_data_error(&self) -> bool {
+        matches!(self, Error::Data | Error::DataMagic | Error::UnexpectedEof)
+    }
+}
+
+// The most libbz2 takes for either buffer in a single call.
+const MAX_LEN: usize = c_uint::MAX as usize;
+
+// Cuts `input` down to what libbz2 takes in one call, only asking for a flush
+// or the end of the stream once the last of it is handed over.
+pub(crate) fn limit_input(input: &[u8], action: Action) -> (&[u8], Action) {
+    if input.len() > MAX_LEN {
+        (&input[..MAX_LEN], Action::Run)
+    } else {
+        (input, action)
+    }
+}
+
+// Cuts `output` down to what libbz2 fills in one call.
+pub(crate) fn limit_output<T>(output: &mut [T]) -> &mut [T] {
+    let len = output.len().min(MAX_LEN);
+    &mut output[..len]
+}
+
+// `output` as memory for libbz2 to write into, which is all that's done with
+// the `*_uninit` methods' output.
+//
+// Nothing uninitialized may be written through the result, as the bytes are
+// still read as `u8` afterwards.
+pub(crate) unsafe fn as_uninit(output: &mut [u8]) -> &mut [MaybeUninit<u8>] {
+    slice::from_raw_parts_mut(output.as_mut_ptr() as *mut MaybeUninit<u8>, output.len())
+}
+
+// The current chunk of `output`, which isn't initialized, for libbz2 to write
+// into without the memory having to be zeroed first.
+//
+// `advance_mut` must only be called for the bytes written into it.
+#[cfg(feature = "bytes")]
+pub(crate) unsafe fn uninit_chunk<M: BufMut>(output: &mut M) -> &mut [MaybeUninit<u8>] {
+    let out = output.chunk_mut();
+    slice::from_raw_parts_mut(out.as_mut_ptr() as *mut MaybeUninit<u8>, out.len())
+}
+
+// Any other code is returned as an error too rather than panicking, so that no
+// input can bring down the process. Codes which aren't `BZ_*` errors at all
+// mean the stream wasn't used the way libbz2 expects.
+fn unexpected(code: c_int) -> Error {
+    Error::from_code(code).unwrap_or(Error::Sequence)
+}
+
 impl error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let description = match self {
-            Error::Sequence => "bzip2: sequence of operations invalid",
-            Error::Data => "bzip2: invalid data",
-            Error::DataMagic => "bzip2: bz2 header missing",
-            Error::Param => "bzip2: invalid parameters",
+            Error::Sequence => {
+                "bzip2: operations called out of sequence, e.g. compressing more \
+                 data after finishing the stream"
+            }
+            Error::Data => {
+                "bzip2: corrupt compressed data, a block failed its CRC or structural \
+                 checks"
+            }
+            Error::DataMagic => "bzip2: not bzip2 data, the stream is missing the BZh magic",
+            Error::Param => "bzip2: invalid parameters, such as a block size outside of 1-9",
+            Error::OutputLimit => "bzip2: output doesn't fit in the limit or buffer given",
+            Error::Mem => "bzip2: not enough memory for the buffers of libbz2",
+            Error::Io => "bzip2: reading or writing the underlying file failed",
+            Error::UnexpectedEof => "bzip2: compressed data ended in the middle of a stream",
+            Error::Config => {
+                "bzip2: libbz2 was built for different integer sizes than this platform"
+            }
         };
         f.write_str(description)
     }
 }
 
+/// Corrupt data maps to `InvalidData`, and misuse of the API such as invalid
+/// parameters to `InvalidInput`.
 impl From<Error> for std::io::Error {
     fn from(data: Error) -> std::io::Error {
-        std::io::Error::new(std::io::ErrorKind::Other, data)
+        let kind = match data {
+            Error::Data | Error::DataMagic | Error::OutputLimit => std::io::ErrorKind::InvalidData,
+            Error::Sequence | Error::Param => std::io::ErrorKind::InvalidInput,
+            Error::UnexpectedEof => std::io::ErrorKind::UnexpectedEof,
+            Error::Mem => std::io::ErrorKind::OutOfMemory,
+            Error::Io | Error::Config => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, data)
+    }
+}
+
+impl Status {
+    /// Returns whether this is `Status::StreamEnd`, after which no more data
+    /// is taken by the stream.
+    pub fn is_stream_end(&self) -> bool {
+        *self == Status::StreamEnd
+    }
+
+    /// Returns whether the operation went fine, which is all statuses but
+    /// `Status::MemNeeded`.
+    pub fn is_ok(&self) -> bool {
+        *self != Status::MemNeeded
+    }
+
+    /// Turns `Status::MemNeeded` into `Error::Mem`, so that a status can be
+    /// passed on with `?` along with the errors of the same call.
+    ///
+    /// ```
+    /// use bzip2::{Decompress, Error, Status};
+    ///
+    /// fn step(d: &mut Decompress, input: &[u8], output: &mut [u8]) -> Result<bool, Error> {
+    ///     let status = d.decompress(input, output)?.into_result()?;
+    ///     Ok(status.is_stream_end())
+    /// }
+    /// # assert_eq!(step(&mut Decompress::new(false), b"BZh9", &mut [0; 10]), Ok(false));
+    /// ```
+    pub fn into_result(self) -> Result<Status, Error> {
+        match self {
+            Status::MemNeeded => Err(Error::Mem),
+            status => Ok(status),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            Status::Ok => "bzip2: ok",
+            Status::FlushOk => "bzip2: flushing the compression stream",
+            Status::RunOk => "bzip2: compressing",
+            Status::FinishOk => "bzip2: finishing the compression stream",
+            Status::StreamEnd => "bzip2: end of the stream reached",
+            Status::MemNeeded => "bzip2: not enough memory for the buffers of libbz2",
+        };
+        f.write_str(description)
     }
 }
 
@@ -322,3 +709,144 @@ impl<D: Direction> Drop for Stream<D> {
         }
     }
 }
+
+#[cfg(all(test, feature = "bytes"))]
+mod tests {
+    use bytes::{Buf, BufMut, BytesMut};
+
+    use {Action, Compress, Compression, Decompress, Status};
+
+    #[test]
+    fn bufs() {
+        let data = (0..50_000u32).map(|i| (i % 17) as u8).collect::<Vec<_>>();
+        // input split into two chunks, and output growing as needed
+        let mut input = (&data[..20_000]).chain(&data[20_000..]);
+        let mut compressed = BytesMut::new();
+        let mut c = Compress::new(Compression::fast(), 30);
+        while c
+            .compress_buf(&mut input, &mut compressed, Action::Finish)
+            .unwrap()
+            != Status::StreamEnd
+        {}
+        assert!(!input.has_remaining());
+        assert!(compressed[..] == ::compress(&data, Compression::fast())[..]);
+
+        // output into a fixed slice, a bit at a time
+        let mut input = compressed.freeze();
+        let mut buf = vec![0; data.len()];
+        let mut output = &mut buf[..];
+        let mut d = Decompress::new(false);
+        loop {
+            let mut limited = (&mut output).limit(1000);
+            if d.decompress_buf(&mut input, &mut limited).unwrap() == Status::StreamEnd {
+                break;
+            }
+        }
+        assert_eq!(output.remaining_mut(), 0);
+        assert!(buf == data);
+    }
+}
+
+#[cfg(all(test, feature = "fast-crc", not(feature = "libbz2-rs-sys")))]
+mod fast_crc {
+    use rand::{thread_rng, Rng};
+
+    use ffi;
+    use {Compression, Decompress, Error};
+
+    // the CRC of bzip2, one bit at a time
+    fn crc(mut crc: u32, data: &[u8]) -> u32 {
+        for &byte in data {
+            crc ^= u32::from(byte) << 24;
+            for _ in 0..8 {
+                crc = (crc << 1)
+                    ^ if crc & 0x8000_0000 != 0 {
+                        0x04c1_1db7
+                    } else {
+                        0
+                    };
+            }
+        }
+        crc
+    }
+
+    #[test]
+    fn matches_table() {
+        let mut rng = thread_rng();
+        let data = (0..4096).map(|_| rng.gen()).collect::<Vec<u8>>();
+        let lens = (0..20)
+            .map(|_| rng.gen_range(600..4000))
+            .collect::<Vec<_>>();
+        for len in (0..600).chain(lens) {
+            let start = rng.gen_range(0..16);
+            let init = rng.gen();
+            let data = &data[start..start + len];
+            let fast = unsafe { ffi::BZ2_crc32Update(init, data.as_ptr(), len as _) };
+            assert_eq!(fast, crc(init, data), "{} bytes at {}", len, start);
+        }
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let data = (0..300_000u64)
+            .map(|i| (i * i % 253) as u8)
+            .collect::<Vec<_>>();
+        let compressed = ::compress(&data, Compression::fast());
+        assert!(::decompress(&compressed).unwrap() == data);
+
+        // the CRC of the first block follows the stream and block headers
+        let mut corrupt = compressed.clone();
+        corrupt[12] ^= 1;
+        for &small in &[false, true] {
+            let mut out = vec![0; data.len()];
+            let res = Decompress::new(small).decompress(&corrupt, &mut out);
+            assert_eq!(res.unwrap_err(), Error::Data);
+        }
+    }
+}
+
+#[cfg(all(test, not(any(feature = "libbz2-rs-sys", feature = "freestanding"))))]
+mod small_fallback {
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+
+    use {Compression, Decompress, Status};
+
+    // fails the allocation of the blocks outside of small mode, which is
+    // 3.6M at level 9, while libbz2 frees with `free` as usual
+    extern "C" fn alloc(_: *mut c_void, items: c_int, size: c_int) -> *mut c_void {
+        let len = items as usize * size as usize;
+        if len > 3_000_000 {
+            return ptr::null_mut();
+        }
+        unsafe { libc::malloc(len) }
+    }
+
+    fn starved() -> Decompress {
+        let mut d = Decompress::new(false);
+        d.inner.raw.bzalloc = Some(alloc);
+        d
+    }
+
+    #[test]
+    fn falls_back() {
+        let data = (0..700_000u32).map(|i| (i % 239) as u8).collect::<Vec<_>>();
+        let compressed = ::compress(&data, Compression::best());
+        let mut out = vec![0; data.len()];
+        assert_eq!(
+            starved().decompress(&compressed, &mut out),
+            Ok(Status::MemNeeded)
+        );
+
+        // with the header split over several calls too
+        for &split in &[0, 2, 4, 10] {
+            let mut d = starved();
+            d.set_small_fallback(true);
+            assert_eq!(d.decompress(&compressed[..split], &mut out), Ok(Status::Ok));
+            let status = d.decompress(&compressed[split..], &mut out).unwrap();
+            assert_eq!(status, Status::StreamEnd);
+            assert_eq!(d.total_in(), compressed.len() as u64);
+            assert!(out == data);
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//! Compression and decompression passing owned buffers back and forth.
+//!
+//! Completion-based runtimes such as tokio-uring or monoio take ownership of
+//! buffers while an operation is in flight and hand them back once it's
+//! done, so nothing may be borrowed across an await point. The encoder and
+//! decoder in here fit that model: data read by the runtime is handed over
+//! with `feed`, which gives back an emptied buffer to read into next, and
+//! coded data is taken out with `read_owned`, which appends to a buffer
+//! that can be passed straight to the runtime's write.
+//!
+//! Neither of them does any I/O, so the same code works with any runtime.
+//!
+//! # Example
+//!
+//! ```
+//! use bzip2::owned::{Decoder, Encoder};
+//! use bzip2::Compression;
+//!
+//! // stands in for the owned-buffer read of a runtime
+//! fn read(src: &mut &[u8], mut buf: Vec<u8>) -> (std::io::Result<usize>, Vec<u8>) {
+//!     let n = src.len().min(buf.capacity() - buf.len()).min(5);
+//!     buf.extend_from_slice(&src[..n]);
+//!     *src = &src[n..];
+//!     (Ok(n), buf)
+//! }
+//!
+//! let mut src = &b"Hello, World!"[..];
+//! let mut e = Encoder::new(Compression::default());
+//! let mut input = Vec::with_capacity(1024);
+//! let mut compressed = Vec::new();
+//! while !e.is_done() {
+//!     if e.needs_input() {
+//!         let (res, buf) = read(&mut src, input);
+//!         res.unwrap();
+//!         // an empty buffer ends the input
+//!         input = e.feed(buf);
+//!     }
+//!     let (res, buf) = e.read_owned(compressed);
+//!     res.unwrap();
+//!     compressed = buf;
+//! }
+//!
+//! let mut d = Decoder::new();
+//! d.feed(compressed);
+//! let (res, out) = d.read_owned(Vec::with_capacity(64));
+//! assert_eq!(res.unwrap(), 13);
+//! assert_eq!(out, b"Hello, World!");
+//! assert!(d.is_done());
+//! ```
+
+use std::io;
+use std::mem::{self, MaybeUninit};
+
+use coder::{self, Coder};
+use Compression;
+
+const CHUNK: usize = 32 * 1024;
+
+/// Feeds owned input buffers into a `Coder`.
+struct Owned<C> {
+    coder: C,
+    input: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<C: Coder> Owned<C> {
+    fn new(coder: C) -> Owned<C> {
+        Owned {
+            coder,
+            input: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.eof && self.pos == self.input.len() && !self.coder.done()
+    }
+
+    fn feed(&mut self, buf: Vec<u8>) -> Vec<u8> {
+        assert!(
+            self.needs_input(),
+            "input fed before the previous one was used"
+        );
+        if buf.is_empty() {
+            self.eof = true;
+        }
+        self.pos = 0;
+        let mut old = mem::replace(&mut self.input, buf);
+        old.clear();
+        old.reserve(CHUNK);
+        old
+    }
+
+    fn read_owned(&mut self, mut buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        let len = buf.len();
+        if buf.capacity() == len {
+            buf.reserve(CHUNK);
+        }
+        // the coders only ever write to their output, so it isn't zeroed
+        let res = self.code(buf.spare_capacity_mut());
+        unsafe { buf.set_len(len + *res.as_ref().unwrap_or(&0)) };
+        (res, buf)
+    }
+
+    fn code(&mut self, output: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        let mut produced = 0;
+        while produced < output.len() && !self.coder.done() {
+            if self.pos < self.input.len() {
+                let input = &self.input[self.pos..];
+                let (c, p) = self.coder.run(input, &mut output[produced..])?;
+                self.pos += c;
+                produced += p;
+                if c == 0 && p == 0 {
+                    // the end of a single stream, the rest is left over
+                    break;
+                }
+            } else if self.eof {
+                let (p, done) = self.coder.finish(&mut output[produced..])?;
+                produced += p;
+                if done {
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+        Ok(produced)
+    }
+}
+
+/// A bzip2 compressor taking and handing out owned buffers.
+pub struct Encoder {
+    inner: Owned<coder::Encoder>,
+}
+
+impl Encoder {
+    /// Creates a new encoder compressing at the given level.
+    pub fn new(level: Compression) -> Encoder {
+        Encoder {
+            inner: Owned::new(coder::Encoder::new(level)),
+        }
+    }
+
+    /// Returns whether all input fed so far has been compressed and more can
+    /// be fed.
+    pub fn needs_input(&self) -> bool {
+        self.inner.needs_input()
+    }
+
+    /// Hands over the data in `buf` to be compressed, returning the buffer
+    /// fed before, cleared so that the next input can be read into its
+    /// spare capacity.
+    ///
+    /// An empty `buf` ends the input, the end of the stream is then written
+    /// by `read_owned`.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `needs_input` returns true.
+    pub fn feed(&mut self, buf: Vec<u8>) -> Vec<u8> {
+        self.inner.feed(buf)
+    }
+
+    /// Compresses the input fed so far, appending to `buf` and returning it
+    /// along with the number of bytes appended.
+    ///
+    /// At most the spare capacity of `buf` is filled, if it has none some
+    /// is reserved first. Compressed data is only produced once a block has
+    /// been collected, so this usually returns 0 until more input is needed
+    /// or the input has ended.
+    pub fn read_owned(&mut self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        self.inner.read_owned(buf)
+    }
+
+    /// Returns whether the end of the stream has been handed out.
+    pub fn is_done(&self) -> bool {
+        self.inner.coder.done()
+    }
+
+    /// Returns the number of bytes consumed by the compressor
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes produced by the compressor
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+/// A bzip2 decompressor taking and handing out owned buffers.
+pub struct Decoder {
+    inner: Owned<coder::Decoder>,
+}
+
+impl Decoder {
+    /// Creates a new decoder decompressing a single bzip2 stream.
+    pub fn new() -> Decoder {
+        Decoder {
+            inner: Owned::new(coder::Decoder::new(false)),
+        }
+    }
+
+    /// Creates a new decoder decompressing all members of a multistream.
+    pub fn new_multi() -> Decoder {
+        Decoder {
+            inner: Owned::new(coder::Decoder::new(true)),
+        }
+    }
+
+    /// Returns whether all input fed so far has been decompressed and more
+    /// can be fed.
+    pub fn needs_input(&self) -> bool {
+        self.inner.needs_input()
+    }
+
+    /// Hands over the data in `buf` to be decompressed, returning the buffer
+    /// fed before, cleared so that the next input can be read into its
+    /// spare capacity.
+    ///
+    /// An empty `buf` ends the input, after which `read_owned` returns an
+    /// error if the stream isn't complete.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `needs_input` returns true.
+    pub fn feed(&mut self, buf: Vec<u8>) -> Vec<u8> {
+        self.inner.feed(buf)
+    }
+
+    /// Decompresses the input fed so far, appending to `buf` and returning
+    /// it along with the number of bytes appended.
+    ///
+    /// At most the spare capacity of `buf` is filled, if it has none some
+    /// is reserved first. Returning 0 means that either more input is
+    /// needed or the end of the stream has been reached.
+    pub fn read_owned(&mut self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        self.inner.read_owned(buf)
+    }
+
+    /// Returns whether the end of the stream has been reached.
+    ///
+    /// This is never the case for multistreams, whose end is only known
+    /// once the input has ended.
+    pub fn is_done(&self) -> bool {
+        self.inner.coder.done()
+    }
+
+    /// Returns the input fed after the end of a single stream.
+    pub fn remaining(&self) -> &[u8] {
+        &self.inner.input[self.inner.pos..]
+    }
+
+    /// Returns the number of bytes that the decompressor has consumed.
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes that the decompressor has produced.
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use rand::{thread_rng, Rng};
+
+    use super::{Decoder, Encoder};
+    use Compression;
+
+    #[test]
+    fn roundtrip() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        let mut e = Encoder::new(Compression::default());
+        let mut chunks = data.chunks(1000);
+        let mut input = Vec::with_capacity(1000);
+        let mut compressed = Vec::new();
+        while !e.is_done() {
+            if e.needs_input() {
+                input.extend_from_slice(chunks.next().unwrap_or(&[]));
+                input = e.feed(input);
+            }
+            // hand out small buffers, like a queue of in-flight writes
+            let (res, buf) = e.read_owned(Vec::with_capacity(100));
+            res.unwrap();
+            compressed.extend_from_slice(&buf);
+        }
+        assert_eq!(e.total_in(), data.len() as u64);
+        assert_eq!(e.total_out(), compressed.len() as u64);
+
+        let mut expected = Vec::new();
+        ::read::BzDecoder::new(&compressed[..])
+            .read_to_end(&mut expected)
+            .unwrap();
+        assert!(expected == data);
+
+        let two = [&compressed[..], &compressed[..]].concat();
+        for &multi in &[false, true] {
+            let mut d = if multi {
+                Decoder::new_multi()
+            } else {
+                Decoder::new()
+            };
+            let mut chunks = two.chunks(777);
+            let mut out = Vec::new();
+            loop {
+                if d.needs_input() {
+                    d.feed(chunks.next().map_or(Vec::new(), |c| c.to_vec()));
+                }
+                let (res, buf) = d.read_owned(out);
+                out = buf;
+                if res.unwrap() == 0 && !d.needs_input() {
+                    break;
+                }
+            }
+            if multi {
+                assert!(out == [&data[..], &data[..]].concat());
+            } else {
+                assert!(d.is_done());
+                assert!(out == data);
+                let rest = d.remaining().len() + chunks.map(|c| c.len()).sum::<usize>();
+                assert_eq!(rest, compressed.len());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let mut e = Encoder::new(Compression::default());
+        e.feed(b"hello".to_vec());
+        let (_, buf) = e.read_owned(Vec::new());
+        e.feed(Vec::new());
+        let (res, compressed) = e.read_owned(buf);
+        res.unwrap();
+
+        let mut d = Decoder::new();
+        d.feed(compressed[..compressed.len() - 1].to_vec());
+        d.read_owned(Vec::new()).0.unwrap();
+        d.feed(Vec::new());
+        let err = d.read_owned(Vec::new()).0.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,507 @@
+//! Worker threads and helpers shared by the parallel encoders.
+
+use std::collections::VecDeque;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+#[cfg(any(feature = "futures-io", feature = "tokio1"))]
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use {Compression, Decompress, Error, Status};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A set of worker threads which can be shared by any number of parallel
+/// encoders and decoders.
+///
+/// Each of the parallel types spawns threads of its own when created with
+/// `new`. Creating them with `with_thread_pool` instead runs their work on an
+/// existing pool, so that an application compressing many streams at once
+/// doesn't end up with a set of threads per stream. Cloning a pool is cheap
+/// and shares its threads, which exit once the last clone is dropped.
+#[derive(Clone)]
+pub struct ThreadPool {
+    pool: Arc<Pool>,
+}
+
+// Where the jobs of the parallel encoders run.
+struct Pool {
+    backend: Backend,
+}
+
+enum Backend {
+    /// A fixed set of worker threads which run jobs in submission order.
+    Threads {
+        tx: Option<Sender<Job>>,
+        workers: Vec<JoinHandle<()>>,
+    },
+    /// The global rayon thread pool.
+    #[cfg(feature = "rayon")]
+    Rayon,
+    /// Jobs run on the thread submitting them, where there are no threads.
+    Inline,
+}
+
+/// The eventual result of a job submitted with `ThreadPool::spawn`.
+pub struct Pending<T> {
+    rx: Receiver<T>,
+    res: Option<T>,
+    // woken once the job is done, for async callers
+    #[cfg_attr(not(any(feature = "futures-io", feature = "tokio1")), allow(dead_code))]
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+/// Limits on the memory and threads used by the parallel encoders and
+/// decoders, such as `read::ParBzEncoder` and `read::ParBzDecoder`.
+///
+/// Each of them keeps a queue of chunks being worked on, and holds both the
+/// input and the output of every chunk in the queue in memory. A
+/// configuration bounds the length of that queue and the size of each chunk,
+/// and so the memory used.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ParConfig {
+    max_in_flight: Option<usize>,
+    chunk_size: Option<usize>,
+    backpressure: Backpressure,
+}
+
+/// What the parallel encoders and decoders do when they have to wait for a
+/// worker thread.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum Backpressure {
+    /// Block until the worker is done. This is the default.
+    #[default]
+    Block,
+    /// Return an error of kind `WouldBlock` instead, leaving it to the caller
+    /// to try again later.
+    ///
+    /// Note that the writers also finish their stream when dropped, which
+    /// blocks regardless, so they should be finished with `try_finish`
+    /// before being dropped.
+    WouldBlock,
+}
+
+impl ParConfig {
+    /// Creates the default configuration, see the individual settings.
+    pub fn new() -> ParConfig {
+        ParConfig::default()
+    }
+
+    /// Sets the maximum number of chunks being worked on at once.
+    ///
+    /// Defaults to twice the number of threads, so that every thread has the
+    /// next chunk queued up when it's done with the current one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn max_in_flight(mut self, n: usize) -> ParConfig {
+        assert!(n > 0, "maximum chunks in flight must be non-zero");
+        self.max_in_flight = Some(n);
+        self
+    }
+
+    /// Sets the size of each chunk in bytes.
+    ///
+    /// For encoders this is the amount of uncompressed data compressed into
+    /// each member, which defaults to one block of the compression level. For
+    /// decoders it is the amount of compressed data read from the underlying
+    /// reader at once, which defaults to the chunk size of `BufferSizes`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn chunk_size(mut self, size: usize) -> ParConfig {
+        assert!(size > 0, "chunk size must be non-zero");
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// Sets what to do when a worker thread has to be waited for.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> ParConfig {
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub(crate) fn max_in_flight_for(&self, pool: &ThreadPool) -> usize {
+        self.max_in_flight.unwrap_or(pool.threads() * 2)
+    }
+
+    pub(crate) fn chunk_size_or(&self, default: usize) -> usize {
+        self.chunk_size.unwrap_or(default)
+    }
+
+    pub(crate) fn backpressure_mode(&self) -> Backpressure {
+        self.backpressure
+    }
+}
+
+impl ThreadPool {
+    /// Spawns a pool of `threads` worker threads, or as many as
+    /// `available_threads` returns if `threads` is 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the threads can't be spawned. On targets without any
+    /// threads, such as WASI, jobs run on the thread submitting them instead.
+    pub fn new(threads: usize) -> ThreadPool {
+        ThreadPool::with_name(threads, "bzip2")
+    }
+
+    /// Same as `new`, but the threads are named `name` followed by a number,
+    /// for example `bzip2-0`, which shows up in debuggers and panic messages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the threads can't be spawned.
+    pub fn with_name(threads: usize, name: &str) -> ThreadPool {
+        ThreadPool {
+            pool: Arc::new(Pool::new(threads, name)),
+        }
+    }
+
+    /// Runs jobs on the global rayon thread pool instead of threads of its
+    /// own.
+    #[cfg(feature = "rayon")]
+    pub fn rayon() -> ThreadPool {
+        ThreadPool {
+            pool: Arc::new(Pool::rayon()),
+        }
+    }
+
+    /// Returns the number of threads jobs are run on.
+    pub fn threads(&self) -> usize {
+        self.pool.threads()
+    }
+
+    pub(crate) fn spawn<T, F>(&self, f: F) -> Pending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.pool.spawn(f)
+    }
+}
+
+impl Default for ThreadPool {
+    /// Spawns as many threads as `available_threads` returns.
+    fn default() -> ThreadPool {
+        ThreadPool::new(0)
+    }
+}
+
+/// Returns the number of threads the parallel encoders and decoders use by
+/// default, when they're given 0 for it.
+///
+/// It's the parallelism `std::thread::available_parallelism` reports, which
+/// takes the CPU affinity of the process and the CPU quota of its cgroup
+/// into account on Linux, so that containers limited to a few CPUs of a
+/// large machine don't start a thread for each CPU of the machine. Where
+/// that's unknown it's 1.
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+impl Pool {
+    fn new(threads: usize, name: &str) -> Pool {
+        let threads = if threads == 0 {
+            available_threads()
+        } else {
+            threads
+        };
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut workers = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let rx = rx.clone();
+            let worker = thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    job();
+                });
+            match worker {
+                Ok(worker) => workers.push(worker),
+                // such as on WASI, the work is done inline there instead
+                Err(ref e) if i == 0 && e.kind() == io::ErrorKind::Unsupported => {
+                    return Pool {
+                        backend: Backend::Inline,
+                    };
+                }
+                Err(e) => panic!("failed to spawn bzip2 worker thread: {:?}", e),
+            }
+        }
+        Pool {
+            backend: Backend::Threads {
+                tx: Some(tx),
+                workers,
+            },
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    fn rayon() -> Pool {
+        Pool {
+            backend: Backend::Rayon,
+        }
+    }
+
+    fn threads(&self) -> usize {
+        match self.backend {
+            Backend::Threads { ref workers, .. } => workers.len(),
+            #[cfg(feature = "rayon")]
+            Backend::Rayon => rayon::current_num_threads(),
+            Backend::Inline => 1,
+        }
+    }
+
+    fn spawn<T, F>(&self, f: F) -> Pending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let job_waker = waker.clone();
+        let job = move || {
+            // a panicking job is reported through its `Pending` by dropping
+            // `tx`, the worker thread itself stays alive
+            if let Ok(res) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                let _ = tx.send(res);
+            }
+            drop(tx);
+            if let Some(waker) = job_waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        };
+        match self.backend {
+            Backend::Threads { ref tx, .. } => tx.as_ref().unwrap().send(Box::new(job)).unwrap(),
+            #[cfg(feature = "rayon")]
+            Backend::Rayon => rayon::spawn(job),
+            Backend::Inline => job(),
+        }
+        Pending {
+            rx,
+            res: None,
+            waker,
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        match self.backend {
+            Backend::Threads {
+                ref mut tx,
+                ref mut workers,
+            } => {
+                drop(tx.take());
+                for worker in workers.drain(..) {
+                    let _ = worker.join();
+                }
+            }
+            #[cfg(feature = "rayon")]
+            Backend::Rayon => {}
+            Backend::Inline => {}
+        }
+    }
+}
+
+impl<T> Pending<T> {
+    // Returns whether `wait` would return without blocking.
+    fn is_ready(&mut self) -> bool {
+        if self.res.is_none() {
+            match self.rx.try_recv() {
+                Ok(res) => self.res = Some(res),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        true
+    }
+}
+
+impl<T> Pending<io::Result<T>> {
+    /// Blocks until the job has completed, returning its result.
+    pub fn wait(self) -> io::Result<T> {
+        if let Some(res) = self.res {
+            return res;
+        }
+        // blocking a rayon worker on jobs queued to its own pool deadlocks
+        // once all of its workers do, so help with them instead
+        #[cfg(feature = "rayon")]
+        {
+            if rayon::current_thread_index().is_some() {
+                loop {
+                    match self.rx.try_recv() {
+                        Ok(res) => return res,
+                        Err(TryRecvError::Empty) => {}
+                        Err(TryRecvError::Disconnected) => break,
+                    }
+                    if rayon::yield_now() != Some(rayon::Yield::Executed) {
+                        thread::yield_now();
+                    }
+                }
+            }
+        }
+        match self.rx.recv() {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::other("bzip2 worker thread panicked")),
+        }
+    }
+
+    /// Returns the result of the job if it's done, or arranges for the task
+    /// of `cx` to be woken once it is.
+    #[cfg(any(feature = "futures-io", feature = "tokio1"))]
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        if !self.is_ready() {
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            // the job may have finished before the waker was in place
+            if !self.is_ready() {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(match self.res.take() {
+            Some(res) => res,
+            None => Err(io::Error::other("bzip2 worker thread panicked")),
+        })
+    }
+}
+
+/// Makes sure the oldest job in `pending` can be waited for, returning a
+/// `WouldBlock` error if it can't without blocking and `backpressure` asks
+/// for that.
+pub fn ready<T>(pending: &mut VecDeque<Pending<T>>, backpressure: Backpressure) -> io::Result<()> {
+    if backpressure == Backpressure::Block {
+        return Ok(());
+    }
+    if pending.front_mut().is_none_or(|job| job.is_ready()) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "bzip2 workers are still busy",
+    ))
+}
+
+/// Number of uncompressed bytes handed to each worker, matching the block size
+/// of `level` so that every member holds about one block.
+pub fn chunk_size(level: Compression) -> usize {
+    level.level().max(1) as usize * 100_000
+}
+
+/// Compresses `data` into a complete bzip2 member.
+pub fn compress_member(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    Ok(::compress(data, level))
+}
+
+/// A member handed back by `decompress_member`.
+pub struct Member {
+    /// The compressed data the member was decompressed from.
+    pub input: Vec<u8>,
+    /// The decompressed data, or `None` if `input` ended before the member
+    /// did, or the error decompressing it failed with.
+    pub output: io::Result<Option<Vec<u8>>>,
+}
+
+/// Decompresses `input`, which must contain exactly one bzip2 member.
+///
+/// The input is handed back even if it fails to decompress, as it may only
+/// be the tail of a member split at a header which appeared by chance.
+pub fn decompress_member(input: Vec<u8>) -> Member {
+    let output = decompress_all(&input);
+    Member { input, output }
+}
+
+fn decompress_all(input: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut d = Decompress::new(false);
+    let mut out = Vec::with_capacity(input.len() * 4);
+    loop {
+        let consumed = d.total_in() as usize;
+        let status = d
+            .decompress_vec(&input[consumed..], &mut out)
+            .map_err(io::Error::from)?;
+        match status {
+            Status::StreamEnd if d.total_in() as usize == input.len() => return Ok(Some(out)),
+            Status::StreamEnd => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "trailing data after bzip2 stream",
+                ))
+            }
+            Status::MemNeeded => return Err(Error::Mem.into()),
+            _ => {}
+        }
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity().max(4096));
+        } else if d.total_in() as usize == input.len() {
+            // there's room for output and no input left, so more input is
+            // needed to make progress
+            return Ok(None);
+        }
+    }
+}
+
+/// Length of the header matched by `find_member`.
+pub const HEADER_LEN: usize = 10;
+
+/// Finds the start of the next bzip2 member in `data` at or after `from`.
+///
+/// Members are byte aligned and start with a `BZh` magic, the block size and
+/// either a block or an end of stream magic. These ten bytes may also appear
+/// inside of compressed data, so a match is only a candidate.
+pub fn find_member(data: &[u8], from: usize) -> Option<usize> {
+    const BLOCK_MAGIC: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+    const EOS_MAGIC: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+    if from >= data.len() {
+        return None;
+    }
+    data[from..]
+        .windows(HEADER_LEN)
+        .position(|w| {
+            &w[..3] == b"BZh"
+                && (b'1'..=b'9').contains(&w[3])
+                && (w[4..] == BLOCK_MAGIC || w[4..] == EOS_MAGIC)
+        })
+        .map(|i| i + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadPool;
+    use std::io;
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore)]
+    fn names() {
+        let pool = ThreadPool::with_name(2, "test-pool");
+        assert_eq!(pool.threads(), 2);
+        let job =
+            pool.spawn(|| -> io::Result<_> { Ok(thread::current().name().map(String::from)) });
+        let name = job.wait().unwrap().unwrap();
+        assert!(name == "test-pool-0" || name == "test-pool-1");
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore)]
+    fn available_threads() {
+        let n = super::available_threads();
+        assert!(n >= 1);
+        assert_eq!(ThreadPool::default().threads(), n);
+        assert_eq!(ThreadPool::new(0).threads(), n);
+        assert_eq!(ThreadPool::new(3).threads(), 3);
+
+        let data = (0..300_000u32).map(|i| (i % 97) as u8).collect::<Vec<_>>();
+        let mut e = ::write::ParBzEncoder::new(Vec::new(), ::Compression::fast(), 0);
+        io::Write::write_all(&mut e, &data).unwrap();
+        let compressed = e.finish().unwrap();
+        assert!(::decompress(&compressed).unwrap() == data);
+    }
+}
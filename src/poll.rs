@@ -0,0 +1,344 @@
+//! Executor-agnostic compression and decompression state machines.
+//!
+//! The encoder and decoder in here don't do any I/O of their own, they work
+//! on buffers provided by the caller and say what they need through `Poll`:
+//! `Poll::Ready` once an operation is complete, and `Poll::Pending` when the
+//! caller has to do I/O first, i.e. make room in the output buffer or supply
+//! more input. This makes them straightforward to drive from custom runtimes
+//! or completion-based I/O without depending on any async I/O traits.
+//!
+//! Both buffers are passed as mutable references to slices which are
+//! advanced past the bytes consumed and produced, respectively.
+//!
+//! # Example
+//!
+//! ```
+//! use bzip2::poll::{Decoder, Encoder};
+//! use bzip2::Compression;
+//!
+//! let mut compressed = vec![0; 1024];
+//! let mut output = &mut compressed[..];
+//! let mut encoder = Encoder::new(Compression::default());
+//! let mut input = &b"Hello, World!"[..];
+//! assert!(encoder.poll_compress(&mut input, &mut output).is_ready());
+//! assert!(encoder.poll_finish(&mut output).is_ready());
+//! let len = 1024 - output.len();
+//!
+//! let mut buf = [0; 1024];
+//! let mut output = &mut buf[..];
+//! let mut decoder = Decoder::new();
+//! let mut input = &compressed[..len];
+//! assert!(decoder.poll_decompress(&mut input, &mut output).is_ready());
+//! let len = 1024 - output.len();
+//! assert_eq!(&buf[..len], b"Hello, World!");
+//! ```
+
+use std::io;
+use std::mem;
+use std::task::Poll;
+
+use coder::{self, Coder};
+use mem::as_uninit;
+use {CompressStream, Compression, DecompressStream};
+
+fn advance(output: &mut &mut [u8], produced: usize) {
+    let out = mem::take(output);
+    *output = &mut out[produced..];
+}
+
+/// A bzip2 compressor working on caller-provided buffers.
+pub struct Encoder {
+    inner: coder::Encoder,
+}
+
+impl Encoder {
+    /// Creates a new encoder compressing at the given level.
+    pub fn new(level: Compression) -> Encoder {
+        Encoder {
+            inner: coder::Encoder::new(level),
+        }
+    }
+
+    /// Compresses `input` into `output`.
+    ///
+    /// Returns `Poll::Ready` once all of `input` has been consumed, and
+    /// `Poll::Pending` if `output` is full before that, in which case it has
+    /// to be drained before calling this again with the rest of the input.
+    /// Compressed data is only produced once a block has been collected, so
+    /// usually most of the input is consumed without producing anything.
+    pub fn poll_compress(
+        &mut self,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if input.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            if output.is_empty() {
+                return Poll::Pending;
+            }
+            let (consumed, produced) = self.inner.run(input, unsafe { as_uninit(output) })?;
+            *input = &input[consumed..];
+            advance(output, produced);
+        }
+    }
+
+    /// Writes out everything compressed so far, ending the current block.
+    ///
+    /// Returns `Poll::Ready` once done, and `Poll::Pending` if `output` is
+    /// full before that, in which case it has to be drained before calling
+    /// this again. No more input is accepted until the flush completes.
+    pub fn poll_flush(&mut self, output: &mut &mut [u8]) -> Poll<io::Result<()>> {
+        let inner = &mut self.inner;
+        poll_output(output, |out| inner.flush(unsafe { as_uninit(out) }))
+    }
+
+    /// Ends the bzip2 stream.
+    ///
+    /// Returns `Poll::Ready` once the end of the stream has been written, and
+    /// `Poll::Pending` if `output` is full before that, in which case it has
+    /// to be drained before calling this again. No more input is accepted
+    /// afterwards.
+    pub fn poll_finish(&mut self, output: &mut &mut [u8]) -> Poll<io::Result<()>> {
+        let inner = &mut self.inner;
+        poll_output(output, |out| inner.finish(unsafe { as_uninit(out) }))
+    }
+
+    /// Returns whether the end of the stream has been written.
+    pub fn is_done(&self) -> bool {
+        self.inner.done()
+    }
+
+    /// Returns the number of bytes consumed by the compressor
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Returns the number of bytes produced by the compressor
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+fn poll_output<F>(output: &mut &mut [u8], mut f: F) -> Poll<io::Result<()>>
+where
+    F: FnMut(&mut [u8]) -> io::Result<(usize, bool)>,
+{
+    loop {
+        if output.is_empty() {
+            return Poll::Pending;
+        }
+        let (produced, done) = f(output)?;
+        advance(output, produced);
+        if done {
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+/// A bzip2 decompressor working on caller-provided buffers.
+///
+/// Only a single bzip2 stream is decoded, multistreams can be decoded by
+/// creating a new decoder whenever one has finished while input is left.
+pub struct Decoder {
+    inner: coder::Decoder,
+}
+
+impl Decoder {
+    /// Creates a new decoder.
+    pub fn new() -> Decoder {
+        Decoder {
+            inner: coder::Decoder::new(false),
+        }
+    }
+
+    /// Decompresses `input` into `output`.
+    ///
+    /// Returns `Poll::Ready` once the end of the bzip2 stream has been
+    /// reached, leaving anything after it in `input`. Otherwise returns
+    /// `Poll::Pending` when no progress can be made: either `output` is full
+    /// and has to be drained, or all of `input` has been consumed and more
+    /// is needed.
+    ///
+    /// Reaching the end of the input before this returned `Poll::Ready`
+    /// means that the stream was cut short.
+    pub fn poll_decompress(
+        &mut self,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.inner.done() {
+                return Poll::Ready(Ok(()));
+            }
+            if output.is_empty() {
+                return Poll::Pending;
+            }
+            let (consumed, produced) = self.inner.run(input, unsafe { as_uninit(output) })?;
+            *input = &input[consumed..];
+            advance(output, produced);
+            if consumed == 0 && produced == 0 && !self.inner.done() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Returns whether the end of the stream has been reached.
+    pub fn is_done(&self) -> bool {
+        self.inner.done()
+    }
+
+    /// Returns the number of bytes that the decompressor has consumed.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Returns the number of bytes that the decompressor has produced.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl CompressStream for Encoder {
+    fn compress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+        self.inner.run(input, unsafe { as_uninit(output) })
+    }
+
+    fn flush(&mut self, output: &mut [u8]) -> io::Result<(usize, bool)> {
+        self.inner.flush(unsafe { as_uninit(output) })
+    }
+
+    fn finish(&mut self, output: &mut [u8]) -> io::Result<(usize, bool)> {
+        self.inner.finish(unsafe { as_uninit(output) })
+    }
+
+    fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+impl DecompressStream for Decoder {
+    fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+        if self.inner.done() {
+            return Ok((0, 0));
+        }
+        self.inner.run(input, unsafe { as_uninit(output) })
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.done()
+    }
+
+    fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use rand::{thread_rng, Rng};
+
+    use super::{Decoder, Encoder};
+    use Compression;
+
+    #[test]
+    fn small_buffers() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        // everything goes through buffers of 7 bytes
+        let mut compressed = Vec::new();
+        let mut e = Encoder::new(Compression::default());
+        let mut buf = [0; 7];
+        for chunk in data.chunks(7) {
+            let mut input = chunk;
+            loop {
+                let mut output = &mut buf[..];
+                let res = e.poll_compress(&mut input, &mut output);
+                let n = 7 - output.len();
+                compressed.extend_from_slice(&buf[..n]);
+                if let Poll::Ready(res) = res {
+                    res.unwrap();
+                    break;
+                }
+            }
+        }
+        loop {
+            let mut output = &mut buf[..];
+            let res = e.poll_finish(&mut output);
+            let n = 7 - output.len();
+            compressed.extend_from_slice(&buf[..n]);
+            if let Poll::Ready(res) = res {
+                res.unwrap();
+                break;
+            }
+        }
+        assert!(e.is_done());
+        assert_eq!(e.total_out(), compressed.len() as u64);
+
+        let mut out = Vec::new();
+        let mut d = Decoder::new();
+        let trailing = [&compressed[..], b"trailer"].concat();
+        let mut input = &trailing[..];
+        loop {
+            let mut chunk = &input[..input.len().min(7)];
+            let mut output = &mut buf[..];
+            let res = d.poll_decompress(&mut chunk, &mut output);
+            let n = 7 - output.len();
+            out.extend_from_slice(&buf[..n]);
+            input = &input[input.len().min(7) - chunk.len()..];
+            if let Poll::Ready(res) = res {
+                res.unwrap();
+                break;
+            }
+        }
+        assert!(out == data);
+        assert_eq!(input, b"trailer");
+    }
+
+    #[test]
+    fn flush() {
+        let mut e = Encoder::new(Compression::default());
+        let mut buf = vec![0; 1024];
+        let mut output = &mut buf[..];
+        assert!(e.poll_compress(&mut &b"hello"[..], &mut output).is_ready());
+        let before = output.len();
+        assert!(e.poll_flush(&mut output).is_ready());
+        assert!(output.len() < before);
+
+        let mut empty = &mut [][..];
+        assert!(e.poll_finish(&mut empty).is_pending());
+        assert!(e.poll_finish(&mut output).is_ready());
+        let len = 1024 - output.len();
+
+        let mut d = Decoder::new();
+        let mut out = [0; 16];
+        let mut output = &mut out[..];
+        assert!(d.poll_decompress(&mut &buf[..len], &mut output).is_ready());
+        assert_eq!(output.len(), 11);
+
+        // a cut short stream only ever needs more input
+        let mut d = Decoder::new();
+        let mut output = &mut out[..];
+        let mut input = &buf[..len - 1];
+        assert!(d.poll_decompress(&mut input, &mut output).is_pending());
+        assert!(input.is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+//! An adapter compressing everything sent into a `futures::Sink` of byte
+//! chunks.
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_sink::Sink;
+
+use coder::{Coder, Encoder};
+use mem::uninit_chunk;
+use Compression;
+
+const CHUNK: usize = 32 * 1024;
+
+/// A sink compressing the chunks sent into it and passing the compressed
+/// chunks on to another sink.
+///
+/// Compressed data is only produced once libbz2 has collected a whole
+/// block, when flushed, or when closed. Closing finishes the bzip2 stream
+/// before closing the wrapped sink, so `poll_close` has to be called for the
+/// output to be complete.
+pub struct BzEncoder<S> {
+    inner: S,
+    coder: Encoder,
+    buf: BytesMut,
+    flushed: bool,
+    finished: bool,
+}
+
+impl<S: Sink<Bytes> + Unpin> BzEncoder<S>
+where
+    S::Error: From<io::Error>,
+{
+    /// Creates a new encoder compressing at the given level into `s`.
+    pub fn new(s: S, level: Compression) -> BzEncoder<S> {
+        BzEncoder {
+            inner: s,
+            coder: Encoder::new(level),
+            buf: BytesMut::new(),
+            flushed: true,
+            finished: false,
+        }
+    }
+
+    /// Appends output of the coder to `buf` until `f` reports it's done.
+    fn code<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut Encoder, &mut [MaybeUninit<u8>]) -> io::Result<(usize, bool)>,
+    {
+        loop {
+            self.buf.reserve(CHUNK);
+            // the coder only ever writes to its output, so the spare capacity
+            // of `buf` isn't zeroed
+            let out = unsafe { uninit_chunk(&mut self.buf) };
+            let (produced, done) = f(&mut self.coder, out)?;
+            unsafe { self.buf.advance_mut(produced) };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Passes the compressed data collected so far on to the wrapped sink.
+    fn send(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        if !self.buf.is_empty() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+            Pin::new(&mut self.inner).start_send(self.buf.split().freeze())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> BzEncoder<S> {
+    /// Acquires a reference to the underlying sink.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying sink.
+    ///
+    /// Note that to avoid data corruption, this method should not be used to
+    /// send data into the underlying sink.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes this encoder, returning the underlying sink.
+    ///
+    /// Compressed data which hasn't been sent yet is lost, and unless
+    /// `poll_close` has completed the bzip2 stream isn't finished.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns the number of bytes consumed by the compressor
+    pub fn total_in(&self) -> u64 {
+        self.coder.total_in()
+    }
+
+    /// Returns the number of bytes produced by the compressor
+    pub fn total_out(&self) -> u64 {
+        self.coder.total_out()
+    }
+}
+
+impl<S: Sink<Bytes> + Unpin> Sink<Bytes> for BzEncoder<S>
+where
+    S::Error: From<io::Error>,
+{
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.get_mut().send(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut item: Bytes) -> Result<(), S::Error> {
+        let this = self.get_mut();
+        this.flushed = false;
+        this.code(|coder, out| {
+            let (consumed, produced) = coder.run(&item, out)?;
+            item.advance(consumed);
+            Ok((produced, item.is_empty()))
+        })?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let this = self.get_mut();
+        if !this.flushed {
+            this.code(|coder, out| coder.flush(out))?;
+            this.flushed = true;
+        }
+        match this.send(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let this = self.get_mut();
+        if !this.finished {
+            this.code(|coder, out| coder.finish(out))?;
+            this.finished = true;
+        }
+        match this.send(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use bytes::Bytes;
+    use futures_sink::Sink;
+    use rand::{thread_rng, Rng};
+
+    use super::BzEncoder;
+    use read::BzDecoder;
+    use Compression;
+
+    /// Collects the chunks sent into it, only being ready every other time.
+    #[derive(Default)]
+    struct Chunks {
+        chunks: Vec<Bytes>,
+        ready: bool,
+        closed: bool,
+    }
+
+    impl Sink<Bytes> for Chunks {
+        type Error = io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.ready = !self.ready;
+            if self.ready {
+                Poll::Ready(Ok(()))
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+            assert!(!self.closed);
+            self.chunks.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll<T>(mut f: impl FnMut(&mut Context<'_>) -> Poll<T>) -> T {
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(t) = f(&mut cx) {
+                return t;
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = vec![0; 2_000_000];
+        thread_rng().fill(&mut data[..1_000_000]);
+
+        let mut s = BzEncoder::new(Chunks::default(), Compression::default());
+        for chunk in data.chunks(10_000) {
+            poll(|cx| Pin::new(&mut s).poll_ready(cx)).unwrap();
+            Pin::new(&mut s)
+                .start_send(Bytes::copy_from_slice(chunk))
+                .unwrap();
+        }
+        poll(|cx| Pin::new(&mut s).poll_close(cx)).unwrap();
+        assert_eq!(s.total_in(), data.len() as u64);
+
+        let chunks = s.into_inner();
+        assert!(chunks.closed);
+        // full blocks were passed on before the end of the stream
+        assert!(chunks.chunks.len() > 1);
+        let compressed = chunks.chunks.concat();
+
+        let mut out = Vec::new();
+        BzDecoder::new(&compressed[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert!(out == data);
+    }
+
+    #[test]
+    fn flush() {
+        let mut s = BzEncoder::new(Chunks::default(), Compression::default());
+        poll(|cx| Pin::new(&mut s).poll_ready(cx)).unwrap();
+        Pin::new(&mut s).start_send(Bytes::from("hello")).unwrap();
+        poll(|cx| Pin::new(&mut s).poll_flush(cx)).unwrap();
+        let flushed = s.get_ref().chunks.concat().len();
+        assert!(flushed > 4);
+        assert_eq!(s.total_out(), flushed as u64);
+    }
+}
@@ -0,0 +1,283 @@
+//! Files read and written through libbz2's own `FILE *` based API.
+//!
+//! These are thin wrappers around `BZ2_bzReadOpen`, `BZ2_bzRead`,
+//! `BZ2_bzWriteOpen` and `BZ2_bzWrite`, which are only available with the
+//! `stdio` feature. They're mostly useful when porting C code relying on
+//! that API, as the encoders and decoders in the `read`, `bufread` and
+//! `write` modules work on top of any stream and are more flexible.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::ptr;
+
+use libc::{c_int, c_uint, FILE};
+
+use ffi;
+use {Compression, Error};
+
+/// A file being decompressed by `BZ2_bzRead`.
+pub struct BzReader {
+    file: *mut FILE,
+    bz: *mut ffi::BZFILE,
+    done: bool,
+}
+
+/// A file being compressed by `BZ2_bzWrite`.
+///
+/// The end of the stream is written by `finish`, or when this is dropped.
+pub struct BzWriter {
+    file: *mut FILE,
+    bz: *mut ffi::BZFILE,
+}
+
+// libbz2 keeps no state of its own outside of a `BZFILE`, which is only ever
+// used through `&mut self`, and the locking of stdio makes its `FILE` safe to
+// use from any thread
+unsafe impl Send for BzReader {}
+unsafe impl Send for BzWriter {}
+
+impl BzReader {
+    /// Opens the file at `path` and prepares to decompress the bzip2 stream
+    /// at its start.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<BzReader> {
+        let file = fopen(path.as_ref(), "rb")?;
+        let mut err = 0;
+        let bz = unsafe { ffi::BZ2_bzReadOpen(&mut err, file.cast(), 0, 0, ptr::null_mut(), 0) };
+        if err != ffi::BZ_OK {
+            unsafe {
+                libc::fclose(file);
+            }
+            return Err(error(err, ptr::null_mut()));
+        }
+        Ok(BzReader {
+            file,
+            bz,
+            done: false,
+        })
+    }
+}
+
+impl Read for BzReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let mut err = 0;
+        let n = unsafe { ffi::BZ2_bzRead(&mut err, self.bz, buf.as_mut_ptr() as *mut _, len) };
+        match err {
+            ffi::BZ_OK => Ok(n as usize),
+            ffi::BZ_STREAM_END => {
+                self.done = true;
+                Ok(n as usize)
+            }
+            err => Err(error(err, self.bz)),
+        }
+    }
+}
+
+impl Drop for BzReader {
+    fn drop(&mut self) {
+        let mut err = 0;
+        unsafe {
+            ffi::BZ2_bzReadClose(&mut err, self.bz);
+            libc::fclose(self.file);
+        }
+    }
+}
+
+impl BzWriter {
+    /// Creates the file at `path`, truncating it if it exists, and prepares
+    /// to write a bzip2 stream compressed at the given level to it.
+    pub fn create<P: AsRef<Path>>(path: P, level: Compression) -> io::Result<BzWriter> {
+        let file = fopen(path.as_ref(), "wb")?;
+        let mut err = 0;
+        let bz =
+            unsafe { ffi::BZ2_bzWriteOpen(&mut err, file.cast(), level.level() as c_int, 0, 30) };
+        if err != ffi::BZ_OK {
+            unsafe {
+                libc::fclose(file);
+            }
+            return Err(error(err, ptr::null_mut()));
+        }
+        Ok(BzWriter { file, bz })
+    }
+
+    /// Writes the end of the stream and closes the file, returning the
+    /// number of bytes consumed and produced by the compressor.
+    pub fn finish(mut self) -> io::Result<(u64, u64)> {
+        let res = self.close();
+        self.bz = ptr::null_mut();
+        res
+    }
+
+    fn close(&mut self) -> io::Result<(u64, u64)> {
+        let (mut in_lo, mut in_hi, mut out_lo, mut out_hi): (c_uint, c_uint, c_uint, c_uint) =
+            (0, 0, 0, 0);
+        let mut err = 0;
+        unsafe {
+            ffi::BZ2_bzWriteClose64(
+                &mut err,
+                self.bz,
+                0,
+                &mut in_lo,
+                &mut in_hi,
+                &mut out_lo,
+                &mut out_hi,
+            );
+        }
+        let closed = unsafe { libc::fclose(self.file) };
+        if err != ffi::BZ_OK {
+            return Err(error(err, ptr::null_mut()));
+        }
+        if closed != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let total_in = (u64::from(in_hi) << 32) | u64::from(in_lo);
+        let total_out = (u64::from(out_hi) << 32) | u64::from(out_lo);
+        Ok((total_in, total_out))
+    }
+}
+
+impl Write for BzWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize);
+        let mut err = 0;
+        unsafe {
+            ffi::BZ2_bzWrite(&mut err, self.bz, buf.as_ptr() as *mut _, len as c_int);
+        }
+        match err {
+            ffi::BZ_OK => Ok(len),
+            err => Err(error(err, self.bz)),
+        }
+    }
+
+    /// Does nothing, libbz2 only writes out whole blocks until `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BzWriter {
+    fn drop(&mut self) {
+        if !self.bz.is_null() {
+            let _ = self.close();
+        }
+    }
+}
+
+fn fopen(path: &Path, mode: &str) -> io::Result<*mut FILE> {
+    #[cfg(unix)]
+    let path = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes()
+    };
+    #[cfg(not(unix))]
+    let path = path
+        .to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path is not valid unicode"))?
+        .as_bytes();
+    let path = CString::new(path)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+    let mode = CString::new(mode).unwrap();
+    let file = unsafe { libc::fopen(path.as_ptr(), mode.as_ptr()) };
+    if file.is_null() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(file)
+    }
+}
+
+/// Turns the `bzerror` of a call into an `io::Error`, described by
+/// `BZ2_bzerror` if there's a `BZFILE` to ask.
+fn error(err: c_int, bz: *mut ffi::BZFILE) -> io::Error {
+    let kind = match Error::from_code(err) {
+        Some(Error::Io) => return io::Error::last_os_error(),
+        Some(e) => io::Error::from(e).kind(),
+        None => io::ErrorKind::Other,
+    };
+    if bz.is_null() {
+        return io::Error::new(kind, format!("bzip2 error {}", err));
+    }
+    let mut errnum = 0;
+    let msg = unsafe { CStr::from_ptr(ffi::BZ2_bzerror(bz, &mut errnum)) };
+    io::Error::new(kind, format!("bzip2: {}", msg.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{BzReader, BzWriter};
+    use Compression;
+
+    fn path(name: &str) -> PathBuf {
+        // `temp_dir` and `process::id` panic on WASI, where the runtime has
+        // to pass in a directory
+        let dir = env::var_os("TMPDIR").map_or_else(env::temp_dir, PathBuf::from);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        dir.join(format!("bzip2-stdio-{}-{}.bz2", now.as_nanos(), name))
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let path = path("roundtrip");
+
+        let mut w = BzWriter::create(&path, Compression::best()).unwrap();
+        w.write_all(&data).unwrap();
+        let (total_in, total_out) = w.finish().unwrap();
+        assert_eq!(total_in, data.len() as u64);
+
+        let compressed = fs::read(&path).unwrap();
+        assert_eq!(total_out, compressed.len() as u64);
+        let mut out = Vec::new();
+        ::read::BzDecoder::new(&compressed[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert!(out == data);
+
+        let mut out = Vec::new();
+        BzReader::open(&path)
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert!(out == data);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let path = path("errors");
+        assert_eq!(
+            BzReader::open(&path).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let mut compressed = Vec::new();
+        ::write::BzEncoder::new(&mut compressed, Compression::default())
+            .write_all(b"hello")
+            .unwrap();
+        fs::write(&path, &compressed[..compressed.len() - 1]).unwrap();
+        let err = BzReader::open(&path)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        fs::write(&path, b"definitely not bzip2").unwrap();
+        let err = BzReader::open(&path)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,362 @@
+//! Adapters compressing or decompressing a `futures::Stream` of byte chunks.
+//!
+//! These fit chunk-oriented async APIs such as HTTP bodies or multipart
+//! uploads, where data is passed around as a stream of `Bytes` rather than
+//! through `AsyncRead`/`AsyncWrite`. Each adapter wraps a stream of
+//! `io::Result<Bytes>` and is a stream of the coded chunks in turn.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_core::Stream;
+
+use coder::{Coder, Decoder, Encoder};
+use mem::uninit_chunk;
+use Compression;
+
+const CHUNK: usize = 32 * 1024;
+
+/// Drives a `Coder` with the chunks of a stream.
+pub(crate) struct Chunks<S, C> {
+    pub(crate) inner: S,
+    pub(crate) coder: C,
+    chunk: Bytes,
+    buf: BytesMut,
+    eof: bool,
+    finished: bool,
+}
+
+impl<S, C> Chunks<S, C>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+    C: Coder,
+{
+    pub(crate) fn new(inner: S, coder: C) -> Chunks<S, C> {
+        Chunks {
+            inner,
+            coder,
+            chunk: Bytes::new(),
+            buf: BytesMut::new(),
+            eof: false,
+            finished: false,
+        }
+    }
+
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+        loop {
+            if self.finished || self.coder.done() {
+                return Poll::Ready(None);
+            }
+            if self.chunk.is_empty() && !self.eof {
+                match Pin::new(&mut self.inner).poll_next(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => self.chunk = chunk,
+                    Poll::Ready(Some(Err(e))) => {
+                        self.finished = true;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    Poll::Ready(None) => self.eof = true,
+                    Poll::Pending => return Poll::Pending,
+                }
+                continue;
+            }
+
+            self.buf.reserve(CHUNK);
+            // the coder only ever writes to its output, so the spare capacity
+            // of `buf` isn't zeroed
+            let out = unsafe { uninit_chunk(&mut self.buf) };
+            let res = if self.eof {
+                self.coder.finish(out).map(|(produced, done)| {
+                    self.finished = done;
+                    produced
+                })
+            } else {
+                self.coder
+                    .run(&self.chunk, out)
+                    .map(|(consumed, produced)| {
+                        self.chunk.advance(consumed);
+                        produced
+                    })
+            };
+            match res {
+                Ok(0) => {}
+                Ok(produced) => {
+                    unsafe { self.buf.advance_mut(produced) };
+                    return Poll::Ready(Some(Ok(self.buf.split().freeze())));
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+/// A stream of the compressed chunks of another stream.
+///
+/// Once the wrapped stream has ended, the end of the bzip2 stream is emitted
+/// and this stream ends as well.
+pub struct BzEncoder<S> {
+    inner: Chunks<S, Encoder>,
+}
+
+/// A stream of the decompressed chunks of another stream.
+///
+/// Only the first bzip2 stream is decoded, the wrapped stream isn't polled
+/// anymore once it has ended.
+pub struct BzDecoder<S> {
+    inner: Chunks<S, Decoder>,
+}
+
+/// A stream of the decompressed chunks of another stream which decodes all
+/// members of a multistream.
+pub struct MultiBzDecoder<S> {
+    inner: Chunks<S, Decoder>,
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> BzEncoder<S> {
+    /// Creates a new encoder compressing the chunks of `s` at the given
+    /// level.
+    pub fn new(s: S, level: Compression) -> BzEncoder<S> {
+        BzEncoder {
+            inner: Chunks::new(s, Encoder::new(level)),
+        }
+    }
+}
+
+impl<S> BzEncoder<S> {
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner.inner
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this encoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner.inner
+    }
+
+    /// Consumes this encoder, returning the underlying stream.
+    ///
+    /// Any part of a chunk which hasn't been compressed yet is lost.
+    pub fn into_inner(self) -> S {
+        self.inner.inner
+    }
+
+    /// Returns the number of bytes consumed by the compressor
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes produced by the compressor
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for BzEncoder<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().inner.poll_next(cx)
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> BzDecoder<S> {
+    /// Creates a new decoder decompressing the chunks of `s`.
+    pub fn new(s: S) -> BzDecoder<S> {
+        BzDecoder {
+            inner: Chunks::new(s, Decoder::new(false)),
+        }
+    }
+}
+
+impl<S> BzDecoder<S> {
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner.inner
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner.inner
+    }
+
+    /// Consumes this decoder, returning the underlying stream.
+    ///
+    /// Any part of a chunk which hasn't been decompressed yet is lost.
+    pub fn into_inner(self) -> S {
+        self.inner.inner
+    }
+
+    /// Returns the number of bytes that the decompressor has consumed.
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes that the decompressor has produced.
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for BzDecoder<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().inner.poll_next(cx)
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> MultiBzDecoder<S> {
+    /// Creates a new decoder decompressing all members of the multistream
+    /// whose chunks `s` yields.
+    pub fn new(s: S) -> MultiBzDecoder<S> {
+        MultiBzDecoder {
+            inner: Chunks::new(s, Decoder::new(true)),
+        }
+    }
+}
+
+impl<S> MultiBzDecoder<S> {
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner.inner
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner.inner
+    }
+
+    /// Consumes this decoder, returning the underlying stream.
+    ///
+    /// Any part of a chunk which hasn't been decompressed yet is lost.
+    pub fn into_inner(self) -> S {
+        self.inner.inner
+    }
+
+    /// Returns the number of bytes that the decompressor has consumed.
+    pub fn total_in(&self) -> u64 {
+        self.inner.coder.total_in()
+    }
+
+    /// Returns the number of bytes that the decompressor has produced.
+    pub fn total_out(&self) -> u64 {
+        self.inner.coder.total_out()
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for MultiBzDecoder<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().inner.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use bytes::Bytes;
+    use futures_core::Stream;
+    use rand::{thread_rng, Rng};
+
+    use super::{BzDecoder, BzEncoder, MultiBzDecoder};
+    use Compression;
+
+    /// Yields its chunks, returning `Pending` before each of them.
+    struct Chunks {
+        chunks: VecDeque<io::Result<Bytes>>,
+        pending: bool,
+    }
+
+    impl Chunks {
+        fn new(data: &[u8], size: usize) -> Chunks {
+            Chunks {
+                chunks: data
+                    .chunks(size)
+                    .map(|c| Ok(Bytes::copy_from_slice(c)))
+                    .collect(),
+                pending: false,
+            }
+        }
+    }
+
+    impl Stream for Chunks {
+        type Item = io::Result<Bytes>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.chunks.pop_front())
+        }
+    }
+
+    fn collect<S: Stream<Item = io::Result<Bytes>> + Unpin>(mut s: S) -> io::Result<Vec<u8>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        loop {
+            match Pin::new(&mut s).poll_next(&mut cx) {
+                Poll::Ready(Some(chunk)) => out.extend_from_slice(&chunk?),
+                Poll::Ready(None) => return Ok(out),
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        let compressed = collect(BzEncoder::new(
+            Chunks::new(&data, 1000),
+            Compression::default(),
+        ))
+        .unwrap();
+        let two = [&compressed[..], &compressed[..]].concat();
+
+        let mut s = BzDecoder::new(Chunks::new(&two, 777));
+        assert!(collect(&mut s).unwrap() == data);
+        assert_eq!(s.total_in(), compressed.len() as u64);
+
+        let s = MultiBzDecoder::new(Chunks::new(&two, 777));
+        assert!(collect(s).unwrap() == [&data[..], &data[..]].concat());
+    }
+
+    #[test]
+    fn errors() {
+        let compressed = collect(BzEncoder::new(
+            Chunks::new(b"hello", 1),
+            Compression::default(),
+        ))
+        .unwrap();
+        let s = BzDecoder::new(Chunks::new(&compressed[..compressed.len() - 1], 5));
+        let err = collect(s).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // errors of the wrapped stream are passed on
+        let mut chunks = Chunks::new(&compressed, 5);
+        chunks.chunks[1] = Err(io::Error::other("boom"));
+        let err = collect(BzDecoder::new(chunks)).unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+    }
+}
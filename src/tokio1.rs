@@ -0,0 +1,938 @@
+//! Encoders and decoders for the I/O traits of tokio 1.x.
+//!
+//! The `tokio` feature of this crate only supports the long deprecated
+//! tokio-io 0.1, the types in here are enabled by the `tokio1` feature
+//! instead. The writers are cancellation safe, see the `write` module.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio_crate::io::{AsyncBufRead, AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use aio;
+use coder::Coder;
+
+/// Adapts the tokio traits to the ones the `aio` module works with.
+struct Compat<T>(T);
+
+impl<T: AsyncBufRead + Unpin> aio::BufSource for Compat<T> {
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.0).poll_fill_buf(cx)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        Pin::new(&mut self.0).consume(amt)
+    }
+}
+
+impl<T: AsyncRead + Unpin> aio::Source for Compat<T> {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut self.0).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncSeek + Unpin> aio::Seeker for Compat<T> {
+    fn start_seek(&mut self, pos: u64) -> io::Result<()> {
+        Pin::new(&mut self.0).start_seek(io::SeekFrom::Start(pos))
+    }
+
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.0).poll_complete(cx)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> aio::Sink for Compat<T> {
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn poll_read<R: aio::BufSource, C: Coder>(
+    r: &mut aio::Reader<R, C>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<io::Result<()>> {
+    match r.poll_read(cx, buf.initialize_unfilled()) {
+        Poll::Ready(Ok(n)) => {
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+pub mod bufread {
+    //! Async encoders and decoders reading from an `AsyncBufRead`.
+    //!
+    //! These read straight out of the buffer of their source, so wrapping an
+    //! already buffered stream such as a `tokio::io::BufReader` doesn't copy
+    //! the data a second time.
+
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio_crate::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+    use super::{poll_read, Compat};
+    use aio::Reader;
+    use coder::{Coder, Decoder, Encoder};
+    use Compression;
+
+    /// A bz2 encoder, or compressor.
+    ///
+    /// This structure implements `AsyncRead` and will read uncompressed data
+    /// from an underlying stream and emit a stream of compressed data.
+    pub struct BzEncoder<R> {
+        inner: Reader<Compat<R>, Encoder>,
+    }
+
+    /// A bz2 decoder, or decompressor.
+    ///
+    /// This structure implements `AsyncRead` and takes a stream of
+    /// compressed data as input, providing the decompressed data when read
+    /// from. Only the first bzip2 stream is decoded.
+    pub struct BzDecoder<R> {
+        inner: Reader<Compat<R>, Decoder>,
+    }
+
+    /// A bzip2 streaming decoder that decodes all members of a multistream.
+    pub struct MultiBzDecoder<R> {
+        inner: Reader<Compat<R>, Decoder>,
+    }
+
+    impl<R: AsyncBufRead + Unpin> BzEncoder<R> {
+        /// Creates a new encoder which will read uncompressed data from the
+        /// given stream and emit the compressed stream.
+        pub fn new(r: R, level: Compression) -> BzEncoder<R> {
+            BzEncoder {
+                inner: Reader::new(Compat(r), Encoder::new(level)),
+            }
+        }
+    }
+
+    impl<R> BzEncoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this encoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this encoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes consumed by the compressor
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes produced by the compressor
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncRead for BzEncoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            poll_read(&mut self.get_mut().inner, cx, buf)
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> BzDecoder<R> {
+        /// Creates a new decoder which will decompress data read from the
+        /// given stream.
+        pub fn new(r: R) -> BzDecoder<R> {
+            BzDecoder {
+                inner: Reader::new(Compat(r), Decoder::new(false)),
+            }
+        }
+    }
+
+    impl<R> BzDecoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this decoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        ///
+        /// Any data following the bzip2 stream is left in the reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncRead for BzDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            poll_read(&mut self.get_mut().inner, cx, buf)
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> MultiBzDecoder<R> {
+        /// Creates a new decoder from the given reader. If the bzip2 stream
+        /// contains multiple members all will be decoded.
+        pub fn new(r: R) -> MultiBzDecoder<R> {
+            MultiBzDecoder {
+                inner: Reader::new(Compat(r), Decoder::new(true)),
+            }
+        }
+    }
+
+    impl<R> MultiBzDecoder<R> {
+        /// Acquires a reference to the underlying stream
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream
+        ///
+        /// Note that mutation of the stream may result in surprising results
+        /// if this decoder is continued to be used.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+
+        /// Limits the work done by a single read to about `budget` bytes.
+        ///
+        /// Once that much input has been consumed without producing any
+        /// output, the task is woken and `Poll::Pending` is returned to give
+        /// other tasks on the executor a chance to run. Reads also return at
+        /// most `budget` bytes. There's no limit by default.
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.inner.budget = budget;
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncRead for MultiBzDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            poll_read(&mut self.get_mut().inner, cx, buf)
+        }
+    }
+}
+
+pub mod read {
+    //! Async decoders reading from an `AsyncRead` which can also seek.
+
+    use std::io::{self, SeekFrom};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio_crate::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+    use super::Compat;
+    use aio::SeekReader;
+    use BzIndex;
+
+    /// A decompression stream which can seek to any offset of the
+    /// decompressed data, like `bzip2::read::BzSeekDecoder`.
+    ///
+    /// This implements `AsyncRead` and `AsyncSeek`, and only decompresses the
+    /// block holding the data asked for. There's no async scan of the input,
+    /// the index of its blocks has to be built beforehand, e.g. with
+    /// `BzIndex::build` on a blocking reader, or loaded with
+    /// `BzIndex::read_from`.
+    ///
+    /// Blocks are decompressed on the task reading from the decoder, which
+    /// takes a while for the larger block sizes.
+    pub struct BzSeekDecoder<R> {
+        inner: SeekReader<Compat<R>>,
+    }
+
+    impl<R: AsyncRead + AsyncSeek + Unpin> BzSeekDecoder<R> {
+        /// Creates a new decoder for the compressed data in `r` starting at
+        /// offset `start`, using an index built earlier for the same data.
+        ///
+        /// No data is read until the first read from the decoder. If `index`
+        /// doesn't match the data reads return errors.
+        pub fn with_index(r: R, start: u64, index: BzIndex) -> BzSeekDecoder<R> {
+            BzSeekDecoder {
+                inner: SeekReader::new(Compat(r), start, index),
+            }
+        }
+    }
+
+    impl<R> BzSeekDecoder<R> {
+        /// Returns the index of the blocks of the underlying data.
+        pub fn index(&self) -> &BzIndex {
+            &self.inner.index
+        }
+
+        /// Acquires a reference to the underlying reader.
+        pub fn get_ref(&self) -> &R {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying stream.
+        ///
+        /// Note that the position of the stream is changed by reads from
+        /// this decoder, and that changing its data results in errors or
+        /// garbage.
+        pub fn get_mut(&mut self) -> &mut R {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.inner.inner.0
+        }
+    }
+
+    impl<R: AsyncRead + AsyncSeek + Unpin> AsyncRead for BzSeekDecoder<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match self
+                .get_mut()
+                .inner
+                .poll_read(cx, buf.initialize_unfilled())
+            {
+                Poll::Ready(Ok(n)) => {
+                    buf.advance(n);
+                    Poll::Ready(Ok(()))
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    impl<R: AsyncRead + AsyncSeek + Unpin> AsyncSeek for BzSeekDecoder<R> {
+        fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+            self.get_mut().inner.seek(pos).map(|_| ())
+        }
+
+        fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Poll::Ready(Ok(self.inner.position()))
+        }
+    }
+}
+
+pub mod write {
+    //! Async encoders and decoders writing into an `AsyncWrite`.
+    //!
+    //! # Cancellation safety
+    //!
+    //! All of these are safe to use with futures which are dropped before
+    //! completion, such as the ones of `tokio::io::AsyncWriteExt` in a
+    //! `tokio::select!`. Data is only reported as written once it has been
+    //! taken over by the encoder or decoder, and coded output which the
+    //! underlying writer didn't accept yet stays buffered inside of them
+    //! until the next call to `poll_write`, `poll_flush` or `poll_shutdown`.
+    //! An interrupted `poll_shutdown` in particular can simply be called
+    //! again to resume finishing the stream.
+    //!
+    //! Nothing is written out when these are dropped though, so the stream
+    //! is only complete once `poll_shutdown` has returned `Ready`.
+
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio_crate::io::AsyncWrite;
+
+    use super::Compat;
+    use aio::{ParWriter, Writer};
+    use coder::{Coder, Decoder, Encoder};
+    use {Compression, ParConfig, ThreadPool};
+
+    /// A bz2 encoder, or compressor.
+    ///
+    /// This structure implements `AsyncWrite` and will compress all data
+    /// written to it into the underlying stream.
+    pub struct BzEncoder<W> {
+        inner: Writer<Compat<W>, Encoder>,
+    }
+
+    /// A bz2 decoder, or decompressor.
+    ///
+    /// This structure implements `AsyncWrite` and will decompress all data
+    /// written to it into the underlying stream. Only the first bzip2 stream
+    /// is decoded, writes after its end return `Ok(0)`.
+    pub struct BzDecoder<W> {
+        inner: Writer<Compat<W>, Decoder>,
+    }
+
+    /// A bzip2 streaming decoder that decodes all members of a multistream
+    /// written to it.
+    pub struct MultiBzDecoder<W> {
+        inner: Writer<Compat<W>, Decoder>,
+    }
+
+    impl<W: AsyncWrite + Unpin> BzEncoder<W> {
+        /// Create a new compression stream which will compress at the given
+        /// level to write compress output to the give output stream.
+        pub fn new(w: W, level: Compression) -> BzEncoder<W> {
+            BzEncoder {
+                inner: Writer::new(Compat(w), Encoder::new(level)),
+            }
+        }
+    }
+
+    impl<W> BzEncoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this encoder, returning the underlying writer.
+        ///
+        /// The stream is not finished, call `poll_shutdown` first for that.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes consumed by the compressor
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes produced by the compressor
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for BzEncoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> BzDecoder<W> {
+        /// Create a new decoding stream which will decompress all data
+        /// written to it into `w`.
+        pub fn new(w: W) -> BzDecoder<W> {
+            BzDecoder {
+                inner: Writer::new(Compat(w), Decoder::new(false)),
+            }
+        }
+    }
+
+    impl<W> BzDecoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying writer.
+        ///
+        /// Decompressed data which hasn't been written out yet is lost.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for BzDecoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> MultiBzDecoder<W> {
+        /// Create a new decoding stream which will decompress all members of
+        /// the multistream written to it into `w`.
+        pub fn new(w: W) -> MultiBzDecoder<W> {
+            MultiBzDecoder {
+                inner: Writer::new(Compat(w), Decoder::new(true)),
+            }
+        }
+    }
+
+    impl<W> MultiBzDecoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this decoder, returning the underlying writer.
+        ///
+        /// Decompressed data which hasn't been written out yet is lost.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes that the decompressor has consumed.
+        pub fn total_in(&self) -> u64 {
+            self.inner.coder.total_in()
+        }
+
+        /// Returns the number of bytes that the decompressor has produced.
+        pub fn total_out(&self) -> u64 {
+            self.inner.coder.total_out()
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for MultiBzDecoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+
+    /// A compression stream which compresses data on several threads at once.
+    ///
+    /// This is the async counterpart of `bzip2::write::ParBzEncoder`: data is
+    /// split into chunks which are compressed as independent members on the
+    /// threads of a `ThreadPool`, so the task writing to it only does the I/O
+    /// and never blocks the runtime on compression, no `spawn_blocking`
+    /// needed. The output is a multistream.
+    ///
+    /// Once as many chunks as allowed by the `ParConfig` are in flight,
+    /// writes return `Poll::Pending` until the oldest one has been written
+    /// out; the backpressure mode of the config is ignored. The stream is
+    /// only complete once `poll_shutdown` has returned `Ready`.
+    pub struct ParBzEncoder<W> {
+        inner: ParWriter<Compat<W>>,
+    }
+
+    impl<W: AsyncWrite + Unpin> ParBzEncoder<W> {
+        /// Create a new compression stream which will compress at the given
+        /// level using `threads` worker threads, writing compressed output
+        /// to `w`.
+        ///
+        /// Passing 0 for `threads` uses as many as `available_threads` returns.
+        pub fn new(w: W, level: Compression, threads: usize) -> ParBzEncoder<W> {
+            ParBzEncoder::with_config(w, level, threads, ParConfig::default())
+        }
+
+        /// Same as `new`, but with the limits of `config` on the chunks being
+        /// compressed at once.
+        ///
+        /// Passing 0 for `threads` uses as many as `available_threads` returns.
+        pub fn with_config(
+            w: W,
+            level: Compression,
+            threads: usize,
+            config: ParConfig,
+        ) -> ParBzEncoder<W> {
+            ParBzEncoder::with_thread_pool(w, level, &ThreadPool::new(threads), config)
+        }
+
+        /// Same as `new`, but compresses on the global rayon thread pool
+        /// instead of spawning dedicated threads.
+        #[cfg(feature = "rayon")]
+        pub fn with_rayon(w: W, level: Compression) -> ParBzEncoder<W> {
+            ParBzEncoder::with_thread_pool(w, level, &ThreadPool::rayon(), ParConfig::default())
+        }
+
+        /// Same as `with_config`, but compresses on the threads of `pool`,
+        /// which may be shared with other encoders and decoders.
+        pub fn with_thread_pool(
+            w: W,
+            level: Compression,
+            pool: &ThreadPool,
+            config: ParConfig,
+        ) -> ParBzEncoder<W> {
+            ParBzEncoder {
+                inner: ParWriter::new(Compat(w), level, pool, config),
+            }
+        }
+    }
+
+    impl<W> ParBzEncoder<W> {
+        /// Acquires a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            &self.inner.inner.0
+        }
+
+        /// Acquires a mutable reference to the underlying writer.
+        ///
+        /// Note that to avoid data corruption, this method should not be used
+        /// to write data into the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            &mut self.inner.inner.0
+        }
+
+        /// Consumes this encoder, returning the underlying writer.
+        ///
+        /// The stream is not finished, call `poll_shutdown` first for that.
+        pub fn into_inner(self) -> W {
+            self.inner.inner.0
+        }
+
+        /// Returns the number of bytes written to this stream.
+        pub fn total_in(&self) -> u64 {
+            self.inner.total_in
+        }
+
+        /// Returns the number of compressed bytes written to the underlying
+        /// writer.
+        pub fn total_out(&self) -> u64 {
+            self.inner.total_out
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for ParBzEncoder<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().inner.poll_close(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use rand::{thread_rng, Rng};
+    use tokio_crate::io::{
+        AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader,
+        ReadBuf,
+    };
+
+    use super::bufread::{BzDecoder, BzEncoder, MultiBzDecoder};
+    use super::read::BzSeekDecoder;
+    use super::write;
+    use super::write::ParBzEncoder;
+    use {BzIndex, Compression, ParConfig};
+
+    /// Takes a few bytes at a time, returning `Pending` in between.
+    #[derive(Default)]
+    struct Choppy {
+        data: Vec<u8>,
+        pending: bool,
+    }
+
+    impl AsyncWrite for Choppy {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.pending = !self.pending;
+            if self.pending {
+                return Poll::Pending;
+            }
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Polls a fresh future from `f` once, dropping it if it isn't ready.
+    fn poll_once<F: Future>(f: F) -> Poll<F::Output> {
+        let mut cx = Context::from_waker(Waker::noop());
+        Box::pin(f).as_mut().poll(&mut cx)
+    }
+
+    fn read_to_end<R: AsyncRead + Unpin>(mut r: R) -> io::Result<Vec<u8>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let mut buf = ReadBuf::new(&mut buf);
+            match Pin::new(&mut r).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => return Ok(out),
+                Poll::Ready(Ok(())) => out.extend_from_slice(buf.filled()),
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        let r = BzEncoder::new(BufReader::new(&data[..]), Compression::default());
+        let compressed = read_to_end(r).unwrap();
+
+        let two = [&compressed[..], &compressed[..]].concat();
+        let r = BzDecoder::new(BufReader::with_capacity(7, &two[..]));
+        assert!(read_to_end(r).unwrap() == data);
+        let mut r = MultiBzDecoder::new(BufReader::new(&two[..]));
+        assert!(read_to_end(&mut r).unwrap() == [&data[..], &data[..]].concat());
+        assert_eq!(r.total_in(), two.len() as u64);
+    }
+
+    #[test]
+    fn leaves_trailing_data() {
+        let compressed = ::write::BzEncoder::new(Vec::new(), Compression::default())
+            .finish()
+            .unwrap();
+        let input = [&compressed[..], b"trailer"].concat();
+        let mut r = BzDecoder::new(&input[..]);
+        assert!(read_to_end(&mut r).unwrap().is_empty());
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let rest = Pin::new(r.get_mut()).poll_fill_buf(&mut cx);
+        match rest {
+            Poll::Ready(Ok(rest)) => assert_eq!(rest, b"trailer"),
+            _ => panic!("trailing data was consumed"),
+        }
+    }
+
+    #[test]
+    fn seek() {
+        let mut data = vec![0; 250_000];
+        thread_rng().fill(&mut data[..]);
+        let compressed = {
+            let mut w = ::write::BzEncoder::new(Vec::new(), Compression::fast());
+            io::Write::write_all(&mut w, &data).unwrap();
+            w.finish().unwrap()
+        };
+        let index = BzIndex::build(&compressed[..]).unwrap();
+
+        let mut r = BzSeekDecoder::with_index(io::Cursor::new(&compressed[..]), 0, index);
+        let mut buf = [0; 1000];
+        for &pos in &[200_000, 10, 249_500, 99_500] {
+            let n = match poll_once(r.seek(io::SeekFrom::Start(pos))) {
+                Poll::Ready(n) => n.unwrap(),
+                Poll::Pending => panic!("pending"),
+            };
+            assert_eq!(n, pos);
+            let n = match poll_once(r.read(&mut buf)) {
+                Poll::Ready(n) => n.unwrap(),
+                Poll::Pending => panic!("pending"),
+            };
+            let pos = pos as usize;
+            assert!(n > 0);
+            assert_eq!(&buf[..n], &data[pos..pos + n]);
+        }
+
+        let r = BzSeekDecoder::with_index(
+            io::Cursor::new(&compressed[..]),
+            0,
+            BzIndex::build(&compressed[..]).unwrap(),
+        );
+        assert!(read_to_end(r).unwrap() == data);
+    }
+
+    #[test]
+    fn parallel() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        let config = ParConfig::new().chunk_size(7_000).max_in_flight(3);
+        let mut w = ParBzEncoder::with_config(Vec::new(), Compression::fast(), 2, config);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            if let Poll::Ready(n) = Pin::new(&mut w).poll_write(&mut cx, rest) {
+                rest = &rest[n.unwrap()..];
+            }
+        }
+        while Pin::new(&mut w).poll_shutdown(&mut cx).is_pending() {}
+
+        let compressed = w.into_inner();
+        let r = MultiBzDecoder::new(&compressed[..]);
+        assert!(read_to_end(r).unwrap() == data);
+    }
+
+    #[test]
+    fn cancelled_writes() {
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..50_000]);
+
+        let mut w = write::BzEncoder::new(Choppy::default(), Compression::default());
+        let mut rest = &data[..];
+        let mut cancelled = 0;
+        while !rest.is_empty() {
+            match poll_once(w.write(rest)) {
+                Poll::Ready(n) => rest = &rest[n.unwrap()..],
+                Poll::Pending => cancelled += 1,
+            }
+        }
+        loop {
+            match poll_once(w.shutdown()) {
+                Poll::Ready(res) => break res.unwrap(),
+                Poll::Pending => cancelled += 1,
+            }
+        }
+        assert!(cancelled > 0);
+        let compressed = w.into_inner().data;
+
+        let mut w = write::BzDecoder::new(Choppy::default());
+        let mut rest = &compressed[..];
+        while !rest.is_empty() {
+            if let Poll::Ready(n) = poll_once(w.write(rest)) {
+                rest = &rest[n.unwrap()..];
+            }
+        }
+        while poll_once(w.shutdown()).is_pending() {}
+        assert!(w.into_inner().data == data);
+    }
+}
@@ -0,0 +1,391 @@
+//! A tower middleware speaking `Content-Encoding: bzip2`.
+//!
+//! `BzLayer` wraps a service of `http` requests and responses. Request
+//! bodies sent with a `Content-Encoding` of `bzip2` are decompressed before
+//! they reach the service, and responses are compressed when the request
+//! listed `bzip2` in its `Accept-Encoding`. Everything else passes through
+//! unchanged, only with the body wrapped in a `BzBody`.
+//!
+//! Only an explicit `bzip2` in `Accept-Encoding` enables compression, not
+//! `*`, as this is mostly useful for clients known to support it.
+
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY,
+};
+use http::{Method, Request, Response, StatusCode};
+use http_body::{Body, Frame, SizeHint};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use body::{BzEncoder, MultiBzDecoder};
+use Compression;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A layer adding bzip2 support to services, see the module documentation.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BzLayer {
+    level: Compression,
+}
+
+impl BzLayer {
+    /// Creates a new layer compressing responses at the default level.
+    pub fn new() -> BzLayer {
+        BzLayer::default()
+    }
+
+    /// Sets the level responses are compressed at.
+    pub fn level(mut self, level: Compression) -> BzLayer {
+        self.level = level;
+        self
+    }
+}
+
+impl<S> Layer<S> for BzLayer {
+    type Service = Bz<S>;
+
+    fn layer(&self, inner: S) -> Bz<S> {
+        Bz::new(inner, self.level)
+    }
+}
+
+/// A service decompressing requests and compressing responses with bzip2 for
+/// another service.
+#[derive(Clone, Debug)]
+pub struct Bz<S> {
+    inner: S,
+    level: Compression,
+}
+
+impl<S> Bz<S> {
+    /// Wraps `inner`, compressing responses at the given level.
+    pub fn new(inner: S, level: Compression) -> Bz<S> {
+        Bz { inner, level }
+    }
+
+    /// Acquires a reference to the wrapped service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the wrapped service.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes this service, returning the wrapped one.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, ReqB, ResB> Service<Request<ReqB>> for Bz<S>
+where
+    S: Service<Request<BzBody<ReqB>>, Response = Response<ResB>>,
+    ReqB: Body + Unpin,
+    ReqB::Error: Into<BoxError>,
+    ResB: Body + Unpin,
+    ResB::Error: Into<BoxError>,
+{
+    type Response = Response<BzBody<ResB>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqB>) -> ResponseFuture<S::Future> {
+        let level = if req.method() != Method::HEAD && accepts_bzip2(req.headers()) {
+            Some(self.level)
+        } else {
+            None
+        };
+        let (mut parts, body) = req.into_parts();
+        let body = if is_bzip2(&parts.headers) {
+            parts.headers.remove(CONTENT_ENCODING);
+            parts.headers.remove(CONTENT_LENGTH);
+            BzBody::new(Kind::Decode(MultiBzDecoder::new(body)))
+        } else {
+            BzBody::new(Kind::Identity(body))
+        };
+        ResponseFuture {
+            inner: self.inner.call(Request::from_parts(parts, body)),
+            level,
+        }
+    }
+}
+
+/// The future of the response of a `Bz` service.
+pub struct ResponseFuture<F> {
+    inner: F,
+    level: Option<Compression>,
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: Body + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Output = Result<Response<BzBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `inner` is never moved, and `level` doesn't need to be pinned
+        let (inner, level) = unsafe {
+            let this = self.get_unchecked_mut();
+            (Pin::new_unchecked(&mut this.inner), this.level)
+        };
+        let res = match inner.poll(cx) {
+            Poll::Ready(Ok(res)) => res,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let (mut parts, body) = res.into_parts();
+        let status = parts.status;
+        let body = match level {
+            Some(level)
+                if !parts.headers.contains_key(CONTENT_ENCODING)
+                    && !status.is_informational()
+                    && status != StatusCode::NO_CONTENT
+                    && status != StatusCode::NOT_MODIFIED =>
+            {
+                parts.headers.remove(CONTENT_LENGTH);
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static("bzip2"));
+                parts
+                    .headers
+                    .append(VARY, HeaderValue::from_static("accept-encoding"));
+                BzBody::new(Kind::Encode(BzEncoder::new(body, level)))
+            }
+            _ => BzBody::new(Kind::Identity(body)),
+        };
+        Poll::Ready(Ok(Response::from_parts(parts, body)))
+    }
+}
+
+enum Kind<B> {
+    Identity(B),
+    Encode(BzEncoder<B>),
+    Decode(MultiBzDecoder<B>),
+}
+
+/// The body of requests and responses passing through `Bz`, which is
+/// compressed, decompressed or passed on as is.
+pub struct BzBody<B> {
+    kind: Kind<B>,
+}
+
+impl<B> BzBody<B> {
+    fn new(kind: Kind<B>) -> BzBody<B> {
+        BzBody { kind }
+    }
+
+    /// Returns whether the data of the wrapped body is passed on as is.
+    pub fn is_identity(&self) -> bool {
+        matches!(self.kind, Kind::Identity(_))
+    }
+}
+
+impl<B> Body for BzBody<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        match self.get_mut().kind {
+            Kind::Identity(ref mut b) => match Pin::new(b).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => {
+                    Poll::Ready(Some(Ok(frame.map_data(|mut data| {
+                        let len = data.remaining();
+                        data.copy_to_bytes(len)
+                    }))))
+                }
+                Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::other(e)))),
+                Poll::Ready(None) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            },
+            Kind::Encode(ref mut b) => Pin::new(b).poll_frame(cx),
+            Kind::Decode(ref mut b) => Pin::new(b).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self.kind {
+            Kind::Identity(ref b) => b.is_end_stream(),
+            _ => false,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.kind {
+            Kind::Identity(ref b) => b.size_hint(),
+            _ => SizeHint::new(),
+        }
+    }
+}
+
+fn is_bzip2(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("bzip2"))
+}
+
+fn accepts_bzip2(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim();
+            let refused = params.any(|p| {
+                let p = p.trim();
+                let q = p.strip_prefix("q=").or_else(|| p.strip_prefix("Q="));
+                q.and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+            });
+            name.eq_ignore_ascii_case("bzip2") && !refused
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::{self, Future};
+    use std::io::Read;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use bytes::Bytes;
+    use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use http::{HeaderMap, Request, Response};
+    use http_body::{Body, Frame};
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    use super::{accepts_bzip2, BzBody, BzLayer};
+    use Compression;
+
+    /// A body of a single chunk.
+    struct Once(Option<Bytes>);
+
+    impl Body for Once {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(self.0.take().map(|b| Ok(Frame::data(b))))
+        }
+    }
+
+    /// Responds with the body of the request.
+    struct Echo;
+
+    impl Service<Request<BzBody<Once>>> for Echo {
+        type Response = Response<BzBody<Once>>;
+        type Error = Infallible;
+        type Future = future::Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<BzBody<Once>>) -> Self::Future {
+            assert!(!req.headers().contains_key(CONTENT_ENCODING));
+            future::ready(Ok(Response::new(req.into_body())))
+        }
+    }
+
+    fn collect<B: Body<Data = Bytes> + Unpin>(mut b: B) -> Vec<u8> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        while let Poll::Ready(Some(frame)) = Pin::new(&mut b).poll_frame(&mut cx) {
+            let frame = frame.ok().unwrap();
+            out.extend_from_slice(frame.data_ref().unwrap());
+        }
+        out
+    }
+
+    fn call(req: Request<Once>) -> Response<BzBody<BzBody<Once>>> {
+        let mut svc = BzLayer::new().level(Compression::fast()).layer(Echo);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut fut = svc.call(req);
+        match Pin::new(&mut fut).poll(&mut cx) {
+            Poll::Ready(res) => res.unwrap(),
+            Poll::Pending => panic!("pending"),
+        }
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        ::read::BzEncoder::new(data, Compression::default())
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn roundtrip() {
+        let compressed = compress(b"hello world");
+        let req = Request::builder()
+            .header(CONTENT_ENCODING, "bzip2")
+            .header(ACCEPT_ENCODING, "gzip, bzip2;q=0.5")
+            .body(Once(Some(compressed.into())))
+            .unwrap();
+        let res = call(req);
+        assert_eq!(res.headers()[CONTENT_ENCODING], "bzip2");
+        assert!(!res.body().is_identity());
+
+        let mut out = Vec::new();
+        ::read::BzDecoder::new(&collect(res.into_body())[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"hello world");
+    }
+
+    #[test]
+    fn passthrough() {
+        let req = Request::builder()
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Once(Some(Bytes::from("hello"))))
+            .unwrap();
+        let res = call(req);
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        assert!(res.body().is_identity());
+        assert_eq!(collect(res.into_body()), b"hello");
+    }
+
+    #[test]
+    fn accept_encoding() {
+        let accepts = |v: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, v.parse().unwrap());
+            accepts_bzip2(&headers)
+        };
+        assert!(accepts("bzip2"));
+        assert!(accepts("gzip,BZIP2 ; q=1"));
+        assert!(!accepts("bzip2;q=0"));
+        assert!(!accepts("bzip2;q=0.0, gzip"));
+        assert!(!accepts("*"));
+        assert!(!accepts("bzip22"));
+    }
+}
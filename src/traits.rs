@@ -0,0 +1,141 @@
+//! Traits for streaming compressors and decompressors in general.
+
+use std::io;
+
+/// A compressor fed and drained through plain slices.
+///
+/// This is implemented by `poll::Encoder`, and can be implemented for the
+/// compressors of other formats, so that code such as archivers can be
+/// generic over them or pick one at runtime as a `Box<dyn CompressStream>`.
+pub trait CompressStream {
+    /// Compresses as much of `input` into `output` as possible, returning the
+    /// number of bytes consumed and produced.
+    fn compress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)>;
+
+    /// Writes out everything compressed so far, returning the number of bytes
+    /// produced and whether the flush is complete. Has to be called until it
+    /// is before compressing more input.
+    fn flush(&mut self, output: &mut [u8]) -> io::Result<(usize, bool)>;
+
+    /// Ends the stream, returning the number of bytes produced and whether
+    /// the stream is complete. Has to be called until it is, after which no
+    /// more input is accepted.
+    fn finish(&mut self, output: &mut [u8]) -> io::Result<(usize, bool)>;
+
+    /// Number of bytes consumed so far.
+    fn total_in(&self) -> u64;
+
+    /// Number of bytes produced so far.
+    fn total_out(&self) -> u64;
+}
+
+/// A decompressor fed and drained through plain slices.
+///
+/// This is implemented by `poll::Decoder`, and can be implemented for the
+/// decompressors of other formats like `CompressStream`.
+pub trait DecompressStream {
+    /// Decompresses as much of `input` into `output` as possible, returning
+    /// the number of bytes consumed and produced.
+    ///
+    /// Nothing after the end of the stream is consumed.
+    fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)>;
+
+    /// Whether the end of the stream has been reached.
+    fn is_done(&self) -> bool;
+
+    /// Number of bytes consumed so far.
+    fn total_in(&self) -> u64;
+
+    /// Number of bytes produced so far.
+    fn total_out(&self) -> u64;
+}
+
+impl<T: CompressStream + ?Sized> CompressStream for Box<T> {
+    fn compress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+        (**self).compress(input, output)
+    }
+
+    fn flush(&mut self, output: &mut [u8]) -> io::Result<(usize, bool)> {
+        (**self).flush(output)
+    }
+
+    fn finish(&mut self, output: &mut [u8]) -> io::Result<(usize, bool)> {
+        (**self).finish(output)
+    }
+
+    fn total_in(&self) -> u64 {
+        (**self).total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        (**self).total_out()
+    }
+}
+
+impl<T: DecompressStream + ?Sized> DecompressStream for Box<T> {
+    fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+        (**self).decompress(input, output)
+    }
+
+    fn is_done(&self) -> bool {
+        (**self).is_done()
+    }
+
+    fn total_in(&self) -> u64 {
+        (**self).total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        (**self).total_out()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressStream, DecompressStream};
+    use poll::{Decoder, Encoder};
+    use Compression;
+
+    fn compress<C: CompressStream>(mut c: C, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = [0; 1000];
+        while !input.is_empty() {
+            let (consumed, produced) = c.compress(input, &mut buf).unwrap();
+            input = &input[consumed..];
+            out.extend_from_slice(&buf[..produced]);
+        }
+        loop {
+            let (produced, done) = c.finish(&mut buf).unwrap();
+            out.extend_from_slice(&buf[..produced]);
+            if done {
+                assert_eq!(c.total_out(), out.len() as u64);
+                return out;
+            }
+        }
+    }
+
+    fn decompress<D: DecompressStream>(mut d: D, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = [0; 1000];
+        while !d.is_done() {
+            let (consumed, produced) = d.decompress(input, &mut buf).unwrap();
+            assert!(consumed > 0 || produced > 0, "truncated input");
+            input = &input[consumed..];
+            out.extend_from_slice(&buf[..produced]);
+        }
+        assert_eq!(input, b"trailing");
+        out
+    }
+
+    #[test]
+    fn generic_roundtrip() {
+        let data = (0..100_000u32).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+        let compressor: Box<dyn CompressStream> = Box::new(Encoder::new(Compression::fast()));
+        let mut compressed = compress(compressor, &data);
+        compressed.extend_from_slice(b"trailing");
+
+        let decompressor: Box<dyn DecompressStream> = Box::new(Decoder::new());
+        assert!(decompress(decompressor, &compressed) == data);
+        assert!(decompress(Decoder::new(), &compressed) == data);
+    }
+}
@@ -0,0 +1,135 @@
+//! Checks that the encoders and decoders can be sent to other threads, and
+//! that the async ones don't need pinning, whenever what they wrap allows it.
+//!
+//! These only have to compile, so a field added without those auto traits
+//! breaks the build of the tests rather than code downstream.
+
+extern crate bzip2;
+
+use std::fs::File;
+
+use bzip2::{bufread, read, write};
+
+fn send<T: Send>() {}
+
+#[allow(dead_code)]
+fn send_unpin<T: Send + Unpin>() {}
+
+type Slice = &'static [u8];
+
+#[test]
+fn sync_io() {
+    send::<bzip2::Compress>();
+    send::<bzip2::Decompress>();
+    send::<bzip2::BzIndex>();
+    send::<bzip2::ThreadPool>();
+    send::<bzip2::BufferPool>();
+    send::<bzip2::poll::Encoder>();
+    send::<bzip2::poll::Decoder>();
+    send::<bzip2::owned::Encoder>();
+    send::<bzip2::owned::Decoder>();
+
+    send::<read::BzEncoder<File>>();
+    send::<read::BzDecoder<File>>();
+    send::<read::MultiBzDecoder<File>>();
+    send::<read::BzSliceDecoder>();
+    send::<read::ParBzEncoder<File>>();
+    send::<read::ParBzDecoder<File>>();
+    send::<read::ParMultiBzDecoder<File>>();
+    send::<read::BzSeekDecoder<File>>();
+    send::<read::ResyncBzDecoder<File>>();
+
+    send::<bufread::BzEncoder<Slice>>();
+    send::<bufread::BzDecoder<Slice>>();
+    send::<bufread::MultiBzDecoder<Slice>>();
+    send::<bufread::BzChunks<Slice>>();
+
+    send::<write::BzEncoder<File>>();
+    send::<write::FinishGuard<File>>();
+    send::<write::BzDecoder<File>>();
+    send::<write::MultiBzDecoder<File>>();
+    send::<write::ParBzEncoder<File>>();
+}
+
+#[cfg(feature = "stdio")]
+#[test]
+fn stdio() {
+    send::<bzip2::stdio::BzReader>();
+    send::<bzip2::stdio::BzWriter>();
+}
+
+#[cfg(feature = "futures-io")]
+#[test]
+fn futures_io() {
+    use bzip2::futures_io::{bufread, read, write};
+
+    send_unpin::<bufread::BzEncoder<Slice>>();
+    send_unpin::<bufread::BzDecoder<Slice>>();
+    send_unpin::<bufread::MultiBzDecoder<Slice>>();
+    send_unpin::<read::BzEncoder<Slice>>();
+    send_unpin::<read::BzDecoder<Slice>>();
+    send_unpin::<read::MultiBzDecoder<Slice>>();
+    send_unpin::<read::BzSeekDecoder<Slice>>();
+    send_unpin::<write::BzEncoder<Vec<u8>>>();
+    send_unpin::<write::BzDecoder<Vec<u8>>>();
+    send_unpin::<write::MultiBzDecoder<Vec<u8>>>();
+    send_unpin::<write::ParBzEncoder<Vec<u8>>>();
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn tokio1() {
+    use bzip2::tokio1::{bufread, read, write};
+
+    send_unpin::<bufread::BzEncoder<Slice>>();
+    send_unpin::<bufread::BzDecoder<Slice>>();
+    send_unpin::<bufread::MultiBzDecoder<Slice>>();
+    send_unpin::<read::BzSeekDecoder<Slice>>();
+    send_unpin::<write::BzEncoder<Vec<u8>>>();
+    send_unpin::<write::BzDecoder<Vec<u8>>>();
+    send_unpin::<write::MultiBzDecoder<Vec<u8>>>();
+    send_unpin::<write::ParBzEncoder<Vec<u8>>>();
+}
+
+#[cfg(feature = "codec")]
+#[test]
+fn codec() {
+    send_unpin::<bzip2::codec::BzCodec>();
+}
+
+#[cfg(feature = "stream")]
+#[test]
+fn stream() {
+    use bzip2::stream;
+
+    send_unpin::<stream::BzEncoder<()>>();
+    send_unpin::<stream::BzDecoder<()>>();
+    send_unpin::<stream::MultiBzDecoder<()>>();
+}
+
+#[cfg(feature = "sink")]
+#[test]
+fn sink() {
+    send_unpin::<bzip2::sink::BzEncoder<()>>();
+}
+
+#[cfg(feature = "body")]
+#[test]
+fn body() {
+    use bzip2::body;
+
+    send_unpin::<body::BzEncoder<()>>();
+    send_unpin::<body::BzDecoder<()>>();
+    send_unpin::<body::MultiBzDecoder<()>>();
+}
+
+#[cfg(feature = "tower")]
+#[test]
+fn tower() {
+    use bzip2::tower;
+
+    send_unpin::<tower::BzLayer>();
+    send_unpin::<tower::Bz<()>>();
+    send_unpin::<tower::ResponseFuture<()>>();
+    send_unpin::<tower::BzBody<()>>();
+}
@@ -0,0 +1,69 @@
+//! Installing crate-wide buffer sizes, in a test binary of its own as they
+//! affect every stream created afterwards.
+
+extern crate bzip2;
+
+use std::io;
+use std::io::prelude::*;
+
+use bzip2::{BufferSizes, Compression};
+
+/// Records the sizes of the writes to it.
+struct Writes(Vec<usize>);
+
+impl Write for Writes {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.push(data.len());
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records the sizes of the reads from it.
+struct Reads<'a>(&'a [u8], Vec<usize>);
+
+impl<'a> Read for Reads<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.1.push(buf.len());
+        self.0.read(buf)
+    }
+}
+
+#[test]
+fn install() {
+    assert_eq!(BufferSizes::current(), BufferSizes::new());
+    let data = (0..200_000u32)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect::<Vec<_>>();
+    let compressed = bzip2::compress(&data, Compression::fast());
+
+    let sizes = BufferSizes::new().buffer(1024).read_buffer(512).chunk(4096);
+    sizes.install();
+    assert_eq!(BufferSizes::current(), sizes);
+    assert_eq!(sizes.buffer_size(), 1024);
+
+    let mut e = bzip2::write::BzEncoder::new(Writes(Vec::new()), Compression::fast());
+    e.write_all(&data).unwrap();
+    let writes = e.finish().unwrap().0;
+    assert!(writes.iter().all(|&n| n <= 1024));
+    assert_eq!(writes.iter().sum::<usize>(), compressed.len());
+
+    let mut d = bzip2::read::BzDecoder::new(Reads(&compressed, Vec::new()));
+    let mut out = Vec::new();
+    d.read_to_end(&mut out).unwrap();
+    assert!(out == data);
+    assert!(d.get_ref().1.iter().all(|&n| n == 512));
+
+    // an explicit capacity still wins
+    let mut e =
+        bzip2::write::BzEncoder::with_capacity(Writes(Vec::new()), Compression::fast(), 8192);
+    e.write_all(&data).unwrap();
+    let writes = e.finish().unwrap().0;
+    assert!(writes.iter().any(|&n| n > 1024));
+
+    BufferSizes::new().install();
+    assert_eq!(BufferSizes::current(), BufferSizes::new());
+}
@@ -0,0 +1,108 @@
+#![cfg(all(feature = "cli", not(target_os = "wasi")))]
+
+extern crate bzip2;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bzip2::Compression;
+
+fn dir(name: &str) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let dir = env::temp_dir().join(format!("bzip2-cli-{}-{}", now.as_nanos(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn bzip2(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bzip2"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn files() {
+    let dir = dir("files");
+    let data = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let plain = dir.join("data");
+    let compressed = dir.join("data.bz2");
+    fs::write(&plain, &data).unwrap();
+
+    assert!(bzip2(&["compress", "-1", arg(&plain)], b"")
+        .status
+        .success());
+    assert!(!plain.exists());
+    assert!(bzip2(&["test", arg(&compressed)], b"").status.success());
+
+    // refuses to overwrite the output unless forced
+    fs::write(&plain, b"in the way").unwrap();
+    assert!(!bzip2(&["decompress", "-k", arg(&compressed)], b"")
+        .status
+        .success());
+    assert!(bzip2(&["decompress", "-k", "-f", arg(&compressed)], b"")
+        .status
+        .success());
+    assert!(fs::read(&plain).unwrap() == data);
+    assert!(compressed.exists());
+
+    let out = bzip2(&["decompress", "-c", arg(&compressed)], b"");
+    assert!(out.status.success());
+    assert!(out.stdout == data);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn stdio() {
+    let out = bzip2(&["compress"], b"hello world");
+    assert!(out.status.success());
+    assert_eq!(bzip2::decompress(&out.stdout).unwrap(), b"hello world");
+
+    let out = bzip2(&["decompress"], &out.stdout);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"hello world");
+
+    assert!(!bzip2(&["test"], b"not bzip2").status.success());
+    assert!(!bzip2(&["frobnicate"], b"").status.success());
+}
+
+#[test]
+fn recover() {
+    let dir = dir("recover");
+    // three blocks of just under 100k, 100k and 50k, the middle one of which
+    // gets damaged
+    let data = (0..250_000u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+        .collect::<Vec<_>>();
+    let mut compressed = bzip2::compress(&data, Compression::fast());
+    let middle = compressed.len() / 2;
+    compressed[middle] ^= 0x55;
+    let damaged = dir.join("damaged.bz2");
+    fs::write(&damaged, &compressed).unwrap();
+    assert!(!bzip2(&["test", arg(&damaged)], b"").status.success());
+
+    assert!(bzip2(&["recover", arg(&damaged)], b"").status.success());
+    let rec = |n: u32| fs::read(dir.join(format!("rec{:05}damaged.bz2", n))).unwrap();
+    let (first, last) = (
+        bzip2::decompress(&rec(1)).unwrap(),
+        bzip2::decompress(&rec(3)).unwrap(),
+    );
+    assert!(bzip2::decompress(&rec(2)).is_err());
+    assert!(data.starts_with(&first) && first.len() > 99_000);
+    assert!(data.ends_with(&last) && last.len() > 49_000);
+    assert!(!dir.join("rec00004damaged.bz2").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
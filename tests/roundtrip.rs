@@ -0,0 +1,166 @@
+//! Property tests compressing and decompressing generated data through every
+//! layer of the crate, with all of the parameters libbz2 takes.
+
+extern crate bzip2;
+extern crate quickcheck;
+
+use std::io::prelude::*;
+
+use bzip2::{Action, Compress, Compression, Decompress, Status};
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+/// The data to compress, along with how to compress it.
+#[derive(Clone, Debug)]
+struct Case {
+    data: Vec<u8>,
+    level: u32,
+    work_factor: u32,
+    small: bool,
+}
+
+impl Arbitrary for Case {
+    fn arbitrary(g: &mut Gen) -> Case {
+        // long runs and short periods are what the initial RLE and the
+        // sorting of libbz2 struggle with the most, and the larger ones span
+        // several blocks at the lowest levels
+        let len = usize::arbitrary(g) % 150_000;
+        let data = match u8::arbitrary(g) % 4 {
+            0 => Vec::arbitrary(g),
+            1 => vec![u8::arbitrary(g); len],
+            2 => {
+                let (a, b) = (u8::arbitrary(g), u8::arbitrary(g));
+                (0..len).map(|i| if i % 2 == 0 { a } else { b }).collect()
+            }
+            _ => {
+                let mut period = Vec::<u8>::arbitrary(g);
+                period.push(u8::arbitrary(g));
+                period.iter().cloned().cycle().take(len).collect()
+            }
+        };
+        Case {
+            data,
+            level: u32::arbitrary(g) % 9 + 1,
+            work_factor: u32::arbitrary(g) % 251,
+            small: bool::arbitrary(g),
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Case>> {
+        let case = self.clone();
+        Box::new(self.data.shrink().map(move |data| Case {
+            data,
+            ..case.clone()
+        }))
+    }
+}
+
+fn check(f: fn(Case) -> bool) {
+    QuickCheck::new().tests(40).quickcheck(f);
+}
+
+fn mem_compress(data: &[u8], level: Compression, work_factor: u32) -> Vec<u8> {
+    let mut c = Compress::new(level, work_factor);
+    let mut out = Vec::with_capacity(64);
+    loop {
+        let rest = &data[c.total_in() as usize..];
+        if c.compress_vec(rest, &mut out, Action::Finish).unwrap() == Status::StreamEnd {
+            return out;
+        }
+        out.reserve(out.capacity());
+    }
+}
+
+fn mem_decompress(data: &[u8], small: bool) -> Vec<u8> {
+    let mut d = Decompress::new(small);
+    let mut out = Vec::with_capacity(64);
+    loop {
+        let rest = &data[d.total_in() as usize..];
+        if d.decompress_vec(rest, &mut out).unwrap() == Status::StreamEnd {
+            assert_eq!(d.total_in(), data.len() as u64);
+            return out;
+        }
+        out.reserve(out.capacity());
+    }
+}
+
+#[test]
+fn mem() {
+    fn test(case: Case) -> bool {
+        // `compress` picks the smallest block size the data fits in
+        let level = Compression::new(case.level).for_size(case.data.len() as u64);
+        let compressed = mem_compress(&case.data, level, case.work_factor);
+        // the work factor only changes how fast the blocks are sorted
+        compressed == bzip2::compress(&case.data, level)
+            && mem_decompress(&compressed, case.small) == case.data
+    }
+    check(test);
+}
+
+#[test]
+fn streams() {
+    fn test(case: Case) -> bool {
+        let level = Compression::new(case.level).for_size(case.data.len() as u64);
+        let data = &case.data[..];
+        let compressed = bzip2::compress(data, level);
+
+        let mut read = Vec::new();
+        bzip2::read::BzEncoder::new(data, level)
+            .read_to_end(&mut read)
+            .unwrap();
+        let mut bufread = Vec::new();
+        bzip2::bufread::BzEncoder::new(data, level)
+            .read_to_end(&mut bufread)
+            .unwrap();
+        let mut w = bzip2::write::BzEncoder::new(Vec::new(), level);
+        w.write_all(data).unwrap();
+        let write = w.finish().unwrap();
+        if read != compressed || bufread != compressed || write != compressed {
+            return false;
+        }
+
+        let mut read = Vec::new();
+        bzip2::read::BzDecoder::new(&compressed[..])
+            .read_to_end(&mut read)
+            .unwrap();
+        let mut bufread = Vec::new();
+        bzip2::bufread::BzDecoder::new(&compressed[..])
+            .read_to_end(&mut bufread)
+            .unwrap();
+        let mut w = bzip2::write::BzDecoder::new(Vec::new());
+        w.write_all(&compressed).unwrap();
+        let write = w.finish().unwrap();
+        read == data && bufread == data && write == data
+    }
+    check(test);
+}
+
+#[test]
+fn multistreams() {
+    fn test(case: Case) -> bool {
+        let level = Compression::new(case.level);
+        // up to three members of the data cut at arbitrary points
+        let data = &case.data[..];
+        let cuts = [0, data.len() / 3, data.len() / 2, data.len()];
+        let mut compressed = Vec::new();
+        for pair in cuts.windows(2) {
+            compressed.extend(bzip2::compress(&data[pair[0]..pair[1]], level));
+        }
+
+        let mut read = Vec::new();
+        bzip2::read::MultiBzDecoder::new(&compressed[..])
+            .read_to_end(&mut read)
+            .unwrap();
+        let mut bufread = Vec::new();
+        bzip2::bufread::MultiBzDecoder::new(&compressed[..])
+            .read_to_end(&mut bufread)
+            .unwrap();
+        let mut w = bzip2::write::MultiBzDecoder::new(Vec::new());
+        w.write_all(&compressed).unwrap();
+        let write = w.finish().unwrap();
+        read == data
+            && bufread == data
+            && write == data
+            && bzip2::decompress(&compressed).unwrap() == data
+    }
+    check(test);
+}
@@ -1,4 +1,4 @@
-#![cfg(feature = "tokio")]
+#![cfg(all(feature = "tokio", not(target_os = "wasi")))]
 
 extern crate bzip2;
 extern crate futures;
@@ -90,7 +90,7 @@ fn echo_random() {
         let t = thread::spawn(move || {
             let mut b = read::BzDecoder::new(b);
             let mut buf = [0; 1024];
-            while v3.len() > 0 {
+            while !v3.is_empty() {
                 let n = b.read(&mut buf).unwrap();
                 for (actual, expected) in buf[..n].iter().zip(&v3) {
                     assert_eq!(*actual, *expected);